@@ -1,10 +1,115 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::debug_info::Location;
+use crate::linker;
+use crate::msg;
+use crate::object::Object;
+use crate::preprocessor::{self, Line};
+
+thread_local! {
+    /// 表示せずに集めているアセンブラのメッセージ
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// アセンブラのメッセージを表示する (集めている間は表示しない)
+pub fn report(message: String) {
+    CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(messages) => messages.push(message),
+        None => println!("{message}"),
+    });
+}
+
+/// アセンブラのメッセージがエラーか判断する
+pub fn is_error(message: &str) -> bool {
+    message.starts_with(&msg!("assembly.error_prefix"))
+}
+
+/// 処理の間のアセンブラのメッセージを表示せずに集める
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let messages = CAPTURED.with(|captured| captured.borrow_mut().take());
+    (result, messages.unwrap_or_default())
+}
+
 /// アセンブラ
-pub fn assembly(asm: String) -> Vec<i32> {
-    println!("アセンブル中・・・");
-    let mut memory: Vec<i32> = Vec::new();
+pub fn assembly(asm: String, path: &Path, defines: &HashMap<String, String>) -> Object {
+    report(msg!("assembly.assembling"));
+    let object = assemble_object(&asm, path, defines);
+    match linker::link(std::slice::from_ref(&object)) {
+        Ok(program) => program,
+        Err(errors) => {
+            for e in errors {
+                report(msg!("assembly.link_error", e));
+            }
+            object
+        }
+    }
+}
+
+/// ソースを再配置可能なオブジェクトモジュールにアセンブルする
+pub fn assemble_object(asm: &str, path: &Path, defines: &HashMap<String, String>) -> Object {
+    let mut assembler = Assembler {
+        object: Object::default(),
+        labels: HashMap::new(),
+        imports: Vec::new(),
+        exports: Vec::new(),
+        fixups: Vec::new(),
+    };
+    for line in preprocessor::preprocess(asm, path, defines) {
+        assembler.line(&line);
+    }
+    assembler.finish()
+}
+
+/// アセンブラの状態
+struct Assembler {
+    object: Object,                     // 出力するモジュール
+    labels: HashMap<String, usize>,     // 定義されたラベルとその位置
+    imports: Vec<String>,               // 外部から読み込むシンボル
+    exports: Vec<(String, Line)>,       // 公開するシンボル
+    fixups: Vec<(usize, String, Line)>, // 後で埋めるラベルの参照
+}
+
+impl Assembler {
+    /// 1行をアセンブルする
+    fn line(&mut self, line: &Line) {
+        let mut code = strip_comment(&line.text).trim();
+
+        // ラベルを定義する
+        if let Some((label, rest)) = code.split_once(':') {
+            if is_identifier(label.trim()) {
+                let label = label.trim().to_string();
+                if self.labels.contains_key(&label) {
+                    error(line, &msg!("assembly.duplicate_label", label));
+                }
+                self.labels.insert(label, self.object.code.len());
+                code = rest.trim();
+                if code.is_empty() {
+                    return;
+                }
+            }
+        }
+
+        // 次に配置するアドレスとソースの位置を対応付ける
+        let location = Location {
+            file: line.file.clone(),
+            line: line.number,
+            column: column(&line.text, code),
+            text: code.to_string(),
+        };
+        (self.object.debug.lines).insert(self.object.code.len(), location);
+
+        // データ命令はメモリにデータを直接配置する
+        if code.starts_with('.') {
+            if let Err(e) = self.directive(code, line) {
+                error(line, &e);
+            }
+            return;
+        }
 
-    for code in asm.split("\n") {
-        let code = code.trim().split(";").collect::<Vec<&str>>()[0];
         let args: Vec<&str> = if code.contains(" ") || code.contains("　") {
             code.split_whitespace().collect()
         } else {
@@ -12,13 +117,29 @@ pub fn assembly(asm: String) -> Vec<i32> {
         };
 
         if args[0] == "data" {
-            continue;
+            return;
         }
 
         if args[0] == "program" {
-            continue;
+            return;
+        }
+
+        // 他のモジュールとシンボルをやり取りする
+        if args[0] == "export" || args[0] == "import" {
+            for name in &args[1..] {
+                let name = name.trim_matches(',');
+                if !is_identifier(name) {
+                    error(line, &msg!("assembly.invalid_symbol", name));
+                } else if args[0] == "export" {
+                    self.exports.push((name.to_string(), line.clone()));
+                } else {
+                    self.imports.push(name.to_string());
+                }
+            }
+            return;
         }
 
+        let memory = &mut self.object.code;
         match args[0] {
             "add" => memory.push(1),
             "sub" => memory.push(2),
@@ -41,10 +162,250 @@ pub fn assembly(asm: String) -> Vec<i32> {
             "write" => memory.push(19),
             "halt" => memory.push(20),
             "winapi" => memory.push(21),
+            "inputchar" => memory.push(22),
+            "inputstr" => memory.push(23),
+            "outputnum" => memory.push(24),
+            "outputstr" => memory.push(25),
+            "eof" => memory.push(26),
+            "ei" => memory.push(27),
+            "di" => memory.push(28),
+            "iret" => memory.push(29),
+            "syscall" => memory.push(30),
             "" => memory.push(0),
-            _ => memory.push(args[0].trim().parse().unwrap_or(0)),
+            _ => self.operand(args[0].trim(), line),
+        }
+    }
+
+    /// 数値かラベルの参照を配置する
+    fn operand(&mut self, value: &str, line: &Line) {
+        if is_identifier(value) {
+            self.fixups
+                .push((self.object.code.len(), value.to_string(), line.clone()));
+            self.object.code.push(0);
+        } else {
+            self.object.code.push(value.parse().unwrap_or(0));
         }
     }
 
-    return memory;
+    /// データ命令を処理してメモリにデータを配置する
+    fn directive(&mut self, code: &str, line: &Line) -> Result<(), String> {
+        let (name, operand) = match code.find(char::is_whitespace) {
+            Some(i) => (&code[..i], code[i..].trim()),
+            None => (code, ""),
+        };
+
+        let memory = &mut self.object.code;
+        match name {
+            // 文字列を1文字ずつ配置する
+            ".string" => {
+                let text = parse_string(operand)?;
+                memory.extend(text.chars().map(|c| c as i32));
+            }
+            // 文字列を配置して終端に0を置く
+            ".zstring" => {
+                let text = parse_string(operand)?;
+                memory.extend(text.chars().map(|c| c as i32));
+                memory.push(0);
+            }
+            // カンマ区切りの数値かラベルを配置する
+            ".word" => {
+                for value in operand.split(',') {
+                    let value = value.trim();
+                    if !is_identifier(value) && value.parse::<i32>().is_err() {
+                        return Err(msg!("assembly.not_a_number", value));
+                    }
+                    self.operand(value, line);
+                }
+            }
+            // 指定した数だけ0を配置する
+            ".zero" => {
+                let count: usize = operand
+                    .parse()
+                    .map_err(|_| msg!("assembly.invalid_count", operand))?;
+                memory.extend(std::iter::repeat_n(0, count));
+            }
+            _ => return Err(msg!("assembly.undefined_directive", name)),
+        }
+        Ok(())
+    }
+
+    /// ラベルの参照を解決してモジュールを完成させる
+    fn finish(mut self) -> Object {
+        let mut labels: Vec<(String, usize)> = self.labels.clone().into_iter().collect();
+        labels.sort_by_key(|(_, offset)| *offset);
+        self.object.debug.labels = labels;
+
+        for (address, label, line) in &self.fixups {
+            if let Some(offset) = self.labels.get(label) {
+                // モジュール内のアドレスはリンク時に再配置する
+                self.object.code[*address] = *offset as i32;
+                self.object.relocations.push(*address);
+            } else if self.imports.contains(label) {
+                self.object.imports.push((*address, label.clone()));
+            } else {
+                error(line, &msg!("assembly.undefined_label", label));
+            }
+        }
+
+        for (name, line) in &self.exports {
+            match self.labels.get(name) {
+                Some(offset) => self.object.exports.push((name.clone(), *offset)),
+                None => error(line, &msg!("assembly.undefined_export", name)),
+            }
+        }
+        self.object
+    }
+}
+
+/// エラーを表示する
+fn error(line: &Line, message: &str) {
+    report(msg!("assembly.error", line.file, line.number, message));
+}
+
+/// 行の中でのコードの列番号を求める (codeはtextの一部を指している)
+fn column(text: &str, code: &str) -> usize {
+    let offset = code.as_ptr() as usize - text.as_ptr() as usize;
+    text[..offset].chars().count() + 1
+}
+
+/// ラベル名として使えるか判断する
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '@')
+}
+
+/// 文字列リテラルの中を除いてコメントを取り除く
+pub fn strip_comment(code: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ';' {
+            return &code[..i];
+        }
+    }
+    code
+}
+
+/// ダブルクォートで囲まれた文字列リテラルのエスケープシーケンスを展開する
+pub fn parse_string(literal: &str) -> Result<String, String> {
+    let body = literal
+        .strip_prefix('"')
+        .ok_or(msg!("assembly.string.unquoted"))?;
+
+    let mut result = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                if !chars.as_str().trim().is_empty() {
+                    return Err(msg!("assembly.string.trailing"));
+                }
+                return Ok(result);
+            }
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('r') => result.push('\r'),
+                Some('0') => result.push('\0'),
+                Some('\\') => result.push('\\'),
+                Some('"') => result.push('"'),
+                Some('\'') => result.push('\''),
+                Some('u') => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .strip_prefix('{')
+                        .and_then(|s| s.find('}'))
+                        .ok_or(msg!("assembly.string.unicode_format"))?;
+                    let hex = &rest[1..end + 1];
+                    let c = u32::from_str_radix(hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or(msg!("assembly.string.invalid_unicode", hex))?;
+                    result.push(c);
+                    chars = rest[end + 2..].chars();
+                }
+                Some(c) => return Err(msg!("assembly.string.undefined_escape", c)),
+                None => break,
+            },
+            _ => result.push(c),
+        }
+    }
+    Err(msg!("assembly.string.unterminated"))
+}
+
+/// 文字列をparse_stringで読める文字列リテラルにする
+pub fn quote_string(text: &str) -> String {
+    format!("\"{}\"", text.escape_debug())
+}
+
+#[cfg(test)]
+mod test_assembly {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::assembly::{assemble_object, assembly};
+
+    fn assemble(asm: &str) -> Vec<i32> {
+        assembly(asm.to_string(), Path::new("test.asm"), &HashMap::new()).code
+    }
+
+    #[test]
+    fn test_string_directives() {
+        let memory = assemble(".string \"Hi;\\n\"\n.zstring \"あ\\u{1F600}\"");
+        assert_eq!(memory, vec![72, 105, 59, 10, 0x3042, 0x1F600, 0]);
+    }
+
+    #[test]
+    fn test_word_and_zero_directives() {
+        let memory = assemble("push\n.word 1, -2, 3 ; 表\n.zero 3\nhalt");
+        assert_eq!(memory, vec![6, 1, -2, 3, 0, 0, 0, 20]);
+    }
+
+    #[test]
+    fn test_labels() {
+        let memory = assemble("push\nend\njump\ntable: .word end, 7\nend:\nhalt");
+        assert_eq!(memory, vec![6, 5, 13, 5, 7, 20]);
+    }
+
+    #[test]
+    fn test_object_symbols() {
+        let object = assemble_object(
+            "import putc\nexport main\nmain:\npush\nputc\npush\nmain",
+            Path::new("test.asm"),
+            &HashMap::new(),
+        );
+        assert_eq!(object.code, vec![6, 0, 6, 0]);
+        assert_eq!(object.exports, vec![("main".to_string(), 0)]);
+        assert_eq!(object.imports, vec![(1, "putc".to_string())]);
+        assert_eq!(object.relocations, vec![3]);
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let program = assembly(
+            "push\n1\nloop:  add ; 足す\n.word 1, 2\nhalt".to_string(),
+            Path::new("test.asm"),
+            &HashMap::new(),
+        );
+        let location = program.debug.location(4).unwrap();
+        assert_eq!((location.line, location.column), (4, 1));
+        assert_eq!(program.debug.location(2).unwrap().column, 8);
+        assert_eq!(program.debug.symbolize(3), Some("loop+1".to_string()));
+        assert_eq!(
+            program.debug.describe(5),
+            "<loop+3> test.asm 5行目 1列目: halt"
+        );
+    }
 }
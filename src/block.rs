@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::msg;
+
+/// ブロックの大きさ (バイト)
+pub const BLOCK_SIZE: usize = 512;
+
+/// 1ブロック分のデータ
+pub type Block = [u8; BLOCK_SIZE];
+
+/// ブロック単位で読み書きする装置
+pub trait BlockDevice {
+    /// ブロックの数
+    fn block_count(&self) -> u32;
+    /// ブロックを読む
+    fn read_block(&mut self, index: u32) -> Result<Block, String>;
+    /// ブロックに書く
+    fn write_block(&mut self, index: u32, block: &Block) -> Result<(), String>;
+}
+
+/// ファイルに保存したディスクイメージ
+pub struct ImageFile {
+    file: File,   // ディスクイメージのファイル
+    path: String, // ファイルのパス (エラーの表示に使う)
+    blocks: u32,  // ブロックの数
+}
+
+impl ImageFile {
+    /// ディスクイメージを開く
+    pub fn open(path: &str) -> Result<ImageFile, String> {
+        let file = (File::options().read(true).write(true).open(path))
+            .map_err(|e| msg!("common.open_failed", path, e))?;
+        let length = (file.metadata()).map_err(|e| msg!("common.read_failed", path, e))?;
+        Ok(ImageFile {
+            file,
+            path: path.to_string(),
+            blocks: (length.len() / BLOCK_SIZE as u64) as u32,
+        })
+    }
+
+    /// 中身が0のディスクイメージを作る
+    pub fn create(path: &str, blocks: u32) -> Result<ImageFile, String> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| msg!("common.create_failed", path, e))?;
+        (file.set_len(blocks as u64 * BLOCK_SIZE as u64))
+            .map_err(|e| msg!("common.write_failed", path, e))?;
+        Ok(ImageFile {
+            file,
+            path: path.to_string(),
+            blocks,
+        })
+    }
+
+    /// ブロックの先頭に移る
+    fn seek(&mut self, index: u32) -> Result<(), String> {
+        if index >= self.blocks {
+            return Err(msg!("block.out_of_range", index, self.blocks));
+        }
+        let position = index as u64 * BLOCK_SIZE as u64;
+        (self.file.seek(SeekFrom::Start(position)))
+            .map_err(|e| msg!("common.read_failed", self.path, e))?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for ImageFile {
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    fn read_block(&mut self, index: u32) -> Result<Block, String> {
+        self.seek(index)?;
+        let mut block = [0; BLOCK_SIZE];
+        (self.file.read_exact(&mut block)).map_err(|e| msg!("common.read_failed", self.path, e))?;
+        Ok(block)
+    }
+
+    fn write_block(&mut self, index: u32, block: &Block) -> Result<(), String> {
+        self.seek(index)?;
+        (self.file.write_all(block)).map_err(|e| msg!("common.write_failed", self.path, e))
+    }
+}
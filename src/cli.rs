@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+
+use crate::i18n::Language;
+use crate::msg;
+
+/// 実行するコマンド
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Command {
+    Run,
+    #[default]
+    Debug,
+    Asm,
+    Disasm,
+    Check,
+    Trace,
+    Link,
+    Repl,
+    Dap,
+    Disk,
+    Help,
+    Version,
+}
+
+impl Command {
+    /// オプション以外に受け付ける引数の数
+    fn max_args(self) -> usize {
+        match self {
+            Command::Link | Command::Help | Command::Version => usize::MAX,
+            Command::Disk => 4,
+            Command::Repl | Command::Dap => 0,
+            _ => 1,
+        }
+    }
+}
+
+/// コマンドラインの指定
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub command: Command,                 // 実行するコマンド
+    pub args: Vec<String>,                // オプション以外の引数
+    pub defines: HashMap<String, String>, // -Dで定義したマクロ
+    pub storage: Option<String>,          // ストレージに使うファイル
+    pub mem_size: Option<usize>,          // メモリの大きさ
+    pub max_steps: Option<u64>,           // 実行できる命令の数の上限
+    pub quantum: Option<u64>,             // プロセスが続けて実行できる命令の数
+    pub input_file: Option<String>,       // 入力に使うファイル
+    pub quiet: bool,                      // 途中経過を表示しない
+    pub format: Option<String>,           // 出力の形式
+    pub output: Option<String>,           // 出力先
+    pub trace: Option<String>,            // 実行トレースの出力先
+    pub profile: Option<String>,          // プロファイルの出力先
+    pub folded: Option<String>,           // フレームグラフの形式の出力先
+    pub coverage: Option<String>,         // カバレッジの一覧の出力先
+    pub lcov: Option<String>,             // LCOVの出力先
+    pub tui: bool,                        // 全画面のデバッガを使う
+    pub gdb: Option<u16>,                 // GDBのリモートサーバーのポート
+    pub lang: Option<Language>,           // メッセージの言語
+    pub record: Option<String>,           // 入力を記録するファイル
+    pub replay: Option<String>,           // 記録した入力を再現するファイル
+    pub devices: Vec<String>,             // 番地に割り当てる装置
+    pub disk: Option<String>,             // システムコールで使うディスクイメージ
+}
+
+impl Options {
+    /// 対話的に使うか判断する (バナーを表示するかどうかに使う)
+    pub fn is_interactive(&self) -> bool {
+        match self.command {
+            Command::Debug => !self.tui && self.gdb.is_none() && self.input_file.is_none(),
+            Command::Repl => true,
+            _ => false,
+        }
+    }
+
+    /// 最初の引数 (ソースのファイル)
+    pub fn file(&self) -> Result<&str, String> {
+        (self.args.first().map(String::as_str)).ok_or(msg!("cli.missing_file"))
+    }
+}
+
+/// コマンドライン引数 (プログラム名を除く) を解釈する
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let command = match args.first().map(String::as_str) {
+        Some("run") => Some(Command::Run),
+        Some("debug") => Some(Command::Debug),
+        Some("asm") => Some(Command::Asm),
+        Some("disasm") => Some(Command::Disasm),
+        Some("check") => Some(Command::Check),
+        Some("trace") => Some(Command::Trace),
+        Some("link") => Some(Command::Link),
+        Some("repl") => Some(Command::Repl),
+        Some("dap") => Some(Command::Dap),
+        Some("disk") => Some(Command::Disk),
+        Some("help") => Some(Command::Help),
+        _ => None,
+    };
+    // コマンドを省略してファイルだけを指定した場合はデバッグする
+    options.command = match command {
+        Some(command) => command,
+        None if args.is_empty() => Command::Help,
+        None => Command::Debug,
+    };
+    let mut iter = args[command.is_some() as usize..].iter();
+    let mut rest: Vec<&String> = Vec::new();
+    let mut expand = false;
+
+    // トレースの表示と比較は絞り込みの指定をそのまま渡す
+    if options.command == Command::Trace {
+        if let Some(sub) = args.get(1).filter(|sub| *sub == "show" || *sub == "diff") {
+            options.args = args[1..].to_vec();
+            if sub == "show" && options.args.len() < 2 || sub == "diff" && options.args.len() < 3 {
+                return Err(msg!("cli.missing_trace_file", sub));
+            }
+            return Ok(options);
+        }
+    }
+
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| (iter.next().cloned()).ok_or(msg!("cli.missing_value", name));
+        match arg.as_str() {
+            "-h" | "--help" => options.command = Command::Help,
+            "-V" | "--version" => options.command = Command::Version,
+            "--quiet" | "-q" => options.quiet = true,
+            "--tui" => options.tui = true,
+            "--storage" => options.storage = Some(value(arg)?),
+            "--mem-size" => options.mem_size = Some(number(arg, &value(arg)?)?),
+            "--max-steps" => options.max_steps = Some(number(arg, &value(arg)?)?),
+            "--quantum" => options.quantum = Some(number(arg, &value(arg)?)?),
+            "--input-file" => options.input_file = Some(value(arg)?),
+            "--format" => options.format = Some(value(arg)?),
+            "--expand" => {
+                options.format = Some("expand".to_string());
+                expand = true;
+            }
+            "-o" | "--output" => options.output = Some(value(arg)?),
+            "--trace" => options.trace = Some(value(arg)?),
+            "--profile" => options.profile = Some(value(arg)?),
+            "--folded" => options.folded = Some(value(arg)?),
+            "--coverage" => options.coverage = Some(value(arg)?),
+            "--lcov" => options.lcov = Some(value(arg)?),
+            "--record" => options.record = Some(value(arg)?),
+            "--replay" => options.replay = Some(value(arg)?),
+            "--device" => options.devices.push(value(arg)?),
+            "--disk" => options.disk = Some(value(arg)?),
+            "--gdb" => options.gdb = Some(number(arg, &value(arg)?)?),
+            "--lang" => {
+                let name = value(arg)?;
+                let lang = Language::parse(&name).ok_or(msg!("cli.unknown_language", name))?;
+                options.lang = Some(lang);
+            }
+            "-D" => define(&mut options.defines, &value(arg)?),
+            _ if arg.starts_with("-D") => define(&mut options.defines, &arg[2..]),
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(msg!("cli.unknown_option", arg));
+            }
+            _ => rest.push(arg),
+        }
+    }
+    options.args = rest.into_iter().cloned().collect();
+    // 以前からの`<ファイル> --expand`はマクロを展開したソースを表示する
+    if expand && command.is_none() && options.command == Command::Debug {
+        options.command = Command::Asm;
+    }
+    if options.mem_size == Some(0) {
+        return Err(msg!("cli.zero_mem_size"));
+    }
+    if options.record.is_some() && options.replay.is_some() {
+        return Err(msg!("cli.record_and_replay"));
+    }
+    // 余分な引数は黙って無視せずに誤りにする
+    if let Some(arg) = options.args.get(options.command.max_args()) {
+        if arg == "execute" || arg == "debug" {
+            return Err(msg!("cli.old_mode_argument", arg));
+        }
+        return Err(msg!("cli.unexpected_argument", arg));
+    }
+    if options.command == Command::Disk && options.args.len() < 2 {
+        return Err(msg!("cli.missing_disk_command"));
+    }
+    Ok(options)
+}
+
+/// `名前=値`のマクロの定義を追加する (値を省略すると1)
+fn define(defines: &mut HashMap<String, String>, define: &str) {
+    let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+    defines.insert(name.to_string(), value.to_string());
+}
+
+/// オプションの値を数値として読む
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    (value.parse()).map_err(|_| msg!("cli.not_a_number", name, value))
+}
+
+#[cfg(test)]
+mod test_cli {
+    use crate::cli::{parse, Command};
+    use crate::i18n::Language;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_subcommands_and_flags() {
+        let options = parse(&args(
+            "run a.asm --storage s.txt --mem-size 1024 --max-steps 100 --quantum 5 --input-file in.txt -q -DX=2",
+        ))
+        .unwrap();
+        assert_eq!(options.command, Command::Run);
+        assert_eq!(options.args, vec!["a.asm"]);
+        assert_eq!(options.storage.as_deref(), Some("s.txt"));
+        assert_eq!(options.mem_size, Some(1024));
+        assert_eq!(options.max_steps, Some(100));
+        assert_eq!(options.quantum, Some(5));
+        assert_eq!(options.input_file.as_deref(), Some("in.txt"));
+        assert!(options.quiet);
+        assert_eq!(options.defines["X"], "2");
+
+        // executeのような単語でモードが変わらない
+        let options = parse(&args("debug.asm")).unwrap();
+        assert_eq!(options.command, Command::Debug);
+        assert_eq!(options.args, vec!["debug.asm"]);
+        assert!(options.is_interactive());
+        assert!(!parse(&args("run debug.asm")).unwrap().is_interactive());
+
+        assert_eq!(
+            parse(&args("asm a.asm --help")).unwrap().command,
+            Command::Help
+        );
+        assert_eq!(parse(&args("--version")).unwrap().command, Command::Version);
+        assert_eq!(parse(&[]).unwrap().command, Command::Help);
+    }
+
+    #[test]
+    fn test_trace_filters_and_errors() {
+        let options = parse(&args("trace show run.jsonl --op store --writes")).unwrap();
+        assert_eq!(options.command, Command::Trace);
+        assert_eq!(options.args, args("show run.jsonl --op store --writes"));
+        assert!(parse(&args("trace diff a.jsonl")).is_err());
+        assert!(parse(&args("run a.asm --mem-size x")).is_err());
+        assert!(parse(&args("run a.asm --max-steps")).is_err());
+        assert!(parse(&args("run a.asm --unknown")).is_err());
+        assert_eq!(
+            parse(&args("run a.asm --lang en")).unwrap().lang,
+            Some(Language::English)
+        );
+        assert!(parse(&args("run a.asm --lang fr")).is_err());
+        assert!(parse(&args("run a.asm --record a.txt --replay a.txt")).is_err());
+        let options = parse(&args("run a.asm --device console --device timer@2000")).unwrap();
+        assert_eq!(options.devices, args("console timer@2000"));
+        let options = parse(&args("disk put a.img hello.txt /docs/hello.txt")).unwrap();
+        assert_eq!(options.command, Command::Disk);
+        assert_eq!(options.args, args("put a.img hello.txt /docs/hello.txt"));
+        assert!(parse(&args("disk create")).is_err());
+        assert!(parse(&args("a.asm execute")).is_err());
+        let options = parse(&args("a.asm --expand")).unwrap();
+        assert_eq!(options.command, Command::Asm);
+        assert_eq!(options.format.as_deref(), Some("expand"));
+        let options = parse(&args("asm a.asm --expand")).unwrap();
+        assert_eq!(options.format.as_deref(), Some("expand"));
+        assert!(parse(&args("run a.asm b.asm")).is_err());
+        assert!(parse(&args("repl a.asm")).is_err());
+        assert!(parse(&args("disk put a.img hello.txt /hello.txt extra")).is_err());
+        assert_eq!(parse(&args("link out.o a.o b.o")).unwrap().args.len(), 3);
+        assert_eq!(
+            parse(&args("run a.asm --disk a.img"))
+                .unwrap()
+                .disk
+                .as_deref(),
+            Some("a.img")
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+
+use crate::debug_info::DebugInfo;
+use crate::instruction::Instruction;
+use crate::msg;
+use crate::profile::Profile;
+
+/// ソースの1行の実行結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub line: usize,                       // 行番号
+    pub text: String,                      // 行の内容
+    pub hits: Option<u64>,                 // 実行した回数 (命令でない行はNone)
+    pub branch: Option<(usize, u64, u64)>, // 条件ジャンプのアドレスと飛んだ回数・飛ばなかった回数
+}
+
+/// ファイルごとの行の実行結果
+#[derive(Debug, Default)]
+pub struct Coverage {
+    files: BTreeMap<String, Vec<Line>>,
+}
+
+impl Coverage {
+    /// プロファイルの結果をソースの行に対応付ける
+    pub fn new(profile: &Profile, debug: &DebugInfo, memory: &[i32]) -> Coverage {
+        let hits = profile.cell_hits();
+        let starts: Vec<&usize> = debug.lines.keys().collect();
+        let mut coverage = Coverage::default();
+        for (i, (address, location)) in debug.lines.iter().enumerate() {
+            let end = starts.get(i + 1).map_or(address + 1, |next| **next);
+            // データ命令や空の行は実行するものではないので数えない
+            let code = !location.text.is_empty() && !location.text.starts_with('.');
+            let line_hits = (hits.get(*address..end.min(hits.len())))
+                .filter(|_| code)
+                .map(|hits| hits.iter().copied().max().unwrap_or(0));
+            let branch = match Instruction::decode(memory, *address) {
+                Some((Instruction::JumpIfZero, _)) if code => {
+                    let (taken, not_taken) = (profile.branches())
+                        .get(address)
+                        .copied()
+                        .unwrap_or_default();
+                    Some((*address, taken, not_taken))
+                }
+                _ => None,
+            };
+            let lines = coverage.files.entry(location.file.clone()).or_default();
+            match lines.iter_mut().find(|line| line.line == location.line) {
+                // 1行に複数の番地がある場合はまとめる
+                Some(line) => {
+                    line.hits = match (line.hits, line_hits) {
+                        (Some(a), Some(b)) => Some(a.max(b)),
+                        (a, b) => a.or(b),
+                    };
+                    line.branch = line.branch.or(branch);
+                }
+                None => lines.push(Line {
+                    line: location.line,
+                    text: location.text.clone(),
+                    hits: line_hits,
+                    branch,
+                }),
+            }
+        }
+        for lines in coverage.files.values_mut() {
+            lines.sort_by_key(|line| line.line);
+        }
+        coverage
+    }
+
+    /// 実行した行の数と命令のある行の数
+    pub fn line_summary(&self) -> (usize, usize) {
+        let lines = self
+            .files
+            .values()
+            .flatten()
+            .filter(|line| line.hits.is_some());
+        let total = lines.clone().count();
+        (lines.filter(|line| line.hits > Some(0)).count(), total)
+    }
+
+    /// 通った分岐の数と分岐の数 (条件ジャンプ1つにつき飛ぶ・飛ばないの2つ)
+    pub fn branch_summary(&self) -> (usize, usize) {
+        let branches = self.files.values().flatten().filter_map(|line| line.branch);
+        let total = branches.clone().count() * 2;
+        let hit = branches
+            .map(|(_, taken, not_taken)| (taken > 0) as usize + (not_taken > 0) as usize)
+            .sum();
+        (hit, total)
+    }
+
+    /// LCOVの形式にする
+    pub fn lcov(&self) -> String {
+        let mut text = String::new();
+        for (file, lines) in &self.files {
+            text += &format!("TN:\nSF:{file}\n");
+            let (mut found, mut hit) = (0, 0);
+            let (mut branches_found, mut branches_hit) = (0, 0);
+            for line in lines {
+                if let Some((address, taken, not_taken)) = line.branch {
+                    // 一度も実行していない分岐は - にする
+                    let executed = taken + not_taken > 0;
+                    for (index, count) in [taken, not_taken].into_iter().enumerate() {
+                        let count = if executed {
+                            count.to_string()
+                        } else {
+                            "-".to_string()
+                        };
+                        text += &format!("BRDA:{},{address},{index},{count}\n", line.line);
+                    }
+                    branches_found += 2;
+                    branches_hit += (taken > 0) as usize + (not_taken > 0) as usize;
+                }
+            }
+            for line in lines {
+                if let Some(hits) = line.hits {
+                    text += &format!("DA:{},{hits}\n", line.line);
+                    found += 1;
+                    hit += (hits > 0) as usize;
+                }
+            }
+            text += &format!("BRF:{branches_found}\nBRH:{branches_hit}\n");
+            text += &format!("LF:{found}\nLH:{hit}\nend_of_record\n");
+        }
+        text
+    }
+
+    /// 行ごとに実行回数を付けたソースの一覧にする
+    pub fn listing(&self) -> String {
+        let (hit, total) = self.line_summary();
+        let (branches_hit, branches) = self.branch_summary();
+        let lines = format!("{hit}/{total} ({:.1}%)", percent(hit, total));
+        let branches = format!(
+            "{branches_hit}/{branches} ({:.1}%)",
+            percent(branches_hit, branches)
+        );
+        let mut text = format!("{}\n", msg!("coverage.summary", lines, branches));
+        for (file, lines) in &self.files {
+            text += &format!("\n== {file}\n");
+            for line in lines {
+                let hits = match line.hits {
+                    Some(0) => "#####".to_string(),
+                    Some(hits) => hits.to_string(),
+                    None => "-".to_string(),
+                };
+                let branch = match line.branch {
+                    Some((_, taken, not_taken)) => {
+                        format!("  [{}]", msg!("coverage.branch", taken, not_taken))
+                    }
+                    None => String::new(),
+                };
+                text += &format!("{hits:>8} | {:>4} | {}{branch}\n", line.line, line.text);
+            }
+        }
+        text
+    }
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod test_coverage {
+    use crate::coverage::Coverage;
+    use crate::profile::Profile;
+    use crate::testing;
+
+    #[test]
+    fn test_lines_and_branches() {
+        // 入力が0ならskipへ飛び、それ以外なら出力する
+        let code = "push\nskip\ninput\njump\npush\n65\noutput\nskip: halt\n.word 1\n";
+        let mut vm = testing::machine("branch.asm", code);
+        vm.push_input("0".to_string());
+        let profile = Profile::run(&mut vm);
+        let coverage = Coverage::new(&profile, vm.debug_info(), vm.memory());
+
+        assert_eq!(coverage.line_summary(), (5, 8));
+        assert_eq!(coverage.branch_summary(), (1, 2));
+        let lcov = coverage.lcov();
+        assert!(lcov.contains("SF:branch.asm\nBRDA:4,3,0,1\nBRDA:4,3,1,0\n"));
+        assert!(lcov.contains("DA:6,0\n"));
+        assert!(!lcov.contains("DA:9,"));
+        assert!(lcov.ends_with("LF:8\nLH:5\nend_of_record\n"));
+        let listing = coverage.listing();
+        assert!(listing.contains("   ##### |    5 | push\n"));
+        assert!(listing.contains("       1 |    4 | jump  [飛んだ1回 飛ばなかった0回]\n"));
+        assert!(listing.contains("       - |    9 | .word 1\n"));
+    }
+}
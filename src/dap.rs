@@ -0,0 +1,607 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::assembly;
+use crate::json::Json;
+use crate::msg;
+use crate::vm::{Mode, State, VirtualMachine};
+
+/// 変数の参照番号 (メモリの行は行番号に足して使う)
+const STACK: i64 = 1;
+const REGISTERS: i64 = 2;
+const MEMORY: i64 = 3;
+const MEMORY_ROW: i64 = 1000;
+
+/// メモリを表示するときの1行のセル数
+const ROW_SIZE: usize = 16;
+
+/// 戻れる命令の数
+const HISTORY_SIZE: usize = 10000;
+
+/// 実行中にリクエストを確認するまでに実行する命令の数
+const POLL_INTERVAL: usize = 1024;
+
+/// 実行中に届いたリクエストの扱い
+enum Poll {
+    Continue,    // 実行を続ける
+    Pause(Json), // 一時停止のリクエストに答えて止まる
+    Stop,        // 切断されたので止まる
+}
+
+/// Debug Adapter Protocolでエディタから仮想マシンを操作するサーバー
+pub struct Server<W: Write> {
+    out: W,                                   // メッセージの送り先
+    seq: i64,                                 // 最後に送ったメッセージの番号
+    vm: Option<VirtualMachine>,               // 起動したプログラム
+    breakpoints: HashMap<String, Vec<usize>>, // ソースごとのブレークポイントのアドレス
+    stop_on_entry: bool,                      // 起動した直後に停止するか
+    printed: usize,                           // 出力イベントで送った出力の長さ
+    requests: Option<Receiver<String>>,       // 読み込んだメッセージ
+    pending: VecDeque<String>,                // 実行中に届いて後で処理するメッセージ
+}
+
+/// 標準入出力でエディタと通信する
+pub fn serve() -> io::Result<()> {
+    let mut server = Server::new(io::stdout());
+    server.run(io::BufReader::new(io::stdin()))
+}
+
+/// Content-Lengthの付いたメッセージを1つ読む (終端ならNone)
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).to_string()))
+}
+
+impl<W: Write> Server<W> {
+    pub fn new(out: W) -> Server<W> {
+        Server {
+            out,
+            seq: 0,
+            vm: None,
+            breakpoints: HashMap::new(),
+            stop_on_entry: false,
+            printed: 0,
+            requests: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// 切断されるまでリクエストを処理する
+    /// (実行中も一時停止を受け取れるように、メッセージは別のスレッドで読む)
+    pub fn run(&mut self, mut input: impl BufRead + Send + 'static) -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(Some(message)) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        self.requests = Some(receiver);
+        while let Some(message) = self.next_message() {
+            let request = match Json::parse(&message) {
+                Ok(request) => request,
+                Err(e) => {
+                    self.output("stderr", format!("{}\n", msg!("vm.fault", e)))?;
+                    continue;
+                }
+            };
+            if !self.handle(&request)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// 実行中に届いたものを先に、なければ次のメッセージを待って返す (切断されたらNone)
+    fn next_message(&mut self) -> Option<String> {
+        (self.pending.pop_front()).or_else(|| self.requests.as_ref()?.recv().ok())
+    }
+
+    /// メッセージを送る
+    fn send(&mut self, fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = vec![("seq", Json::from(self.seq))];
+        message.extend(fields);
+        let body = Json::object(message).to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.out.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut message = vec![("type", "event".into()), ("event", event.into())];
+        if body != Json::Null {
+            message.push(("body", body));
+        }
+        self.send(message)
+    }
+
+    fn output(&mut self, category: &str, output: String) -> io::Result<()> {
+        let body = Json::object(vec![
+            ("category", category.into()),
+            ("output", output.into()),
+        ]);
+        self.event("output", body)
+    }
+
+    /// リクエストを1つ処理する (切断されたらfalseを返す)
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let args = request.get("arguments").unwrap_or(&Json::Null);
+        let result = match command {
+            "initialize" => Ok(Json::object(vec![
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsStepBack", true.into()),
+            ])),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "threads" => Ok(Json::object(vec![(
+                "threads",
+                vec![Json::object(vec![
+                    ("id", 1.into()),
+                    ("name", "main".into()),
+                ])]
+                .into(),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object(vec![(
+                "scopes",
+                vec![
+                    scope(&msg!("dap.scope.stack"), STACK),
+                    scope(&msg!("dap.scope.registers"), REGISTERS),
+                    scope(&msg!("dap.scope.memory"), MEMORY),
+                ]
+                .into(),
+            )])),
+            "variables" => {
+                let reference = args.get("variablesReference").and_then(Json::as_i64);
+                self.variables(reference.unwrap_or(0))
+            }
+            "continue" => Ok(Json::object(vec![("allThreadsContinued", true.into())])),
+            "configurationDone" | "next" | "stepIn" | "stepBack" | "reverseContinue" | "pause"
+            | "disconnect" | "terminate" => Ok(Json::Null),
+            _ => Err(msg!("dap.unsupported", command)),
+        };
+        let success = result.is_ok();
+        let mut response = vec![
+            ("type", "response".into()),
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            ("success", success.into()),
+            ("command", command.into()),
+        ];
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => response.push(("body", body)),
+            Err(message) => response.push(("message", message.into())),
+        }
+        self.send(response)?;
+        if !success {
+            return Ok(true);
+        }
+
+        // 返信の後に実行して停止したことを知らせる
+        match command {
+            "launch" => self.event("initialized", Json::Null)?,
+            "configurationDone" if self.stop_on_entry => self.stopped("entry", None)?,
+            "configurationDone" | "continue" => self.resume(false)?,
+            "next" | "stepIn" => self.resume(true)?,
+            "stepBack" => self.reverse(true)?,
+            "reverseContinue" => self.reverse(false)?,
+            "pause" => self.stopped("pause", None)?,
+            "disconnect" | "terminate" => return Ok(false),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// プログラムをアセンブルして起動する
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let program =
+            (args.get("program").and_then(Json::as_str)).ok_or(msg!("dap.missing_program"))?;
+        let code =
+            fs::read_to_string(program).map_err(|e| msg!("common.read_failed", program, e))?;
+        let storage = crate::io::open_file(program.to_string()).map_err(|e| e.to_string())?;
+        let (object, messages) =
+            assembly::capture(|| assembly::assembly(code, Path::new(program), &HashMap::new()));
+        let errors: Vec<&str> = (messages.iter())
+            .map(String::as_str)
+            .filter(|message| assembly::is_error(message))
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+
+        let mut vm = VirtualMachine::new(storage, object, Mode::Embedded);
+        vm.set_history_size(HISTORY_SIZE);
+        // 入力命令にはinputに書いた値を順番に渡す
+        for value in args.get("input").and_then(Json::as_array).unwrap_or(&[]) {
+            vm.push_input(match value {
+                Json::String(text) => text.clone(),
+                value => value.to_string(),
+            });
+        }
+        self.stop_on_entry = (args.get("stopOnEntry").and_then(Json::as_bool)).unwrap_or(false);
+        self.printed = 0;
+        self.vm = Some(vm);
+        Ok(Json::Null)
+    }
+
+    /// ソースの行にブレークポイントを設定する
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let path = (args.get("source").and_then(|source| source.get("path")))
+            .and_then(Json::as_str)
+            .ok_or(msg!("dap.missing_source"))?;
+        let lines = (args.get("breakpoints").and_then(Json::as_array)).unwrap_or(&[]);
+        let mut addresses = Vec::new();
+        let mut result = Vec::new();
+        for line in lines
+            .iter()
+            .filter_map(|b| b.get("line").and_then(Json::as_i64))
+        {
+            let found = (self.vm.as_ref())
+                .and_then(|vm| vm.debug_info().line_address(Path::new(path), line as usize));
+            result.push(match found {
+                Some((address, actual)) => {
+                    addresses.push(address);
+                    Json::object(vec![("verified", true.into()), ("line", actual.into())])
+                }
+                None => Json::object(vec![
+                    ("verified", false.into()),
+                    ("line", line.into()),
+                    ("message", msg!("dap.no_instruction").into()),
+                ]),
+            });
+        }
+        self.breakpoints.insert(path.to_string(), addresses);
+        Ok(Json::object(vec![("breakpoints", result.into())]))
+    }
+
+    /// 次に実行する命令の位置を1つのフレームとして返す
+    fn stack_trace(&self) -> Result<Json, String> {
+        let vm = self.vm.as_ref().ok_or(msg!("dap.not_launched"))?;
+        let debug = vm.debug_info();
+        let name = (debug.symbolize(vm.pc())).unwrap_or(msg!("common.address", vm.pc()));
+        let mut frame = vec![("id", 1.into()), ("name", name.into())];
+        match debug.location(vm.pc()) {
+            Some(location) => {
+                let file = Path::new(&location.file);
+                let source = Json::object(vec![
+                    (
+                        "name",
+                        (file.file_name())
+                            .map_or(String::new(), |n| n.to_string_lossy().to_string())
+                            .into(),
+                    ),
+                    ("path", location.file.as_str().into()),
+                ]);
+                frame.push(("source", source));
+                frame.push(("line", location.line.into()));
+                frame.push(("column", location.column.into()));
+            }
+            None => {
+                frame.push(("line", 0.into()));
+                frame.push(("column", 0.into()));
+            }
+        }
+        Ok(Json::object(vec![
+            ("stackFrames", vec![Json::object(frame)].into()),
+            ("totalFrames", 1.into()),
+        ]))
+    }
+
+    /// スタック・レジスタ・メモリの内容を変数として返す
+    fn variables(&self, reference: i64) -> Result<Json, String> {
+        let vm = self.vm.as_ref().ok_or(msg!("dap.not_launched"))?;
+        let variables: Vec<Json> = match reference {
+            STACK => (vm.stack().iter().enumerate())
+                .map(|(i, value)| variable(format!("[{i}]"), value.to_string(), 0))
+                .collect(),
+            REGISTERS => vec![
+                variable("pc".to_string(), vm.pc().to_string(), 0),
+                variable("sp".to_string(), vm.stack().len().to_string(), 0),
+            ],
+            MEMORY => (vm.memory().chunks(ROW_SIZE).enumerate())
+                .map(|(row, cells)| {
+                    let values: Vec<String> = cells.iter().map(|cell| cell.to_string()).collect();
+                    let name = format!("{:04}", row * ROW_SIZE);
+                    variable(name, values.join(" "), MEMORY_ROW + row as i64)
+                })
+                .collect(),
+            reference if reference >= MEMORY_ROW => {
+                let start = (reference - MEMORY_ROW) as usize * ROW_SIZE;
+                let end = (start + ROW_SIZE).min(vm.memory().len());
+                (start..end)
+                    .map(|address| {
+                        variable(format!("[{address}]"), vm.memory()[address].to_string(), 0)
+                    })
+                    .collect()
+            }
+            _ => return Err(msg!("dap.unknown_reference", reference)),
+        };
+        Ok(Json::object(vec![("variables", variables.into())]))
+    }
+
+    /// 1命令かブレークポイントまで実行する
+    fn resume(&mut self, single: bool) -> io::Result<()> {
+        let Some(vm) = self.vm.as_mut() else {
+            return Ok(());
+        };
+        if !vm.is_running() {
+            return self.terminated();
+        }
+        let mut count = 0;
+        let poll = loop {
+            vm.step();
+            let pc = vm.pc();
+            let at_breakpoint = self.breakpoints.values().any(|b| b.contains(&pc));
+            if single || !vm.is_running() || at_breakpoint {
+                break Poll::Continue;
+            }
+            count += 1;
+            if count % POLL_INTERVAL == 0 {
+                match poll(self.requests.as_ref(), &mut self.pending) {
+                    Poll::Continue => {}
+                    poll => break poll,
+                }
+            }
+        };
+        self.flush_output()?;
+        match poll {
+            Poll::Pause(request) => return self.handle(&request).map(|_| ()),
+            Poll::Stop => return Ok(()),
+            Poll::Continue => {}
+        }
+        let reason = if single { "step" } else { "breakpoint" };
+        match self.vm.as_ref().map(|vm| vm.state().clone()) {
+            Some(State::Running) => self.stopped(reason, None),
+            Some(State::Fault(message)) => {
+                self.output("stderr", format!("{}\n", msg!("vm.fault", &message)))?;
+                self.stopped("exception", Some(message))
+            }
+            _ => self.terminated(),
+        }
+    }
+
+    /// 1命令かブレークポイントまで戻る
+    fn reverse(&mut self, single: bool) -> io::Result<()> {
+        if let Some(vm) = self.vm.as_mut() {
+            while vm.step_back().is_ok() {
+                let pc = vm.pc();
+                if single || self.breakpoints.values().any(|b| b.contains(&pc)) {
+                    break;
+                }
+            }
+            self.printed = self.printed.min(vm.output().len());
+        }
+        self.stopped(if single { "step" } else { "breakpoint" }, None)
+    }
+
+    /// まだ送っていない出力を出力イベントで送る
+    fn flush_output(&mut self) -> io::Result<()> {
+        let Some(vm) = self.vm.as_ref() else {
+            return Ok(());
+        };
+        let output = vm.output()[self.printed..].to_string();
+        self.printed = vm.output().len();
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.output("stdout", output)
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", 1.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text", text.into()));
+        }
+        self.event("stopped", Json::object(body))
+    }
+
+    fn terminated(&mut self) -> io::Result<()> {
+        let code = match self.vm.as_ref().map(VirtualMachine::state) {
+            Some(State::Fault(_)) => 1,
+            _ => 0,
+        };
+        self.event("exited", Json::object(vec![("exitCode", code.into())]))?;
+        self.event("terminated", Json::Null)
+    }
+}
+
+/// 実行中に届いたメッセージを確認する
+/// (一時停止と切断以外は止まった後で処理するように残しておく)
+fn poll(requests: Option<&Receiver<String>>, pending: &mut VecDeque<String>) -> Poll {
+    let Some(requests) = requests else {
+        return Poll::Continue;
+    };
+    loop {
+        let message = match requests.try_recv() {
+            Ok(message) => message,
+            Err(TryRecvError::Empty) => return Poll::Continue,
+            Err(TryRecvError::Disconnected) => return Poll::Stop,
+        };
+        let Ok(request) = Json::parse(&message) else {
+            pending.push_back(message);
+            continue;
+        };
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        match command.to_string().as_str() {
+            "pause" => return Poll::Pause(request),
+            "disconnect" | "terminate" => {
+                pending.push_back(message);
+                return Poll::Stop;
+            }
+            _ => pending.push_back(message),
+        }
+    }
+}
+
+fn scope(name: &str, reference: i64) -> Json {
+    Json::object(vec![
+        ("name", name.into()),
+        ("variablesReference", reference.into()),
+        ("expensive", false.into()),
+    ])
+}
+
+fn variable(name: String, value: String, reference: i64) -> Json {
+    Json::object(vec![
+        ("name", name.into()),
+        ("value", value.into()),
+        ("variablesReference", reference.into()),
+    ])
+}
+
+#[cfg(test)]
+mod test_dap {
+    use std::io::{BufReader, Cursor};
+
+    use crate::dap::{read_message, Server};
+    use crate::json::Json;
+    use crate::testing;
+
+    /// リクエストをContent-Length付きのメッセージにする
+    fn request(seq: usize, command: &str, arguments: &str) -> String {
+        let body = format!(
+            "{{\"seq\":{seq},\"type\":\"request\",\"command\":\"{command}\",\"arguments\":{arguments}}}"
+        );
+        format!("Content-Length: {}\r\n\r\n{body}", body.len())
+    }
+
+    /// リクエストを順番に送って、返ってきたメッセージを読む
+    fn session(input: String) -> Vec<Json> {
+        let mut output = Vec::new();
+        Server::new(&mut output).run(Cursor::new(input)).unwrap();
+        let mut reader = BufReader::new(output.as_slice());
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(Json::parse(&message).unwrap());
+        }
+        messages
+    }
+
+    /// メッセージの種類 (返信ならコマンド、イベントならイベントの名前)
+    fn kinds(messages: &[Json]) -> Vec<&str> {
+        (messages.iter())
+            .map(|m| {
+                (m.get("command").or(m.get("event")))
+                    .and_then(Json::as_str)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_debug_session() {
+        let path = testing::temp_path("dap.asm");
+        std::fs::write(&path, "push\n72\noutput\npush\n73\noutput\nhalt\n").unwrap();
+        let program = path.display().to_string().replace('\\', "\\\\");
+        let input = [
+            request(1, "initialize", "{}"),
+            request(2, "launch", &format!("{{\"program\":\"{program}\"}}")),
+            request(
+                3,
+                "setBreakpoints",
+                &format!(
+                    "{{\"source\":{{\"path\":\"{program}\"}},\"breakpoints\":[{{\"line\":4}}]}}"
+                ),
+            ),
+            request(4, "configurationDone", "{}"),
+            request(5, "variables", "{\"variablesReference\":2}"),
+            request(6, "continue", "{}"),
+            request(7, "disconnect", "{}"),
+        ]
+        .concat();
+
+        let messages = session(input);
+        assert_eq!(
+            kinds(&messages),
+            vec![
+                "initialize",
+                "launch",
+                "initialized",
+                "setBreakpoints",
+                "configurationDone",
+                "output",
+                "stopped",
+                "variables",
+                "continue",
+                "output",
+                "exited",
+                "terminated",
+                "disconnect"
+            ]
+        );
+        let breakpoint = &messages[3].get("body").unwrap().get("breakpoints").unwrap();
+        assert_eq!(
+            breakpoint.as_array().unwrap()[0].get("verified"),
+            Some(&Json::Bool(true))
+        );
+        let pc = &messages[7].get("body").unwrap().get("variables").unwrap();
+        assert_eq!(
+            pc.as_array().unwrap()[0].get("value"),
+            Some(&Json::from("3"))
+        );
+        assert_eq!(
+            messages[9].get("body").unwrap().get("output"),
+            Some(&Json::from("I"))
+        );
+    }
+
+    #[test]
+    fn test_pause_while_running() {
+        let path = testing::temp_path("dap_loop.asm");
+        std::fs::write(&path, "loop: push\nloop\npush\n0\njump\n").unwrap();
+        let program = path.display().to_string().replace('\\', "\\\\");
+        let input = [
+            request(1, "launch", &format!("{{\"program\":\"{program}\"}}")),
+            request(2, "configurationDone", "{}"),
+            request(3, "stepOut", "{}"),
+            request(4, "pause", "{}"),
+            request(5, "disconnect", "{}"),
+        ]
+        .concat();
+
+        let messages = session(input);
+        assert_eq!(
+            kinds(&messages),
+            vec![
+                "launch",
+                "initialized",
+                "configurationDone",
+                "pause",
+                "stopped",
+                "stepOut",
+                "disconnect"
+            ]
+        );
+        let stopped = messages[4].get("body").unwrap();
+        assert_eq!(stopped.get("reason"), Some(&Json::from("pause")));
+        assert_eq!(messages[5].get("success"), Some(&Json::Bool(false)));
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::msg;
+
+/// ソース上の位置
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,  // ファイル名
+    pub line: usize,   // 行番号
+    pub column: usize, // 列番号
+    pub text: String,  // 行の内容
+}
+
+/// メモリアドレスとソースを対応付けるデバッグ情報
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    pub lines: BTreeMap<usize, Location>, // アドレスから始まる行の位置
+    pub labels: Vec<(String, usize)>,     // ラベルとそのアドレス
+}
+
+impl DebugInfo {
+    /// アドレスを含む行の位置を求める
+    pub fn location(&self, address: usize) -> Option<&Location> {
+        self.lines
+            .range(..=address)
+            .next_back()
+            .map(|(_, location)| location)
+    }
+
+    /// アドレスを直前のラベルからの相対位置で表す
+    pub fn symbolize(&self, address: usize) -> Option<String> {
+        let (name, base) = self
+            .labels
+            .iter()
+            .filter(|(_, base)| *base <= address)
+            .max_by_key(|(_, base)| *base)?;
+        if *base == address {
+            Some(name.clone())
+        } else {
+            Some(format!("{name}+{}", address - base))
+        }
+    }
+
+    /// ラベルのアドレスを求める
+    pub fn address(&self, label: &str) -> Option<usize> {
+        self.labels
+            .iter()
+            .find(|(name, _)| name == label)
+            .map(|(_, address)| *address)
+    }
+
+    /// ソースの行から始まる命令のアドレスを求める
+    /// (命令がない行なら後ろで一番近い行を使い、そのアドレスと行番号を返す)
+    pub fn line_address(&self, file: &Path, line: usize) -> Option<(usize, usize)> {
+        let canonical = fs::canonicalize(file).ok();
+        let same_file = |name: &str| {
+            Path::new(name) == file
+                || (canonical.is_some() && fs::canonicalize(name).ok() == canonical)
+        };
+        self.lines
+            .iter()
+            .filter(|(_, location)| location.line >= line && same_file(&location.file))
+            .min_by_key(|(address, location)| (location.line, **address))
+            .map(|(address, location)| (*address, location.line))
+    }
+
+    /// アドレスの位置をソースの表記で表す
+    pub fn describe(&self, address: usize) -> String {
+        let mut text = match self.location(address) {
+            Some(location) => msg!(
+                "debug_info.location",
+                location.file,
+                location.line,
+                location.column,
+                location.text
+            ),
+            None => msg!("debug_info.unknown"),
+        };
+        if let Some(label) = self.symbolize(address) {
+            text = format!("<{label}> {text}");
+        }
+        text
+    }
+
+    /// 指定した位置に配置したモジュールのデバッグ情報を追加する
+    pub fn append(&mut self, other: &DebugInfo, base: usize) {
+        for (offset, location) in &other.lines {
+            self.lines.insert(base + offset, location.clone());
+        }
+        for (name, offset) in &other.labels {
+            self.labels.push((name.clone(), base + offset));
+        }
+    }
+}
@@ -0,0 +1,744 @@
+use std::fs;
+
+use crate::instruction::Instruction;
+use crate::io;
+use crate::msg;
+use crate::process::Status;
+use crate::vm::{Access, VirtualMachine};
+
+/// 最初に履歴に残す命令の数
+const HISTORY_SIZE: usize = 10000;
+
+/// 番地の指定
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Address(usize), // 番地
+    Label(String),  // ラベル
+}
+
+/// 条件式の項
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Number(i32),    // 数値
+    Memory(Target), // mem[番地]
+    Stack(usize),   // stack[位置]
+    Top,            // スタックの一番上
+    Sp,             // スタックの長さ
+    Pc,             // プログラムカウンタ
+}
+
+/// ブレークポイントの条件式
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    left: Operand,  // 左辺
+    op: String,     // 比較演算子
+    right: Operand, // 右辺
+    text: String,   // 入力された条件式
+}
+
+/// デバッガのコマンド
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Step(usize),
+    Continue,
+    Back(usize),
+    ReverseContinue,
+    History(Option<usize>),
+    Break(Target, Option<Condition>),
+    WatchMemory(Target),
+    WatchStorage(usize),
+    Ignore(usize, usize),
+    Info,
+    Delete(Option<usize>),
+    PrintMemory(Option<(Target, Target)>),
+    SetMemory(Target, i32),
+    SetStack(usize, i32),
+    Stack,
+    Regs,
+    Processes,
+    Disasm(Option<Target>, usize),
+    Where,
+    Output,
+    Save(String),
+    Load(String),
+    Help,
+    Quit,
+}
+
+/// ブレークポイント
+#[derive(Debug, Clone)]
+struct Breakpoint {
+    id: usize,                    // 番号
+    address: usize,               // 番地
+    condition: Option<Condition>, // 停止する条件
+    hits: usize,                  // 条件が成立した回数
+    ignore: usize,                // 無視する回数
+}
+
+/// ウォッチポイント
+#[derive(Debug, Clone)]
+struct Watchpoint {
+    id: usize,      // 番号
+    access: Access, // 監視する場所
+    hits: usize,    // 書き込まれた回数
+}
+
+/// 対話型デバッガ
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>, // 設定されたブレークポイント
+    watchpoints: Vec<Watchpoint>, // 設定されたウォッチポイント
+    next_id: usize,               // 次に割り当てる番号
+}
+
+/// コマンドを解析する
+pub fn parse(line: &str) -> Result<Command, String> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let command = match tokens.as_slice() {
+        [] | ["step"] => Command::Step(1),
+        ["step", count] => Command::Step(repeat(count)?),
+        ["continue"] => Command::Continue,
+        ["back"] => Command::Back(1),
+        ["back", count] => Command::Back(repeat(count)?),
+        ["reverse-continue"] => Command::ReverseContinue,
+        ["history"] => Command::History(None),
+        ["history", size] => Command::History(Some(number(size)?)),
+        ["break", target] => Command::Break(parse_target(target)?, None),
+        ["break", target, "if", condition @ ..] if !condition.is_empty() => Command::Break(
+            parse_target(target)?,
+            Some(parse_condition(&condition.join(" "))?),
+        ),
+        ["watch", "mem", target] => Command::WatchMemory(parse_target(target)?),
+        ["watch", "storage", line] => Command::WatchStorage(number(line)?),
+        ["ignore", id, count] => Command::Ignore(number(id)?, number(count)?),
+        ["info"] => Command::Info,
+        ["delete"] => Command::Delete(None),
+        ["delete", id] => Command::Delete(Some(number(id)?)),
+        ["print", "mem"] => Command::PrintMemory(None),
+        ["print", "mem", range] => {
+            let (from, to) = range
+                .split_once("..")
+                .ok_or(msg!("debugger.range_format", range))?;
+            Command::PrintMemory(Some((parse_target(from)?, parse_target(to)?)))
+        }
+        ["set", "mem", target, value] => {
+            Command::SetMemory(parse_target(target)?, value_of(value)?)
+        }
+        ["set", "stack", index, value] => Command::SetStack(number(index)?, value_of(value)?),
+        ["stack"] => Command::Stack,
+        ["regs"] => Command::Regs,
+        ["ps"] => Command::Processes,
+        ["disasm"] => Command::Disasm(None, 10),
+        ["disasm", target] => Command::Disasm(Some(parse_target(target)?), 10),
+        ["disasm", target, count] => Command::Disasm(Some(parse_target(target)?), number(count)?),
+        ["where"] => Command::Where,
+        ["output"] => Command::Output,
+        ["save", path] => Command::Save(path.to_string()),
+        ["load", path] => Command::Load(path.to_string()),
+        ["help"] => Command::Help,
+        ["quit"] | ["exit"] => Command::Quit,
+        [name, ..] => {
+            let help = msg!("debugger.help");
+            let known = help
+                .lines()
+                .any(|help| help.split_whitespace().next() == Some(name));
+            return Err(if known {
+                format!("{}\n{help}", msg!("debugger.invalid_arguments", name))
+            } else {
+                msg!("debugger.unknown_command", name)
+            });
+        }
+    };
+    Ok(command)
+}
+
+/// 回数や位置などの0以上の数値を解析する
+fn number(token: &str) -> Result<usize, String> {
+    token
+        .parse()
+        .map_err(|_| msg!("debugger.not_a_count", token))
+}
+
+/// 繰り返す回数を解析する (0回は誤り)
+fn repeat(token: &str) -> Result<usize, String> {
+    match number(token)? {
+        0 => Err(msg!("debugger.zero_count", token)),
+        count => Ok(count),
+    }
+}
+
+/// 書き込む値を解析する
+fn value_of(token: &str) -> Result<i32, String> {
+    token
+        .parse()
+        .map_err(|_| msg!("debugger.not_a_number", token))
+}
+
+/// 番地かラベルを解析する
+fn parse_target(token: &str) -> Result<Target, String> {
+    if token.starts_with(|c: char| c.is_ascii_digit()) {
+        Ok(Target::Address(number(token)?))
+    } else if token.is_empty() {
+        Err(msg!("debugger.missing_address"))
+    } else {
+        Ok(Target::Label(token.to_string()))
+    }
+}
+
+/// ブレークポイントの条件式を解析する
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    for op in ["==", "!=", "<=", ">=", "<", ">"] {
+        if let Some((left, right)) = text.split_once(op) {
+            return Ok(Condition {
+                left: parse_operand(left)?,
+                op: op.to_string(),
+                right: parse_operand(right)?,
+                text: text.to_string(),
+            });
+        }
+    }
+    Err(msg!("debugger.missing_operator", text))
+}
+
+/// 条件式の項を解析する
+fn parse_operand(token: &str) -> Result<Operand, String> {
+    let token = token.trim();
+    let index = |name: &str| {
+        token
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('['))
+            .and_then(|rest| rest.strip_suffix(']'))
+    };
+    if let Some(target) = index("mem") {
+        Ok(Operand::Memory(parse_target(target.trim())?))
+    } else if let Some(position) = index("stack") {
+        Ok(Operand::Stack(number(position.trim())?))
+    } else {
+        match token {
+            "top" => Ok(Operand::Top),
+            "sp" => Ok(Operand::Sp),
+            "pc" => Ok(Operand::Pc),
+            _ => Ok(Operand::Number(value_of(token)?)),
+        }
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /// コマンドを受け付けながらプログラムを実行する
+    pub fn run(&mut self, vm: &mut VirtualMachine) {
+        println!("{}", msg!("common.running"));
+        println!("{}", msg!("debugger.help_hint"));
+        vm.set_history_size(HISTORY_SIZE);
+        let mut stopped = false;
+        loop {
+            let line = if vm.is_running() {
+                stopped = false;
+                io::input(&msg!("debugger.prompt"))
+            } else {
+                if !stopped {
+                    vm.report_fault();
+                    stopped = true;
+                }
+                if vm.history_len() == 0 {
+                    break;
+                }
+                // 終了した後もbackで戻れる
+                let line = io::input(&msg!("debugger.finished_prompt"));
+                if line.trim().is_empty() {
+                    break;
+                }
+                line
+            };
+            if let Err(e) = parse(&line).and_then(|command| self.command(vm, command)) {
+                println!("{}", msg!("vm.fault", e));
+            }
+        }
+    }
+
+    /// コマンドを実行する
+    fn command(&mut self, vm: &mut VirtualMachine, command: Command) -> Result<(), String> {
+        match command {
+            Command::Step(count) => self.resume(vm, Some(count)),
+            Command::Continue => {
+                println!("{}", msg!("debugger.continue"));
+                self.resume(vm, None);
+            }
+            Command::Back(count) => self.reverse(vm, Some(count)),
+            Command::ReverseContinue => self.reverse(vm, None),
+            Command::History(None) => println!(
+                "{}",
+                msg!("debugger.history", vm.history_len(), vm.history_size())
+            ),
+            Command::History(Some(size)) => {
+                vm.set_history_size(size);
+                println!("{}", msg!("debugger.history_set", size));
+            }
+            Command::Break(target, condition) => {
+                let address = resolve(vm, &target)?;
+                println!(
+                    "{}",
+                    msg!(
+                        "debugger.break_set",
+                        self.next_id,
+                        address,
+                        vm.debug_info().describe(address)
+                    )
+                );
+                self.breakpoints.push(Breakpoint {
+                    id: self.next_id,
+                    address,
+                    condition,
+                    hits: 0,
+                    ignore: 0,
+                });
+                self.next_id += 1;
+            }
+            Command::WatchMemory(target) => {
+                let address = resolve(vm, &target)?;
+                self.watch(Access::Memory(address));
+            }
+            Command::WatchStorage(line) => self.watch(Access::Storage(line)),
+            Command::Ignore(id, count) => {
+                let breakpoint = (self.breakpoints.iter_mut())
+                    .find(|breakpoint| breakpoint.id == id)
+                    .ok_or(msg!("debugger.no_breakpoint", id))?;
+                breakpoint.ignore = breakpoint.hits + count;
+                println!("{}", msg!("debugger.ignore", id, count));
+            }
+            Command::Info => {
+                for breakpoint in &self.breakpoints {
+                    let condition = match &breakpoint.condition {
+                        Some(condition) => msg!("debugger.info.condition", condition.text),
+                        None => String::new(),
+                    };
+                    println!(
+                        "{}",
+                        msg!(
+                            "debugger.info.breakpoint",
+                            breakpoint.id,
+                            breakpoint.address,
+                            condition,
+                            breakpoint.hits,
+                            vm.debug_info().describe(breakpoint.address)
+                        )
+                    );
+                }
+                for (name, base, size) in vm.bus().devices() {
+                    println!(
+                        "{}",
+                        msg!("debugger.info.device", name, base, base + size - 1)
+                    );
+                }
+                for watchpoint in &self.watchpoints {
+                    println!(
+                        "{}",
+                        msg!(
+                            "debugger.info.watchpoint",
+                            watchpoint.id,
+                            describe_access(watchpoint.access),
+                            watchpoint.hits
+                        )
+                    );
+                }
+            }
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                println!("{}", msg!("debugger.deleted_all"));
+            }
+            Command::Delete(Some(id)) => {
+                let before = self.breakpoints.len() + self.watchpoints.len();
+                self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+                self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+                if self.breakpoints.len() + self.watchpoints.len() == before {
+                    return Err(msg!("debugger.no_point", id));
+                }
+                println!("{}", msg!("debugger.deleted", id));
+            }
+            Command::PrintMemory(None) => {
+                println!("+-- {}", msg!("debugger.memory"));
+                for (i, value) in vm.memory().iter().enumerate() {
+                    if *value != 0 {
+                        println!("| {i:0>3} :  {value}");
+                    }
+                }
+            }
+            Command::PrintMemory(Some((from, to))) => {
+                let (from, to) = (resolve(vm, &from)?, resolve(vm, &to)?);
+                if from > to || to > vm.memory().len() {
+                    return Err(msg!("debugger.range_out_of_memory", from, to));
+                }
+                println!("+-- {}", msg!("debugger.memory"));
+                for i in from..to {
+                    println!("| {i:0>3} :  {}", vm.memory()[i]);
+                }
+            }
+            Command::SetMemory(target, value) => {
+                let address = resolve(vm, &target)?;
+                vm.set_memory(address, value)?;
+                println!("{}", msg!("debugger.memory_set", address, value));
+            }
+            Command::SetStack(index, value) => {
+                vm.set_stack(index, value)?;
+                println!("{}", msg!("debugger.stack_set", index, value));
+            }
+            Command::Stack => println!("{} {:?}", msg!("debugger.stack"), vm.stack()),
+            Command::Regs => {
+                println!("pc  = {}", vm.pc());
+                println!("sp  = {}", vm.stack().len());
+                match vm.stack().last() {
+                    Some(top) => println!("top = {top}"),
+                    None => println!("top = {}", msg!("debugger.empty")),
+                }
+                println!("ie  = {}", vm.interrupts().is_enabled() as i32);
+                println!("isr = {}", vm.interrupts().in_handler() as i32);
+                println!("pid = {}", vm.process_id());
+            }
+            Command::Processes => {
+                let Some(scheduler) = vm.scheduler() else {
+                    println!("{}", msg!("debugger.ps.single", vm.process_id()));
+                    return Ok(());
+                };
+                for process in scheduler.processes() {
+                    let current = process.id == scheduler.current().id;
+                    let status = match process.status {
+                        Status::Ready if current => msg!("debugger.ps.running"),
+                        Status::Ready => msg!("debugger.ps.ready"),
+                        Status::Waiting(id) => msg!("debugger.ps.waiting", id),
+                        Status::Exited(code) => msg!("debugger.ps.exited", code),
+                    };
+                    // 実行中のプロセスのレジスタは保存されていないので仮想マシンから読む
+                    let pc = if current { vm.pc() } else { process.pc };
+                    println!(
+                        "{}",
+                        msg!(
+                            "debugger.ps.process",
+                            if current { "*" } else { " " },
+                            process.id,
+                            status,
+                            pc,
+                            process.base,
+                            process.base + process.size - 1,
+                            process.steps
+                        )
+                    );
+                }
+            }
+            Command::Disasm(target, count) => {
+                let mut address = match target {
+                    Some(target) => resolve(vm, &target)?,
+                    None => vm.pc(),
+                };
+                for _ in 0..count {
+                    if address >= vm.memory().len() {
+                        break;
+                    }
+                    address += disassemble(vm, address);
+                }
+            }
+            Command::Where => {
+                println!(
+                    "{}",
+                    msg!("debugger.where", vm.debug_info().describe(vm.pc()))
+                );
+            }
+            Command::Output => {
+                println!("+-- {}", msg!("debugger.output"));
+                for i in vm.output().split("\n") {
+                    println!("| {i}");
+                }
+            }
+            Command::Save(path) => {
+                fs::write(&path, vm.snapshot()?)
+                    .map_err(|e| msg!("debugger.save_failed", path, e))?;
+                println!("{}", msg!("debugger.saved", path));
+            }
+            Command::Load(path) => {
+                let text =
+                    fs::read_to_string(&path).map_err(|e| msg!("common.read_failed", path, e))?;
+                let storage = (vm.storage().try_clone()).map_err(|e| e.to_string())?;
+                let mut restored = VirtualMachine::restore(&text, storage)?;
+                restored.inherit(vm);
+                *vm = restored;
+                println!("{}", msg!("debugger.loaded", path));
+                println!(
+                    "{}",
+                    msg!("debugger.where", vm.debug_info().describe(vm.pc()))
+                );
+            }
+            Command::Help => println!("{}", msg!("debugger.help")),
+            Command::Quit => {
+                vm.flush_trace();
+                io::input(&msg!("debugger.quit"));
+                std::process::exit(0)
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debugger {
+    /// ウォッチポイントを設定する
+    fn watch(&mut self, access: Access) {
+        println!(
+            "{}",
+            msg!("debugger.watch_set", self.next_id, describe_access(access))
+        );
+        self.watchpoints.push(Watchpoint {
+            id: self.next_id,
+            access,
+            hits: 0,
+        });
+        self.next_id += 1;
+    }
+
+    /// 停止する条件に当たるまで実行する (回数を指定するとその回数で止まる)
+    fn resume(&mut self, vm: &mut VirtualMachine, count: Option<usize>) {
+        let mut steps = 0;
+        while count != Some(steps) {
+            vm.step();
+            steps += 1;
+            if !vm.is_running() || self.check_watchpoints(vm) || self.check_breakpoints(vm, true) {
+                break;
+            }
+        }
+    }
+
+    /// 停止する条件に当たるまで命令を取り消して戻る (回数を指定するとその回数で止まる)
+    fn reverse(&mut self, vm: &mut VirtualMachine, count: Option<usize>) {
+        let mut steps = 0;
+        while count != Some(steps) {
+            let writes = match vm.step_back() {
+                Ok(writes) => writes,
+                Err(message) => {
+                    println!("{message}");
+                    break;
+                }
+            };
+            steps += 1;
+            if count.is_some() {
+                continue;
+            }
+            // 取り消した命令が監視している場所に書き込んでいたらその命令の前で止まる
+            if let Some(watchpoint) = (self.watchpoints.iter()).find(|w| writes.contains(&w.access))
+            {
+                println!(
+                    "{}",
+                    msg!(
+                        "debugger.watch_reversed",
+                        watchpoint.id,
+                        describe_access(watchpoint.access)
+                    )
+                );
+                break;
+            }
+            if self.check_breakpoints(vm, false) {
+                break;
+            }
+        }
+        println!(
+            "{}",
+            msg!("debugger.where", vm.debug_info().describe(vm.pc()))
+        );
+    }
+
+    /// 直前の命令が監視している場所に書き込んだか判断する
+    fn check_watchpoints(&mut self, vm: &VirtualMachine) -> bool {
+        let mut stop = false;
+        for watchpoint in &mut self.watchpoints {
+            if vm.writes().contains(&watchpoint.access) {
+                watchpoint.hits += 1;
+                stop = true;
+                let value = match watchpoint.access {
+                    Access::Memory(address) => msg!("debugger.watch_value", vm.memory()[address]),
+                    Access::Storage(_) => String::new(),
+                };
+                println!(
+                    "{}",
+                    msg!(
+                        "debugger.watch_hit",
+                        watchpoint.id,
+                        describe_access(watchpoint.access),
+                        value
+                    )
+                );
+                println!("| {}", vm.debug_info().describe(vm.address()));
+            }
+        }
+        stop
+    }
+
+    /// 次に実行する命令にブレークポイントがあって条件が成立するか判断する
+    /// (戻るときは成立した回数を数えない)
+    fn check_breakpoints(&mut self, vm: &VirtualMachine, forward: bool) -> bool {
+        for breakpoint in &mut self.breakpoints {
+            if breakpoint.address != vm.pc() {
+                continue;
+            }
+            if let Some(condition) = &breakpoint.condition {
+                match evaluate(vm, condition) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        println!("{}", msg!("debugger.condition_error", breakpoint.id, e));
+                        return true;
+                    }
+                }
+            }
+            if !forward {
+                println!("{}", msg!("debugger.break_reversed", breakpoint.id));
+                return true;
+            }
+            breakpoint.hits += 1;
+            if breakpoint.hits > breakpoint.ignore {
+                println!(
+                    "{}",
+                    msg!(
+                        "debugger.break_hit",
+                        breakpoint.id,
+                        breakpoint.hits,
+                        vm.debug_info().describe(vm.pc())
+                    )
+                );
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// 条件式を評価する
+fn evaluate(vm: &VirtualMachine, condition: &Condition) -> Result<bool, String> {
+    let (a, b) = (value(vm, &condition.left)?, value(vm, &condition.right)?);
+    Ok(match condition.op.as_str() {
+        "==" => a == b,
+        "!=" => a != b,
+        "<=" => a <= b,
+        ">=" => a >= b,
+        "<" => a < b,
+        _ => a > b,
+    })
+}
+
+/// 条件式の項の値を求める
+fn value(vm: &VirtualMachine, operand: &Operand) -> Result<i32, String> {
+    match operand {
+        Operand::Number(value) => Ok(*value),
+        Operand::Memory(target) => {
+            let address = resolve(vm, target)?;
+            (vm.memory().get(address).copied())
+                .ok_or(msg!("debugger.address_out_of_range", address))
+        }
+        Operand::Stack(index) => {
+            (vm.stack().get(*index).copied()).ok_or(msg!("vm.stack_index_missing", index))
+        }
+        Operand::Top => vm
+            .stack()
+            .last()
+            .copied()
+            .ok_or(msg!("debugger.stack_empty")),
+        Operand::Sp => Ok(vm.stack().len() as i32),
+        Operand::Pc => Ok(vm.pc() as i32),
+    }
+}
+
+/// 監視する場所を表示用の文字列にする
+fn describe_access(access: Access) -> String {
+    match access {
+        Access::Memory(address) => msg!("debugger.access.memory", address),
+        Access::Storage(line) => msg!("debugger.access.storage", line),
+    }
+}
+
+/// 番地の指定をアドレスにする
+fn resolve(vm: &VirtualMachine, target: &Target) -> Result<usize, String> {
+    match target {
+        Target::Address(address) => Ok(*address),
+        Target::Label(label) => {
+            (vm.debug_info().address(label)).ok_or(msg!("assembly.undefined_label", label))
+        }
+    }
+}
+
+/// 1命令を逆アセンブルして表示する (命令の長さを返す)
+fn disassemble(vm: &VirtualMachine, address: usize) -> usize {
+    if let Some(label) = (vm.debug_info().labels.iter()).find(|(_, a)| *a == address) {
+        println!("{}:", label.0);
+    }
+    let marker = if address == vm.pc() { "=>" } else { "  " };
+    match Instruction::decode(vm.memory(), address) {
+        Some((instruction, size)) => {
+            println!("{marker} {address:0>3} :  {instruction}");
+            size
+        }
+        None => {
+            println!("{marker} {address:0>3} :  .word {}", vm.memory()[address]);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_debugger {
+    use crate::debugger::{parse, Command, Operand, Target};
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse(""), Ok(Command::Step(1)));
+        assert_eq!(parse("step 5"), Ok(Command::Step(5)));
+        assert_eq!(
+            parse("break loop"),
+            Ok(Command::Break(Target::Label("loop".to_string()), None))
+        );
+        assert_eq!(
+            parse("print mem 10..20"),
+            Ok(Command::PrintMemory(Some((
+                Target::Address(10),
+                Target::Address(20)
+            ))))
+        );
+        assert_eq!(parse("set stack 0 -3"), Ok(Command::SetStack(0, -3)));
+        assert_eq!(parse("watch storage 2"), Ok(Command::WatchStorage(2)));
+        assert_eq!(parse("back"), Ok(Command::Back(1)));
+        assert_eq!(
+            parse("save state.snap"),
+            Ok(Command::Save("state.snap".to_string()))
+        );
+        assert_eq!(parse("reverse-continue"), Ok(Command::ReverseContinue));
+        assert_eq!(parse("history 100"), Ok(Command::History(Some(100))));
+        assert_eq!(parse("ps"), Ok(Command::Processes));
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        let Ok(Command::Break(Target::Address(4), Some(condition))) =
+            parse("break 4 if mem[count] >= stack[0]")
+        else {
+            panic!("条件付きブレークポイントを解析できません");
+        };
+        assert_eq!(
+            condition.left,
+            Operand::Memory(Target::Label("count".to_string()))
+        );
+        assert_eq!(condition.op, ">=");
+        assert_eq!(condition.right, Operand::Stack(0));
+        assert!(parse("break 4 if top").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("s").unwrap_err().contains("定義されてません"));
+        assert!(parse("step x").is_err());
+        assert!(parse("step 0").unwrap_err().contains("1以上"));
+        assert!(parse("back 0").unwrap_err().contains("1以上"));
+        assert!(parse("break").unwrap_err().contains("引数"));
+        assert!(parse("print mem 10").is_err());
+    }
+}
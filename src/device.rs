@@ -0,0 +1,466 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::framebuffer::{self, Framebuffer};
+use crate::interrupt;
+use crate::msg;
+
+/// 装置から使う仮想マシンの機能
+pub trait Host {
+    /// 入力から1文字を読む (入力が終わっていれば-1)
+    fn read_char(&mut self) -> Result<i32, String>;
+    /// 入力が終わったか判断する
+    fn end_of_input(&mut self) -> Result<bool, String>;
+    /// 文字列を出力する
+    fn write_text(&mut self, text: &str);
+    /// ホストの機能を呼び出す (結果は記録と再現の対象になる)
+    fn host_call(&mut self, name: &str, call: &mut dyn FnMut() -> i32) -> Result<i32, String>;
+    /// 読んでいない入力の行が新しく届いたか判断する (同じ行では一度だけtrueになる)
+    fn poll_keyboard(&mut self) -> Result<bool, String>;
+    /// 実行した命令の数
+    fn steps(&self) -> u64;
+}
+
+/// メモリの番地に割り当てる装置
+pub trait Device {
+    /// 装置の名前
+    fn name(&self) -> &'static str;
+    /// 使う番地の数
+    fn size(&self) -> usize;
+    /// 装置の中の位置 (0から) の値を読む
+    fn read(&mut self, offset: usize, host: &mut dyn Host) -> Result<i32, String>;
+    /// 装置の中の位置 (0から) に値を書く
+    fn write(&mut self, offset: usize, value: i32, host: &mut dyn Host) -> Result<(), String>;
+    /// 命令を実行する前に呼ばれる (割り込みを起こすときはその番号を返す)
+    fn tick(&mut self, _host: &mut dyn Host) -> Result<Option<usize>, String> {
+        Ok(None)
+    }
+    /// 実行を終えたときに呼ばれる
+    fn finish(&mut self, _host: &mut dyn Host) -> Result<(), String> {
+        Ok(())
+    }
+    /// 命令を取り消せるように今の状態を返す (Noneなら書き込みを取り消せない装置)
+    fn save(&self) -> Option<Vec<i64>> {
+        None
+    }
+    /// saveで返した状態に戻す
+    fn restore(&mut self, _state: &[i64]) {}
+}
+
+/// 装置を割り当てた番地の範囲
+struct Mapping {
+    base: usize,             // 先頭の番地
+    device: Box<dyn Device>, // 装置
+}
+
+/// 番地の範囲と装置をつなぐバス
+#[derive(Default)]
+pub struct Bus {
+    mappings: Vec<Mapping>,
+}
+
+impl Bus {
+    /// 装置を番地に割り当てる (他の装置と重なる場合はエラー)
+    pub fn map(&mut self, base: usize, device: Box<dyn Device>) -> Result<(), String> {
+        let end = base + device.size();
+        if let Some(other) = (self.mappings.iter())
+            .find(|other| base < other.base + other.device.size() && other.base < end)
+        {
+            return Err(msg!("device.overlap", device.name(), other.device.name()));
+        }
+        self.mappings.push(Mapping { base, device });
+        Ok(())
+    }
+
+    /// 番地に装置が割り当てられているか判断する
+    pub fn contains(&self, address: usize) -> bool {
+        self.find(address).is_some()
+    }
+
+    /// 割り当てた装置の名前と番地の範囲
+    pub fn devices(&self) -> Vec<(&'static str, usize, usize)> {
+        (self.mappings.iter())
+            .map(|mapping| (mapping.device.name(), mapping.base, mapping.device.size()))
+            .collect()
+    }
+
+    /// 番地に割り当てた装置から読む
+    pub fn read(&mut self, address: usize, host: &mut dyn Host) -> Result<i32, String> {
+        let index = self
+            .find(address)
+            .ok_or(msg!("vm.fault.memory_out_of_range", address))?;
+        let mapping = &mut self.mappings[index];
+        mapping.device.read(address - mapping.base, host)
+    }
+
+    /// 番地に割り当てた装置に書く
+    pub fn write(&mut self, address: usize, value: i32, host: &mut dyn Host) -> Result<(), String> {
+        let index = self
+            .find(address)
+            .ok_or(msg!("vm.fault.memory_out_of_range", address))?;
+        let mapping = &mut self.mappings[index];
+        mapping.device.write(address - mapping.base, value, host)
+    }
+
+    /// 全ての装置を進めて起きた割り込みの番号を返す
+    pub fn tick(&mut self, host: &mut dyn Host) -> Result<Vec<usize>, String> {
+        let mut raised = Vec::new();
+        for mapping in &mut self.mappings {
+            raised.extend(mapping.device.tick(host)?);
+        }
+        Ok(raised)
+    }
+
+    /// 全ての装置の状態 (命令を取り消すために記録する)
+    pub fn save(&self) -> Vec<Option<Vec<i64>>> {
+        (self.mappings.iter())
+            .map(|mapping| mapping.device.save())
+            .collect()
+    }
+
+    /// saveで記録した状態に戻す
+    pub fn restore(&mut self, states: &[Option<Vec<i64>>]) {
+        for (mapping, state) in self.mappings.iter_mut().zip(states) {
+            if let Some(state) = state {
+                mapping.device.restore(state);
+            }
+        }
+    }
+
+    /// 番地に割り当てた装置への書き込みを取り消せなければ装置の名前を返す
+    pub fn irreversible(&self, address: usize) -> Option<&'static str> {
+        let mapping = &self.mappings[self.find(address)?];
+        mapping
+            .device
+            .save()
+            .is_none()
+            .then(|| mapping.device.name())
+    }
+
+    /// 全ての装置の終わりの処理をする
+    pub fn finish(&mut self, host: &mut dyn Host) -> Result<(), String> {
+        for mapping in &mut self.mappings {
+            mapping.device.finish(host)?;
+        }
+        Ok(())
+    }
+
+    fn find(&self, address: usize) -> Option<usize> {
+        (self.mappings.iter()).position(|mapping| {
+            (mapping.base..mapping.base + mapping.device.size()).contains(&address)
+        })
+    }
+}
+
+/// `名前[:設定][@番地]`の指定から装置を作る (番地を省略すると決まった番地)
+pub fn create(spec: &str) -> Result<(usize, Box<dyn Device>), String> {
+    let (spec, address) = match spec.rsplit_once('@') {
+        Some((spec, address)) => {
+            let address = address
+                .parse()
+                .map_err(|_| msg!("device.bad_address", address))?;
+            (spec, Some(address))
+        }
+        None => (spec, None),
+    };
+    let (name, options) = spec.split_once(':').unwrap_or((spec, ""));
+    if name == "framebuffer" {
+        let device = Box::new(Framebuffer::new(options)?);
+        return Ok((address.unwrap_or(framebuffer::BASE), device));
+    }
+    if !options.is_empty() {
+        return Err(msg!("device.no_options", name));
+    }
+    let (base, device): (usize, Box<dyn Device>) = match name {
+        "console" => (1000, Box::new(Console::default())),
+        "timer" => (1010, Box::new(Timer::new())),
+        "random" => (1020, Box::new(Random::new())),
+        _ => return Err(msg!("device.unknown", name)),
+    };
+    Ok((address.unwrap_or(base), device))
+}
+
+/// コンソール
+/// (0: 読むと1文字入力・書くと1文字出力、1: 入力が残っていれば1、2: 書くと10進数で出力、
+///  3: 1を書くと入力の行が届くたびにキーボード割り込みを起こす)
+#[derive(Default)]
+pub struct Console {
+    keyboard: bool, // キーボード割り込みを起こすか
+}
+
+impl Device for Console {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn read(&mut self, offset: usize, host: &mut dyn Host) -> Result<i32, String> {
+        match offset {
+            0 => host.read_char(),
+            1 => Ok(!host.end_of_input()? as i32),
+            3 => Ok(self.keyboard as i32),
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: i32, host: &mut dyn Host) -> Result<(), String> {
+        match offset {
+            0 => {
+                let c = char::from_u32(value as u32).ok_or(msg!("vm.fault.invalid_char", value))?;
+                host.write_text(&c.to_string());
+            }
+            2 => host.write_text(&value.to_string()),
+            3 => self.keyboard = value != 0,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Option<Vec<i64>> {
+        Some(vec![self.keyboard as i64])
+    }
+
+    fn restore(&mut self, state: &[i64]) {
+        self.keyboard = state[0] != 0;
+    }
+
+    fn tick(&mut self, host: &mut dyn Host) -> Result<Option<usize>, String> {
+        match self.keyboard && host.poll_keyboard()? {
+            true => Ok(Some(interrupt::KEYBOARD)),
+            false => Ok(None),
+        }
+    }
+}
+
+/// タイマー
+/// (0: 読むとリセットしてから実行した命令の数・書くとリセット、1: 読むと起動してからのミリ秒、
+///  2: 書くとその数の命令ごとにタイマー割り込みを起こす (0で止める))
+pub struct Timer {
+    start: Instant, // 起動した時刻
+    base: u64,      // リセットしたときの命令の数
+    interval: u64,  // 割り込みを起こす間隔
+    next: u64,      // 次に割り込みを起こす命令の数
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            start: Instant::now(),
+            base: 0,
+            interval: 0,
+            next: 0,
+        }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+
+    fn size(&self) -> usize {
+        3
+    }
+
+    fn read(&mut self, offset: usize, host: &mut dyn Host) -> Result<i32, String> {
+        match offset {
+            // 取り消してリセットより前に戻っても負にならないようにする
+            0 => Ok(host.steps().saturating_sub(self.base) as i32),
+            2 => Ok(self.interval as i32),
+            1 => {
+                let start = self.start;
+                host.host_call("time", &mut || start.elapsed().as_millis() as i32)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: i32, host: &mut dyn Host) -> Result<(), String> {
+        match offset {
+            0 => self.base = host.steps(),
+            2 => {
+                self.interval = value.max(0) as u64;
+                self.next = host.steps() + self.interval;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, host: &mut dyn Host) -> Result<Option<usize>, String> {
+        if self.interval == 0 || host.steps() < self.next {
+            return Ok(None);
+        }
+        // 処理が間隔より長くても割り込みがたまらないように今から数える
+        self.next = host.steps() + self.interval;
+        Ok(Some(interrupt::TIMER))
+    }
+
+    fn save(&self) -> Option<Vec<i64>> {
+        Some(vec![
+            self.base as i64,
+            self.interval as i64,
+            self.next as i64,
+        ])
+    }
+
+    fn restore(&mut self, state: &[i64]) {
+        self.base = state[0] as u64;
+        self.interval = state[1] as u64;
+        self.next = state[2] as u64;
+    }
+}
+
+/// 乱数
+/// (0: 読むと0以上の乱数、1: 書くとその値を種にする)
+pub struct Random {
+    state: u32, // xorshiftの状態
+}
+
+impl Random {
+    pub fn new() -> Random {
+        let nanos =
+            (SystemTime::now().duration_since(UNIX_EPOCH)).map_or(0, |time| time.subsec_nanos());
+        let mut random = Random { state: 0 };
+        random.seed(nanos as i32);
+        random
+    }
+
+    /// 種を決める (xorshiftは0から進まないので0は別の値にする)
+    fn seed(&mut self, seed: i32) {
+        self.state = if seed == 0 { 0x2545_F491 } else { seed as u32 };
+    }
+}
+
+impl Device for Random {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn size(&self) -> usize {
+        2
+    }
+
+    fn read(&mut self, offset: usize, host: &mut dyn Host) -> Result<i32, String> {
+        if offset != 0 {
+            return Ok(0);
+        }
+        let state = &mut self.state;
+        host.host_call("random", &mut || {
+            *state ^= *state << 13;
+            *state ^= *state >> 17;
+            *state ^= *state << 5;
+            (*state >> 1) as i32
+        })
+    }
+
+    fn write(&mut self, offset: usize, value: i32, _host: &mut dyn Host) -> Result<(), String> {
+        if offset == 1 {
+            self.seed(value);
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Option<Vec<i64>> {
+        Some(vec![self.state as i64])
+    }
+
+    fn restore(&mut self, state: &[i64]) {
+        self.state = state[0] as u32;
+    }
+}
+
+#[cfg(test)]
+mod test_device {
+    use crate::device::{self, Bus, Console};
+    use crate::testing;
+    use crate::vm::{State, VirtualMachine};
+
+    fn machine(code: &str) -> VirtualMachine {
+        let mut vm = testing::machine("device.asm", code);
+        for name in ["console", "timer", "random"] {
+            let (base, device) = device::create(name).unwrap();
+            vm.map_device(base, device).unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn test_console_timer_and_random() {
+        // コンソールから読んだ文字を出力し、命令の数と種を決めた乱数を出力する
+        let code = "push\n1000\nload\npush\n1000\nstore\n\
+                    push\n1010\nload\npush\n1002\nstore\n\
+                    push\n7\npush\n1021\nstore\npush\n1020\nload\npush\n1002\nstore\n\
+                    push\n1001\nload\npush\n1002\nstore\nhalt\n";
+        let mut vm = machine(code);
+        vm.push_input("x".to_string());
+        vm.run();
+        assert_eq!(vm.state(), &State::Halted);
+        let mut again = machine(code);
+        again.push_input("x".to_string());
+        again.run();
+        assert_eq!(vm.output(), again.output());
+        // xの後に命令の数6、乱数、改行が残っているので1
+        assert!(vm.output().starts_with("x6"));
+        assert!(vm.output().ends_with('1'));
+    }
+
+    #[test]
+    fn test_step_back_restores_devices() {
+        // タイマーをリセットして命令の数を読み、乱数を2つ読む
+        let code = "push\n0\npush\n1010\nstore\npush\n1010\nload\n\
+                    push\n1020\nload\npush\n1020\nload\nhalt\n";
+        let mut vm = machine(code);
+        vm.set_history_size(100);
+        vm.run();
+        let first = vm.stack().to_vec();
+        // 全て取り消してからもう一度実行すると同じ値になる
+        while vm.step_back().is_ok() {}
+        assert_eq!(vm.pc(), 0);
+        vm.run();
+        assert_eq!(vm.stack(), first);
+
+        // フレームバッファへの書き込みは取り消せない
+        let mut vm = machine("push\n1\npush\n2004\nstore\nhalt\n");
+        let (base, device) = device::create("framebuffer:2x2").unwrap();
+        vm.map_device(base, device).unwrap();
+        vm.set_history_size(100);
+        vm.run();
+        assert!(vm.step_back().is_ok());
+        assert!(vm.step_back().unwrap_err().contains("framebuffer"));
+        assert!(vm.step_back().is_err());
+    }
+
+    #[test]
+    fn test_snapshot_with_devices() {
+        // 装置を割り当てている間はスナップショットを作れない
+        let mut vm = machine("halt\n");
+        vm.set_max_steps(Some(50));
+        vm.push_input("7".to_string());
+        assert!(vm.snapshot().unwrap_err().contains("console"));
+
+        // 装置のない状態から復元すると、装置と入力と命令の数の上限は元の仮想マシンから引き継ぐ
+        let code = "input\nloop: push\nloop\npush\n0\njump\n";
+        let snapshot = testing::machine("device.asm", code).snapshot().unwrap();
+        let (_, storage) = testing::storage("device.txt", "");
+        let mut restored = VirtualMachine::restore(&snapshot, storage).unwrap();
+        restored.inherit(&mut vm);
+        assert_eq!(restored.bus().devices().len(), 3);
+        restored.run();
+        assert_eq!(restored.stack()[0], 7);
+        assert!(matches!(restored.state(), State::Fault(message) if message.contains("50")));
+    }
+
+    #[test]
+    fn test_overlap_and_spec() {
+        let mut bus = Bus::default();
+        bus.map(1000, Box::new(Console::default())).unwrap();
+        assert!(bus.map(1003, Box::new(Console::default())).is_err());
+        assert!(bus.contains(1003) && !bus.contains(1004));
+        assert_eq!(device::create("timer@2000").unwrap().0, 2000);
+        assert!(device::create("disk").is_err());
+        assert!(device::create("timer@x").is_err());
+    }
+}
@@ -0,0 +1,726 @@
+use crate::block::{Block, BlockDevice, BLOCK_SIZE};
+use crate::msg;
+
+/// ディスクイメージの先頭に書くマジックナンバー
+const MAGIC: &[u8; 4] = b"SVFS";
+
+/// ディスクイメージの形式の版
+const VERSION: u32 = 1;
+
+/// inodeの大きさ (バイト)
+const INODE_SIZE: usize = 64;
+
+/// 1ブロックに入るinodeの数
+const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+
+/// inodeに直接書くブロック番号の数
+const DIRECT: usize = 12;
+
+/// 間接ブロックに入るブロック番号の数
+const POINTERS: usize = BLOCK_SIZE / 4;
+
+/// ディレクトリの1項目の大きさ (バイト)
+const ENTRY_SIZE: usize = 32;
+
+/// ファイル名の最大の長さ (UTF-8のバイト数)
+pub const NAME_LENGTH: usize = ENTRY_SIZE - 4;
+
+/// ルートディレクトリのinodeの番号 (0は使わない)
+const ROOT: u32 = 1;
+
+/// ファイルの最大の大きさ (バイト)
+pub const MAX_FILE_SIZE: usize = (DIRECT + POINTERS) * BLOCK_SIZE;
+
+/// ファイルの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    File,
+    Directory,
+}
+
+/// inode (ファイルの種類・大きさ・データのブロック)
+#[derive(Debug, Clone, Default)]
+struct Inode {
+    kind: u8,              // 0: 空き、1: ファイル、2: ディレクトリ
+    size: u32,             // 大きさ (バイト)
+    direct: [u32; DIRECT], // データのブロック番号 (0は未割り当て)
+    indirect: u32,         // 続きのブロック番号を並べたブロック
+}
+
+impl Inode {
+    fn new(kind: Kind) -> Inode {
+        Inode {
+            kind: match kind {
+                Kind::File => 1,
+                Kind::Directory => 2,
+            },
+            ..Inode::default()
+        }
+    }
+
+    fn kind(&self) -> Option<Kind> {
+        match self.kind {
+            1 => Some(Kind::File),
+            2 => Some(Kind::Directory),
+            _ => None,
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Inode {
+        let mut inode = Inode {
+            kind: bytes[0],
+            size: read_u32(bytes, 4),
+            indirect: read_u32(bytes, 8 + DIRECT * 4),
+            ..Inode::default()
+        };
+        for (i, block) in inode.direct.iter_mut().enumerate() {
+            *block = read_u32(bytes, 8 + i * 4);
+        }
+        inode
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes.fill(0);
+        bytes[0] = self.kind;
+        write_u32(bytes, 4, self.size);
+        for (i, block) in self.direct.iter().enumerate() {
+            write_u32(bytes, 8 + i * 4, *block);
+        }
+        write_u32(bytes, 8 + DIRECT * 4, self.indirect);
+    }
+}
+
+/// ディレクトリの中のファイル
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: String, // 名前
+    pub kind: Kind,   // 種類
+    pub size: u32,    // 大きさ (バイト)
+}
+
+/// 使っているブロックとinodeの数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub blocks: u32,      // ブロックの数
+    pub used_blocks: u32, // 使っているブロックの数 (管理用も含む)
+    pub inodes: u32,      // inodeの数
+    pub used_inodes: u32, // 使っているinodeの数
+}
+
+/// ブロック装置の上の階層のあるファイルシステム
+/// (ブロック0: スーパーブロック、続いて空きブロックのビットマップ、inodeの表、データ)
+pub struct FileSystem {
+    device: Box<dyn BlockDevice>, // ブロック装置
+    blocks: u32,                  // ブロックの数
+    inodes: u32,                  // inodeの数
+    inode_start: u32,             // inodeの表の最初のブロック
+    data_start: u32,              // データの最初のブロック
+}
+
+impl FileSystem {
+    /// ブロックとinodeの数から管理用の領域の位置を決める
+    fn layout(device: Box<dyn BlockDevice>, blocks: u32, inodes: u32) -> FileSystem {
+        let bitmap_blocks = (blocks as usize).div_ceil(BLOCK_SIZE * 8) as u32;
+        let inode_blocks = (inodes as usize).div_ceil(INODES_PER_BLOCK) as u32;
+        FileSystem {
+            device,
+            blocks,
+            inodes,
+            inode_start: 1 + bitmap_blocks,
+            data_start: 1 + bitmap_blocks + inode_blocks,
+        }
+    }
+
+    /// ブロック装置を初期化して空のファイルシステムを作る
+    pub fn format(device: Box<dyn BlockDevice>, inodes: u32) -> Result<FileSystem, String> {
+        let blocks = device.block_count();
+        let inodes = inodes.max(ROOT + 1);
+        let mut fs = FileSystem::layout(device, blocks, inodes);
+        if fs.data_start >= blocks {
+            return Err(msg!("filesystem.too_small", blocks));
+        }
+        let mut superblock = [0; BLOCK_SIZE];
+        superblock[..4].copy_from_slice(MAGIC);
+        write_u32(&mut superblock, 4, VERSION);
+        write_u32(&mut superblock, 8, blocks);
+        write_u32(&mut superblock, 12, inodes);
+        fs.device.write_block(0, &superblock)?;
+        for index in 1..fs.data_start {
+            fs.device.write_block(index, &[0; BLOCK_SIZE])?;
+        }
+        // 管理用のブロックは使用中にする
+        for index in 0..fs.data_start {
+            fs.set_used(index, true)?;
+        }
+        fs.write_inode(ROOT, &Inode::new(Kind::Directory))?;
+        Ok(fs)
+    }
+
+    /// ブロック装置のファイルシステムを読み込む
+    pub fn mount(mut device: Box<dyn BlockDevice>) -> Result<FileSystem, String> {
+        let superblock = device.read_block(0)?;
+        if &superblock[..4] != MAGIC || read_u32(&superblock, 4) != VERSION {
+            return Err(msg!("filesystem.invalid"));
+        }
+        let blocks = read_u32(&superblock, 8).min(device.block_count());
+        let inodes = read_u32(&superblock, 12);
+        Ok(FileSystem::layout(device, blocks, inodes))
+    }
+
+    /// パスのinodeの番号を探す (`/`で区切る)
+    pub fn lookup(&mut self, path: &str) -> Result<u32, String> {
+        let mut current = ROOT;
+        for name in split(path) {
+            if self.read_inode(current)?.kind() != Some(Kind::Directory) {
+                return Err(msg!("filesystem.not_dir", path));
+            }
+            current = (self.find(current, name)?)
+                .map(|(_, inode)| inode)
+                .ok_or(msg!("filesystem.not_found", path))?;
+        }
+        Ok(current)
+    }
+
+    /// ファイルの種類と大きさ
+    pub fn stat(&mut self, inode: u32) -> Result<(Kind, u32), String> {
+        let node = self.read_inode(inode)?;
+        let kind = node.kind().ok_or(msg!("filesystem.invalid"))?;
+        Ok((kind, node.size))
+    }
+
+    /// ファイルかディレクトリを作る (親のディレクトリはあらかじめ作っておく)
+    pub fn create(&mut self, path: &str, kind: Kind) -> Result<u32, String> {
+        let (parent, name) = self.parent(path)?;
+        if name.len() > NAME_LENGTH {
+            return Err(msg!("filesystem.name_too_long", name, NAME_LENGTH));
+        }
+        if self.find(parent, name)?.is_some() {
+            return Err(msg!("filesystem.exists", path));
+        }
+        let inode = self.allocate_inode()?;
+        self.write_inode(inode, &Inode::new(kind))?;
+
+        // 空いている項目か最後に追加する
+        let entries = self.entries(parent)?;
+        let slot = (entries.iter().position(|(inode, _)| *inode == 0)).unwrap_or(entries.len());
+        let mut entry = [0; ENTRY_SIZE];
+        write_u32(&mut entry, 0, inode);
+        entry[4..4 + name.len()].copy_from_slice(name.as_bytes());
+        self.write_at(parent, slot * ENTRY_SIZE, &entry)?;
+        Ok(inode)
+    }
+
+    /// ファイルか空のディレクトリを削除する
+    pub fn remove(&mut self, path: &str) -> Result<(), String> {
+        if split(path).is_empty() {
+            return Err(msg!("filesystem.remove_root"));
+        }
+        let (parent, name) = self.parent(path)?;
+        let (slot, inode) = (self.find(parent, name)?).ok_or(msg!("filesystem.not_found", path))?;
+        if self.read_inode(inode)?.kind() == Some(Kind::Directory)
+            && self.entries(inode)?.iter().any(|(inode, _)| *inode != 0)
+        {
+            return Err(msg!("filesystem.not_empty", path));
+        }
+        self.truncate(inode)?;
+        self.write_inode(inode, &Inode::default())?;
+        self.write_at(parent, slot * ENTRY_SIZE, &[0; ENTRY_SIZE])?;
+        Ok(())
+    }
+
+    /// ディレクトリの中のファイルを名前の順に並べる
+    pub fn list(&mut self, path: &str) -> Result<Vec<DirEntry>, String> {
+        let inode = self.lookup(path)?;
+        if self.read_inode(inode)?.kind() != Some(Kind::Directory) {
+            return Err(msg!("filesystem.not_dir", path));
+        }
+        let mut list = Vec::new();
+        for (inode, name) in self.entries(inode)? {
+            if inode != 0 {
+                let (kind, size) = self.stat(inode)?;
+                list.push(DirEntry { name, kind, size });
+            }
+        }
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    /// ファイルの途中から読む (読んだバイト数を返す)
+    pub fn read_at(
+        &mut self,
+        inode: u32,
+        position: usize,
+        buffer: &mut [u8],
+    ) -> Result<usize, String> {
+        let mut node = self.read_inode(inode)?;
+        let end = (position + buffer.len()).min(node.size as usize);
+        let mut done = 0;
+        while position + done < end {
+            let offset = (position + done) % BLOCK_SIZE;
+            let length = (BLOCK_SIZE - offset).min(end - position - done);
+            let index = (position + done) / BLOCK_SIZE;
+            let block = match self.block_of(&mut node, index, false)? {
+                Some(block) => self.device.read_block(block)?,
+                None => [0; BLOCK_SIZE],
+            };
+            buffer[done..done + length].copy_from_slice(&block[offset..offset + length]);
+            done += length;
+        }
+        Ok(done)
+    }
+
+    /// ファイルの途中に書く (足りないブロックを割り当てて大きさを広げる)
+    pub fn write_at(&mut self, inode: u32, position: usize, data: &[u8]) -> Result<usize, String> {
+        if position + data.len() > MAX_FILE_SIZE {
+            return Err(msg!("filesystem.too_large", MAX_FILE_SIZE));
+        }
+        let mut node = self.read_inode(inode)?;
+        let mut done = 0;
+        while done < data.len() {
+            let offset = (position + done) % BLOCK_SIZE;
+            let length = (BLOCK_SIZE - offset).min(data.len() - done);
+            let index = (position + done) / BLOCK_SIZE;
+            let result = self.block_of(&mut node, index, true);
+            // 途中で空きがなくなっても割り当てたブロックは失わないようにする
+            let block = match result {
+                Ok(block) => block.expect("allocated"),
+                Err(message) => {
+                    self.write_inode(inode, &node)?;
+                    return Err(message);
+                }
+            };
+            let mut buffer = self.device.read_block(block)?;
+            buffer[offset..offset + length].copy_from_slice(&data[done..done + length]);
+            self.device.write_block(block, &buffer)?;
+            done += length;
+        }
+        node.size = node.size.max((position + done) as u32);
+        self.write_inode(inode, &node)?;
+        Ok(done)
+    }
+
+    /// ファイルの中身を捨てて大きさを0にする
+    pub fn truncate(&mut self, inode: u32) -> Result<(), String> {
+        let mut node = self.read_inode(inode)?;
+        let mut blocks: Vec<u32> = node.direct.to_vec();
+        if node.indirect != 0 {
+            let pointers = self.device.read_block(node.indirect)?;
+            blocks.extend((0..POINTERS).map(|i| read_u32(&pointers, i * 4)));
+            blocks.push(node.indirect);
+        }
+        for block in blocks.into_iter().filter(|block| *block != 0) {
+            self.set_used(block, false)?;
+        }
+        node.direct = [0; DIRECT];
+        node.indirect = 0;
+        node.size = 0;
+        self.write_inode(inode, &node)
+    }
+
+    /// 使っているブロックとinodeの数を数える
+    pub fn usage(&mut self) -> Result<Usage, String> {
+        let mut used_blocks = 0;
+        for index in 0..self.blocks {
+            used_blocks += self.is_used(index)? as u32;
+        }
+        let mut used_inodes = 0;
+        for inode in ROOT..self.inodes {
+            used_inodes += (self.read_inode(inode)?.kind != 0) as u32;
+        }
+        Ok(Usage {
+            blocks: self.blocks,
+            used_blocks,
+            inodes: self.inodes,
+            used_inodes,
+        })
+    }
+
+    /// 親のディレクトリのinodeと最後の名前
+    fn parent<'a>(&mut self, path: &'a str) -> Result<(u32, &'a str), String> {
+        let mut names = split(path);
+        let name = names.pop().ok_or(msg!("filesystem.exists", "/"))?;
+        let parent = self.lookup(&names.join("/"))?;
+        if self.read_inode(parent)?.kind() != Some(Kind::Directory) {
+            return Err(msg!("filesystem.not_dir", path));
+        }
+        Ok((parent, name))
+    }
+
+    /// ディレクトリの項目 (inodeの番号と名前、空いた項目はinodeが0)
+    fn entries(&mut self, directory: u32) -> Result<Vec<(u32, String)>, String> {
+        let size = self.read_inode(directory)?.size as usize;
+        let mut data = vec![0; size];
+        self.read_at(directory, 0, &mut data)?;
+        Ok((data.chunks(ENTRY_SIZE))
+            .map(|entry| {
+                let name = &entry[4..];
+                let length = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                let name = String::from_utf8_lossy(&name[..length]).to_string();
+                (read_u32(entry, 0), name)
+            })
+            .collect())
+    }
+
+    /// ディレクトリから名前の項目を探す (項目の位置とinodeの番号)
+    fn find(&mut self, directory: u32, name: &str) -> Result<Option<(usize, u32)>, String> {
+        Ok((self.entries(directory)?.into_iter().enumerate())
+            .find(|(_, (inode, entry))| *inode != 0 && entry == name)
+            .map(|(slot, (inode, _))| (slot, inode)))
+    }
+
+    /// ファイルの何番目のブロックかからブロック番号を求める (allocateならなければ割り当てる)
+    fn block_of(
+        &mut self,
+        node: &mut Inode,
+        index: usize,
+        allocate: bool,
+    ) -> Result<Option<u32>, String> {
+        if index < DIRECT {
+            if node.direct[index] == 0 && allocate {
+                node.direct[index] = self.allocate_block()?;
+            }
+            return Ok(Some(node.direct[index]).filter(|block| *block != 0));
+        }
+        let index = index - DIRECT;
+        if index >= POINTERS {
+            return Err(msg!("filesystem.too_large", MAX_FILE_SIZE));
+        }
+        if node.indirect == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            node.indirect = self.allocate_block()?;
+        }
+        let mut pointers = self.device.read_block(node.indirect)?;
+        let mut block = read_u32(&pointers, index * 4);
+        if block == 0 && allocate {
+            block = self.allocate_block()?;
+            write_u32(&mut pointers, index * 4, block);
+            self.device.write_block(node.indirect, &pointers)?;
+        }
+        Ok(Some(block).filter(|block| *block != 0))
+    }
+
+    /// 空いているブロックを0で埋めて割り当てる
+    fn allocate_block(&mut self) -> Result<u32, String> {
+        for index in self.data_start..self.blocks {
+            if !self.is_used(index)? {
+                self.set_used(index, true)?;
+                self.device.write_block(index, &[0; BLOCK_SIZE])?;
+                return Ok(index);
+            }
+        }
+        Err(msg!("filesystem.full"))
+    }
+
+    /// 空いているinodeを探す
+    fn allocate_inode(&mut self) -> Result<u32, String> {
+        for inode in ROOT + 1..self.inodes {
+            if self.read_inode(inode)?.kind == 0 {
+                return Ok(inode);
+            }
+        }
+        Err(msg!("filesystem.no_inodes"))
+    }
+
+    /// ビットマップのブロックとその中の位置
+    fn bitmap_position(index: u32) -> (u32, usize, u8) {
+        let bit = index as usize;
+        let block = 1 + (bit / (BLOCK_SIZE * 8)) as u32;
+        (block, bit % (BLOCK_SIZE * 8) / 8, 1 << (bit % 8))
+    }
+
+    fn is_used(&mut self, index: u32) -> Result<bool, String> {
+        let (block, byte, mask) = FileSystem::bitmap_position(index);
+        Ok(self.device.read_block(block)?[byte] & mask != 0)
+    }
+
+    fn set_used(&mut self, index: u32, used: bool) -> Result<(), String> {
+        let (block, byte, mask) = FileSystem::bitmap_position(index);
+        let mut bitmap = self.device.read_block(block)?;
+        match used {
+            true => bitmap[byte] |= mask,
+            false => bitmap[byte] &= !mask,
+        }
+        self.device.write_block(block, &bitmap)
+    }
+
+    /// inodeの表のブロックとその中の位置
+    fn inode_position(&self, inode: u32) -> Result<(u32, usize), String> {
+        if inode == 0 || inode >= self.inodes {
+            return Err(msg!("filesystem.invalid"));
+        }
+        let block = self.inode_start + inode / INODES_PER_BLOCK as u32;
+        Ok((block, inode as usize % INODES_PER_BLOCK * INODE_SIZE))
+    }
+
+    fn read_inode(&mut self, inode: u32) -> Result<Inode, String> {
+        let (block, offset) = self.inode_position(inode)?;
+        let data: Block = self.device.read_block(block)?;
+        Ok(Inode::parse(&data[offset..offset + INODE_SIZE]))
+    }
+
+    fn write_inode(&mut self, inode: u32, node: &Inode) -> Result<(), String> {
+        let (block, offset) = self.inode_position(inode)?;
+        let mut data = self.device.read_block(block)?;
+        node.write(&mut data[offset..offset + INODE_SIZE]);
+        self.device.write_block(block, &data)
+    }
+}
+
+/// 開いたファイル
+struct Handle {
+    inode: u32,      // ファイルのinode
+    position: usize, // 次に読み書きする位置
+}
+
+/// ファイルシステムと開いたファイル (プログラムからはファイル番号で扱う)
+pub struct Disk {
+    fs: FileSystem,               // ファイルシステム
+    handles: Vec<Option<Handle>>, // ファイル番号ごとの開いたファイル
+}
+
+impl Disk {
+    pub fn new(fs: FileSystem) -> Disk {
+        Disk {
+            fs,
+            handles: Vec::new(),
+        }
+    }
+
+    /// ファイルシステム
+    pub fn fs(&mut self) -> &mut FileSystem {
+        &mut self.fs
+    }
+
+    /// ファイルを開いてファイル番号を返す
+    /// (0: 読む、1: 書く (なければ作り、中身を捨てる)、2: 追記する (なければ作る))
+    pub fn open(&mut self, path: &str, mode: i32) -> Result<i32, String> {
+        if !(0..=2).contains(&mode) {
+            return Err(msg!("filesystem.bad_mode", mode));
+        }
+        let inode = match self.fs.lookup(path) {
+            Ok(inode) => inode,
+            Err(_) if mode != 0 => self.fs.create(path, Kind::File)?,
+            Err(message) => return Err(message),
+        };
+        let (kind, size) = self.fs.stat(inode)?;
+        if kind == Kind::Directory {
+            return Err(msg!("filesystem.is_dir", path));
+        }
+        let position = match mode {
+            1 => {
+                self.fs.truncate(inode)?;
+                0
+            }
+            2 => size as usize,
+            _ => 0,
+        };
+        let handle = Some(Handle { inode, position });
+        match self.handles.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.handles[fd] = handle;
+                Ok(fd as i32)
+            }
+            None => {
+                self.handles.push(handle);
+                Ok(self.handles.len() as i32 - 1)
+            }
+        }
+    }
+
+    /// ファイルを閉じる
+    pub fn close(&mut self, fd: i32) -> Result<(), String> {
+        self.handle(fd)?;
+        self.handles[fd as usize] = None;
+        Ok(())
+    }
+
+    /// 今の位置から最大count バイト読む (ファイルの終わりでは空)
+    pub fn read(&mut self, fd: i32, count: usize) -> Result<Vec<u8>, String> {
+        let handle = self.handle(fd)?;
+        let (inode, position) = (handle.inode, handle.position);
+        let mut buffer = vec![0; count];
+        let length = self.fs.read_at(inode, position, &mut buffer)?;
+        buffer.truncate(length);
+        self.handle(fd)?.position += length;
+        Ok(buffer)
+    }
+
+    /// 今の位置に書く
+    pub fn write(&mut self, fd: i32, data: &[u8]) -> Result<usize, String> {
+        let handle = self.handle(fd)?;
+        let (inode, position) = (handle.inode, handle.position);
+        let length = self.fs.write_at(inode, position, data)?;
+        self.handle(fd)?.position += length;
+        Ok(length)
+    }
+
+    /// 読み書きする位置を変える (ファイルの終わりより後には移らない)
+    pub fn seek(&mut self, fd: i32, position: i32) -> Result<usize, String> {
+        if position < 0 {
+            return Err(msg!("filesystem.bad_position", position));
+        }
+        let inode = self.handle(fd)?.inode;
+        let (_, size) = self.fs.stat(inode)?;
+        let handle = self.handle(fd)?;
+        handle.position = (position as usize).min(size as usize);
+        Ok(handle.position)
+    }
+
+    /// ディレクトリの中の名前を改行で区切って並べる (ディレクトリは最後に`/`を付ける)
+    pub fn list(&mut self, path: &str) -> Result<(usize, String), String> {
+        let entries = self.fs.list(path)?;
+        let text = (entries.iter())
+            .map(|entry| match entry.kind {
+                Kind::File => format!("{}\n", entry.name),
+                Kind::Directory => format!("{}/\n", entry.name),
+            })
+            .collect();
+        Ok((entries.len(), text))
+    }
+
+    /// ファイルか空のディレクトリを削除する (開いているファイルは削除できない)
+    pub fn delete(&mut self, path: &str) -> Result<(), String> {
+        let inode = self.fs.lookup(path)?;
+        if (self.handles.iter().flatten()).any(|handle| handle.inode == inode) {
+            return Err(msg!("filesystem.busy", path));
+        }
+        self.fs.remove(path)
+    }
+
+    /// ディレクトリを作る
+    pub fn mkdir(&mut self, path: &str) -> Result<(), String> {
+        self.fs.create(path, Kind::Directory).map(|_| ())
+    }
+
+    fn handle(&mut self, fd: i32) -> Result<&mut Handle, String> {
+        (self.handles.get_mut(fd as usize))
+            .and_then(Option::as_mut)
+            .ok_or(msg!("filesystem.bad_handle", fd))
+    }
+}
+
+/// パスを名前に分ける
+fn split(path: &str) -> Vec<&str> {
+    (path.split('/')).filter(|name| !name.is_empty()).collect()
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test_filesystem {
+    use crate::block::{Block, BlockDevice, ImageFile, BLOCK_SIZE};
+    use crate::filesystem::{Disk, FileSystem, Kind, MAX_FILE_SIZE};
+    use crate::testing;
+    use crate::vm::State;
+
+    /// メモリの上のブロック装置
+    struct MemoryDisk {
+        blocks: Vec<Block>,
+    }
+
+    impl BlockDevice for MemoryDisk {
+        fn block_count(&self) -> u32 {
+            self.blocks.len() as u32
+        }
+
+        fn read_block(&mut self, index: u32) -> Result<Block, String> {
+            Ok(self.blocks[index as usize])
+        }
+
+        fn write_block(&mut self, index: u32, block: &Block) -> Result<(), String> {
+            self.blocks[index as usize] = *block;
+            Ok(())
+        }
+    }
+
+    fn memory_disk(blocks: usize) -> Disk {
+        let device = MemoryDisk {
+            blocks: vec![[0; BLOCK_SIZE]; blocks],
+        };
+        Disk::new(FileSystem::format(Box::new(device), 32).unwrap())
+    }
+
+    #[test]
+    fn test_files_and_directories() {
+        let mut disk = memory_disk(256);
+        disk.mkdir("/docs").unwrap();
+        let fd = disk.open("/docs/a.txt", 1).unwrap();
+        // 直接のブロックを越えて間接ブロックまで書く
+        let data: Vec<u8> = (0..8000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(disk.write(fd, &data).unwrap(), 8000);
+        assert_eq!(disk.seek(fd, 6000).unwrap(), 6000);
+        assert_eq!(disk.read(fd, 5000).unwrap(), data[6000..]);
+        assert!(disk.read(fd, 10).unwrap().is_empty());
+        assert!(disk.delete("/docs/a.txt").is_err());
+        disk.close(fd).unwrap();
+        assert!(disk.close(fd).is_err());
+
+        let fd = disk.open("/docs/a.txt", 2).unwrap();
+        disk.write(fd, b"!").unwrap();
+        disk.close(fd).unwrap();
+        assert_eq!(disk.fs().list("/docs").unwrap()[0].size, 8001);
+        assert_eq!(disk.list("/").unwrap(), (1, "docs/\n".to_string()));
+
+        assert!(disk.open("/missing.txt", 0).is_err());
+        assert!(disk.open("/docs", 0).is_err());
+        assert!(disk.open("/docs/a.txt/b", 1).is_err());
+        assert!(disk.delete("/docs").is_err());
+        let error = disk.fs().remove("/").unwrap_err();
+        assert!(error.contains("ルートディレクトリ"), "{error}");
+        let used = disk.fs().usage().unwrap();
+        disk.delete("/docs/a.txt").unwrap();
+        disk.delete("/docs").unwrap();
+        assert_eq!(disk.list("/").unwrap().0, 0);
+        let free = disk.fs().usage().unwrap();
+        assert_eq!(used.used_blocks - free.used_blocks, 18);
+        assert_eq!(free.used_inodes, 1);
+
+        let fd = disk.open("/big", 1).unwrap();
+        assert!(disk.write(fd, &vec![0; MAX_FILE_SIZE + 1]).is_err());
+        assert!(disk.mkdir(&"x".repeat(29)).is_err());
+    }
+
+    #[test]
+    fn test_image_file_and_system_calls() {
+        let path = testing::temp_path("disk.img");
+        let path = path.display().to_string();
+        let image = ImageFile::create(&path, 64).unwrap();
+        let mut disk = Disk::new(FileSystem::format(Box::new(image), 16).unwrap());
+        disk.mkdir("/in").unwrap();
+        let fd = disk.open("/in/name.txt", 1).unwrap();
+        disk.write(fd, b"VM").unwrap();
+
+        // name.txtを読んで"Hi, "を付けてout.txtに書き、ルートの一覧を出力する
+        let code = "push\nname\npush\n0\npush\n1\nsyscall\n\
+                    push\nbuf\npush\n4\nadd\npush\n8\npush\n3\nsyscall\npop\n\
+                    push\nout\npush\n1\npush\n1\nsyscall\npush\nbuf\npush\n6\npush\n4\nsyscall\npop\n\
+                    push\nroot\npush\nlist\npush\n16\npush\n6\nsyscall\noutputnum\npush\nlist\noutputstr\n\
+                    push\nmissing\npush\n0\npush\n1\nsyscall\noutputnum\nhalt\n\
+                    name: .zstring \"in/name.txt\"\nout: .zstring \"/out.txt\"\nroot: .zstring \"/\"\n\
+                    buf: .word 72, 105, 44, 32\n.zero 8\nlist: .zero 16\nmissing: .zstring \"/none\"\n";
+        let mut vm = testing::machine("disk.asm", code);
+        let image = ImageFile::open(&path).unwrap();
+        vm.set_disk(Disk::new(FileSystem::mount(Box::new(image)).unwrap()));
+        vm.run();
+        assert_eq!(vm.state(), &State::Halted);
+        assert_eq!(vm.output(), "2in/\nout.txt\n-1");
+
+        let image = ImageFile::open(&path).unwrap();
+        let mut disk = Disk::new(FileSystem::mount(Box::new(image)).unwrap());
+        let fd = disk.open("/out.txt", 0).unwrap();
+        assert_eq!(disk.read(fd, 100).unwrap(), b"Hi, VM");
+        assert_eq!(disk.fs().list("/in").unwrap()[0].kind, Kind::File);
+
+        std::fs::write(&path, [0; BLOCK_SIZE]).unwrap();
+        let image = ImageFile::open(&path).unwrap();
+        assert!(FileSystem::mount(Box::new(image)).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::fs;
+use std::io::Write;
+
+use crate::device::{Device, Host};
+use crate::msg;
+
+/// 最初に割り当てる番地
+pub const BASE: usize = 2000;
+
+/// 画素の前に置く制御用の番地の数
+const HEADER: usize = 4;
+
+/// パレットの16色 (VGAの色)
+const PALETTE: [u32; 16] = [
+    0x000000, 0x0000AA, 0x00AA00, 0x00AAAA, 0xAA0000, 0xAA00AA, 0xAA5500, 0xAAAAAA, 0x555555,
+    0x5555FF, 0x55FF55, 0x55FFFF, 0xFF5555, 0xFF55FF, 0xFFFF55, 0xFFFFFF,
+];
+
+/// 画素の値の形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    Palette, // パレットの番号 (0～15)
+    Rgb,     // 0xRRGGBB
+}
+
+/// 画像ファイルの形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+}
+
+impl ImageFormat {
+    /// 拡張子から形式を決める
+    fn from_path(path: &str) -> Result<ImageFormat, String> {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".ppm") {
+            Ok(ImageFormat::Ppm)
+        } else if lower.ends_with(".png") {
+            Ok(ImageFormat::Png)
+        } else {
+            Err(msg!("framebuffer.bad_format", path))
+        }
+    }
+}
+
+/// フレームバッファ
+/// (0: 幅、1: 高さ、2: 色の形式 (0: パレット、1: RGB)、
+///  3: 書くと画像を出力する・読むと出力した回数、4～: 画素 (左上から1行ずつ))
+pub struct Framebuffer {
+    width: usize,           // 幅
+    height: usize,          // 高さ
+    mode: ColorMode,        // 画素の値の形式
+    pixels: Vec<i32>,       // 画素の値
+    output: Option<String>, // 画像を書き出すファイル ({}は出力した回数に置き換える)
+    terminal: bool,         // 端末に色付きのブロックで表示するか
+    frames: usize,          // 出力した回数
+    dirty: bool,            // 最後に出力してから画素を書き換えたか
+}
+
+impl Framebuffer {
+    /// `幅x高さ` `palette` `rgb` `term` `ファイル名`を`,`で区切った設定から作る
+    pub fn new(options: &str) -> Result<Framebuffer, String> {
+        let mut framebuffer = Framebuffer {
+            width: 32,
+            height: 24,
+            mode: ColorMode::Palette,
+            pixels: Vec::new(),
+            output: None,
+            terminal: false,
+            frames: 0,
+            dirty: false,
+        };
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "palette" => framebuffer.mode = ColorMode::Palette,
+                "rgb" => framebuffer.mode = ColorMode::Rgb,
+                "term" => framebuffer.terminal = true,
+                _ => {
+                    if let Some((width, height)) = option.split_once('x') {
+                        if let (Ok(width), Ok(height)) = (width.parse(), height.parse()) {
+                            if width == 0 || height == 0 {
+                                return Err(msg!("framebuffer.bad_size", option));
+                            }
+                            framebuffer.width = width;
+                            framebuffer.height = height;
+                            continue;
+                        }
+                    }
+                    ImageFormat::from_path(option)?;
+                    framebuffer.output = Some(option.to_string());
+                }
+            }
+        }
+        framebuffer.pixels = vec![0; framebuffer.width * framebuffer.height];
+        Ok(framebuffer)
+    }
+
+    /// 画素の色 (0xRRGGBB)
+    fn color(&self, x: usize, y: usize) -> u32 {
+        let value = self.pixels[y * self.width + x];
+        match self.mode {
+            ColorMode::Palette => PALETTE[value.rem_euclid(16) as usize],
+            ColorMode::Rgb => value as u32 & 0xFFFFFF,
+        }
+    }
+
+    /// 全ての画素をRGBの順に並べたバイト列
+    fn rgb_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 3);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                bytes.extend(&self.color(x, y).to_be_bytes()[1..]);
+            }
+        }
+        bytes
+    }
+
+    /// PPM (P6) 形式の画像
+    pub fn ppm(&self) -> Vec<u8> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend(self.rgb_bytes());
+        data
+    }
+
+    /// PNG形式の画像 (圧縮しない)
+    pub fn png(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend((self.width as u32).to_be_bytes());
+        header.extend((self.height as u32).to_be_bytes());
+        // 8ビットのRGB、圧縮・フィルタ・インターレースは標準
+        header.extend([8, 2, 0, 0, 0]);
+
+        // 各行の先頭にフィルタなし (0) を付ける
+        let bytes = self.rgb_bytes();
+        let mut raw = Vec::with_capacity(bytes.len() + self.height);
+        for row in bytes.chunks(self.width * 3) {
+            raw.push(0);
+            raw.extend(row);
+        }
+
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut data, b"IHDR", &header);
+        png_chunk(&mut data, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut data, b"IEND", &[]);
+        data
+    }
+
+    /// 端末に表示する文字列 (上下2つの画素を1文字の前景色と背景色で表す)
+    pub fn blocks(&self) -> String {
+        let mut text = String::new();
+        for y in (0..self.height).step_by(2) {
+            for x in 0..self.width {
+                let [_, r, g, b] = self.color(x, y).to_be_bytes();
+                text += &format!("\x1b[38;2;{r};{g};{b}m");
+                let [_, r, g, b] = match y + 1 < self.height {
+                    true => self.color(x, y + 1),
+                    false => 0,
+                }
+                .to_be_bytes();
+                text += &format!("\x1b[48;2;{r};{g};{b}m▀");
+            }
+            text += "\x1b[0m\n";
+        }
+        text
+    }
+
+    /// 画像をファイルと端末に出力する
+    fn present(&mut self) -> Result<(), String> {
+        if let Some(output) = &self.output {
+            let path = output.replace("{}", &self.frames.to_string());
+            let data = match ImageFormat::from_path(&path)? {
+                ImageFormat::Ppm => self.ppm(),
+                ImageFormat::Png => self.png(),
+            };
+            fs::write(&path, data).map_err(|e| msg!("common.write_failed", path, e))?;
+        }
+        if self.terminal {
+            console::enable_colors();
+            let mut out = std::io::stdout().lock();
+            let _ = out.write_all(self.blocks().as_bytes());
+            let _ = out.flush();
+        }
+        self.frames += 1;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Device for Framebuffer {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn size(&self) -> usize {
+        HEADER + self.pixels.len()
+    }
+
+    fn read(&mut self, offset: usize, _host: &mut dyn Host) -> Result<i32, String> {
+        Ok(match offset {
+            0 => self.width as i32,
+            1 => self.height as i32,
+            2 => (self.mode == ColorMode::Rgb) as i32,
+            3 => self.frames as i32,
+            _ => self.pixels[offset - HEADER],
+        })
+    }
+
+    fn write(&mut self, offset: usize, value: i32, _host: &mut dyn Host) -> Result<(), String> {
+        match offset {
+            0..=2 => {}
+            3 => self.present()?,
+            _ => {
+                self.pixels[offset - HEADER] = value;
+                self.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// 最後に出力した後に描いたもの (一度も出力していなければ全体) を出力する
+    fn finish(&mut self, _host: &mut dyn Host) -> Result<(), String> {
+        if self.dirty || self.frames == 0 {
+            self.present()?;
+        }
+        Ok(())
+    }
+}
+
+/// PNGのチャンクを書く (長さ・種類・データ・CRC)
+fn png_chunk(data: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    data.extend((body.len() as u32).to_be_bytes());
+    let start = data.len();
+    data.extend(kind);
+    data.extend(body);
+    let crc = crc32(&data[start..]);
+    data.extend(crc.to_be_bytes());
+}
+
+/// 無圧縮のブロックだけでできたzlibのデータ
+fn zlib_stored(raw: &[u8]) -> Vec<u8> {
+    let mut data = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        data.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none() as u8;
+        let length = block.len() as u16;
+        data.push(last);
+        data.extend(length.to_le_bytes());
+        data.extend((!length).to_le_bytes());
+        data.extend(block);
+    }
+    data.extend(adler32(raw).to_be_bytes());
+    data
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(windows)]
+mod console {
+    use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
+    use winapi::um::processenv::GetStdHandle;
+    use winapi::um::winbase::STD_OUTPUT_HANDLE;
+    use winapi::um::wincon::ENABLE_VIRTUAL_TERMINAL_PROCESSING;
+
+    /// 色のエスケープシーケンスが使えるようにする
+    pub fn enable_colors() {
+        unsafe {
+            let output = GetStdHandle(STD_OUTPUT_HANDLE);
+            let mut mode = 0;
+            if GetConsoleMode(output, &mut mode) != 0 {
+                SetConsoleMode(output, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING);
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod console {
+    pub fn enable_colors() {}
+}
+
+#[cfg(test)]
+mod test_framebuffer {
+    use crate::device;
+    use crate::framebuffer::{crc32, Framebuffer};
+    use crate::testing;
+    use crate::vm::{State, VirtualMachine};
+
+    fn machine(code: &str, spec: &str) -> VirtualMachine {
+        let mut vm = testing::machine("framebuffer.asm", code);
+        let (base, device) = device::create(spec).unwrap();
+        vm.map_device(base, device).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_present_and_finish() {
+        // 2x2の左上を白、右下を赤にして出力し、左下を緑にして終わる
+        let output = testing::temp_path("frame{}.ppm").display().to_string();
+        let code = "push\n15\npush\n2004\nstore\npush\n12\npush\n2007\nstore\n\
+                    push\n0\npush\n2003\nstore\npush\n10\npush\n2006\nstore\n\
+                    push\n2003\nload\noutputnum\nhalt\n";
+        let mut vm = machine(code, &format!("framebuffer:2x2,{output}"));
+        vm.run();
+        assert_eq!(vm.state(), &State::Halted);
+        assert_eq!(vm.output(), "1");
+        vm.finish_devices().unwrap();
+
+        let first = std::fs::read(output.replace("{}", "0")).unwrap();
+        let mut expected = b"P6\n2 2\n255\n".to_vec();
+        expected.extend([255, 255, 255, 0, 0, 0, 0, 0, 0, 255, 85, 85]);
+        assert_eq!(first, expected);
+        let last = std::fs::read(output.replace("{}", "1")).unwrap();
+        assert_eq!(&last[expected.len() - 6..][..3], [85, 255, 85]);
+    }
+
+    #[test]
+    fn test_png_and_options() {
+        let mut framebuffer = Framebuffer::new("3x1,rgb").unwrap();
+        framebuffer.pixels = vec![0xFF0000, 0x00FF00, 0x0000FF];
+        let png = framebuffer.png();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        // IENDのCRCはいつも同じ
+        assert!(png.ends_with(&[0, 0, 0, 0, 73, 69, 78, 68, 0xAE, 0x42, 0x60, 0x82]));
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert!(framebuffer
+            .blocks()
+            .starts_with("\x1b[38;2;255;0;0m\x1b[48;2;0;0;0m▀"));
+
+        assert!(Framebuffer::new("0x5").is_err());
+        assert!(Framebuffer::new("screen.bmp").is_err());
+        assert!(device::create("framebuffer:8x8,term@3000").is_ok());
+        assert!(device::create("timer:8x8").is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::msg;
+use crate::vm::{State, VirtualMachine};
+
+/// GDBに渡すレジスタの定義 (pc・スタックポインタ・スタックの一番上)
+const TARGET_XML: &str = r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target><feature name="org.simple-vm.core"><reg name="pc" bitsize="32" type="code_ptr"/><reg name="sp" bitsize="32" type="int"/><reg name="top" bitsize="32" type="int"/></feature></target>"#;
+
+/// 1セルのバイト数 (GDBからはメモリをバイト単位で扱う)
+const CELL: usize = 4;
+
+/// 割り込みを確認するまでに実行する命令の数
+const POLL_INTERVAL: usize = 1024;
+
+/// GDBのリモートシリアルプロトコルで仮想マシンを操作するサーバー
+pub struct Stub<'a> {
+    vm: &'a mut VirtualMachine, // 操作する仮想マシン
+    breakpoints: Vec<usize>,    // ブレークポイントを設定したセル
+    ack: bool,                  // パケットを受け取るたびに+を返すか
+}
+
+/// 指定したポートでGDBの接続を待ち、切断されるまで仮想マシンを操作させる
+pub fn serve(vm: &mut VirtualMachine, port: u16) -> Result<(), String> {
+    let listener =
+        TcpListener::bind(("127.0.0.1", port)).map_err(|e| msg!("gdb.bind_failed", port, e))?;
+    println!("{}", msg!("gdb.waiting", port));
+    let (stream, address) = listener.accept().map_err(|e| e.to_string())?;
+    println!("{}", msg!("gdb.connected", address));
+    Stub::new(vm).session(stream).map_err(|e| e.to_string())
+}
+
+impl Stub<'_> {
+    pub fn new(vm: &mut VirtualMachine) -> Stub<'_> {
+        Stub {
+            vm,
+            breakpoints: Vec::new(),
+            ack: true,
+        }
+    }
+
+    /// 接続が切れるまでパケットを処理する
+    pub fn session(&mut self, stream: TcpStream) -> std::io::Result<()> {
+        // 短いパケットをすぐに届ける
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut byte = [0];
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(());
+            }
+            let packet = match byte[0] {
+                b'$' => {
+                    let mut data = Vec::new();
+                    while reader.read(&mut byte)? == 1 && byte[0] != b'#' {
+                        data.push(byte[0]);
+                    }
+                    let mut sum = [0; 2];
+                    reader.read_exact(&mut sum)?;
+                    let sum = std::str::from_utf8(&sum).ok();
+                    if sum.and_then(|sum| u8::from_str_radix(sum, 16).ok()) != Some(checksum(&data))
+                    {
+                        writer.write_all(b"-")?;
+                        continue;
+                    }
+                    if self.ack {
+                        writer.write_all(b"+")?;
+                    }
+                    String::from_utf8_lossy(&data).to_string()
+                }
+                // 止まっているときの割り込みにはそのまま停止を返す
+                0x03 => "?".to_string(),
+                _ => continue,
+            };
+            let reply = self.handle(&packet, &writer);
+            send(&mut writer, &reply)?;
+            if packet == "k" || packet.starts_with('D') {
+                return Ok(());
+            }
+            if packet == "QStartNoAckMode" {
+                self.ack = false;
+            }
+        }
+    }
+
+    /// パケットを1つ処理して返信を作る
+    fn handle(&mut self, packet: &str, stream: &TcpStream) -> String {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" => self.stop_reply(),
+            "g" => (0..3).map(|n| hex_word(self.register(n))).collect(),
+            "G" => {
+                let values: Option<Vec<u32>> = (0..args.len() / 8)
+                    .map(|i| parse_word(&args[i * 8..i * 8 + 8]))
+                    .collect();
+                match values {
+                    Some(values) => {
+                        for (n, value) in values.into_iter().enumerate() {
+                            // spは書き換えられないので無視する
+                            let _ = self.set_register(n, value);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < 3 => hex_word(self.register(n)),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let result = args.split_once('=').and_then(|(n, value)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    self.set_register(n, parse_word(value)?).ok()
+                });
+                reply(result)
+            }
+            "m" => {
+                let range = parse_range(args);
+                range
+                    .and_then(|(address, length)| self.read_memory(address, length))
+                    .unwrap_or("E01".to_string())
+            }
+            "M" => {
+                let result = args.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_range(range)?;
+                    let bytes = parse_bytes(data)?;
+                    (bytes.len() == length).then_some(())?;
+                    self.write_memory(address, &bytes)
+                });
+                reply(result)
+            }
+            "s" => {
+                self.vm.step();
+                self.stop_reply()
+            }
+            "c" => self.resume(stream),
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                if fields.next() != Some("0") {
+                    // ソフトウェアブレークポイント以外には対応しない
+                    return String::new();
+                }
+                match fields
+                    .next()
+                    .and_then(|address| usize::from_str_radix(address, 16).ok())
+                {
+                    Some(address) => {
+                        let cell = address / CELL;
+                        self.breakpoints.retain(|b| *b != cell);
+                        if command == "Z" {
+                            self.breakpoints.push(cell);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "H" | "D" | "k" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        }
+    }
+
+    /// qやQで始まる問い合わせに答える
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_range(range) else {
+                return "E01".to_string();
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = (offset + length).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { "m" } else { "l" };
+            return format!("{more}{}", &TARGET_XML[start..end]);
+        }
+        match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            "QStartNoAckMode" => "OK",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    /// 停止した理由を返す
+    fn stop_reply(&self) -> String {
+        match self.vm.state() {
+            State::Running => "S05".to_string(),
+            State::Halted => "W00".to_string(),
+            State::Fault(_) => {
+                self.vm.report_fault();
+                "W01".to_string()
+            }
+        }
+    }
+
+    /// ブレークポイントか終了か割り込みまで実行する
+    fn resume(&mut self, stream: &TcpStream) -> String {
+        let mut count = 0;
+        loop {
+            self.vm.step();
+            if !self.vm.is_running() || self.breakpoints.contains(&self.vm.pc()) {
+                return self.stop_reply();
+            }
+            count += 1;
+            if count % POLL_INTERVAL == 0 && interrupted(stream) {
+                return "S02".to_string();
+            }
+        }
+    }
+
+    /// レジスタの値 (0: pc 1: sp 2: スタックの一番上)
+    fn register(&self, n: usize) -> u32 {
+        match n {
+            0 => (self.vm.pc() * CELL) as u32,
+            1 => self.vm.stack().len() as u32,
+            _ => self.vm.stack().last().copied().unwrap_or(0) as u32,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, value: u32) -> Result<(), String> {
+        match n {
+            0 => {
+                self.vm.set_pc(value as usize / CELL);
+                Ok(())
+            }
+            2 => match self.vm.stack().len() {
+                0 => Err(msg!("gdb.empty_stack")),
+                len => self.vm.set_stack(len - 1, value as i32),
+            },
+            _ => Err(msg!("gdb.read_only_register")),
+        }
+    }
+
+    /// メモリをバイト単位で読み出す (1セルは4バイトのリトルエンディアン)
+    fn read_memory(&self, address: usize, length: usize) -> Option<String> {
+        let memory = self.vm.memory();
+        (address..address + length)
+            .map(|byte| {
+                let cell = memory.get(byte / CELL)?;
+                Some(format!("{:02x}", (cell >> (byte % CELL * 8)) & 0xff))
+            })
+            .collect()
+    }
+
+    /// メモリにバイト単位で書き込む
+    fn write_memory(&mut self, address: usize, bytes: &[u8]) -> Option<()> {
+        for (i, byte) in bytes.iter().enumerate() {
+            let (cell, shift) = ((address + i) / CELL, (address + i) % CELL * 8);
+            let old = *self.vm.memory().get(cell)?;
+            let value = (old & !(0xff << shift)) | ((*byte as i32) << shift);
+            self.vm.set_memory(cell, value).ok()?;
+        }
+        Some(())
+    }
+}
+
+/// 実行中にGDBから割り込み(0x03)が届いたか確認する
+fn interrupted(stream: &TcpStream) -> bool {
+    let mut byte = [0];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let pending = matches!(stream.peek(&mut byte), Ok(1) if byte[0] == 0x03);
+    let _ = stream.set_nonblocking(false);
+    if pending {
+        let _ = (&*stream).read(&mut byte);
+    }
+    pending
+}
+
+/// パケットを送る
+fn send(stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+    write!(stream, "${data}#{:02x}", checksum(data.as_bytes()))?;
+    stream.flush()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn reply(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+/// 32ビットの値をリトルエンディアンの16進数にする
+fn hex_word(value: u32) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn parse_word(hex: &str) -> Option<u32> {
+    let bytes: [u8; 4] = parse_bytes(hex)?.try_into().ok()?;
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+/// `アドレス,長さ`を読む
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod test_gdb {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::gdb::{checksum, Stub};
+    use crate::object::Object;
+    use crate::testing;
+    use crate::vm::{Mode, VirtualMachine};
+
+    /// パケットを送って返信を受け取る
+    fn request(client: &mut TcpStream, data: &str) -> String {
+        write!(client, "${data}#{:02x}", checksum(data.as_bytes())).unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while client.read(&mut byte).unwrap() == 1 && byte[0] != b'#' {
+            if byte[0] != b'+' && byte[0] != b'$' {
+                reply.push(byte[0]);
+            }
+        }
+        let mut sum = [0; 2];
+        client.read_exact(&mut sum).unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_remote_session() {
+        let (_, storage) = testing::storage("gdb.txt", "");
+        // push 5 / push 9 / store / halt
+        let program = Object {
+            code: vec![6, 5, 6, 9, 15, 20],
+            ..Object::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut vm = VirtualMachine::new(storage, program, Mode::Execute);
+            let (stream, _) = listener.accept().unwrap();
+            Stub::new(&mut vm).session(stream).unwrap();
+            vm.memory()[9]
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_nodelay(true).unwrap();
+        assert_eq!(request(&mut client, "?"), "S05");
+        assert_eq!(request(&mut client, "m0,8"), "0600000005000000");
+        assert_eq!(request(&mut client, "s"), "S05");
+        assert_eq!(request(&mut client, "g"), "080000000100000005000000");
+        assert_eq!(request(&mut client, "Z0,10,4"), "OK");
+        assert_eq!(request(&mut client, "c"), "S05");
+        assert_eq!(request(&mut client, "p0"), "10000000");
+        assert_eq!(request(&mut client, "M0,1:07"), "OK");
+        assert_eq!(request(&mut client, "c"), "W00");
+        assert_eq!(request(&mut client, "m24,4"), "05000000");
+        assert_eq!(request(&mut client, "k"), "OK");
+        assert_eq!(server.join().unwrap(), 5);
+    }
+}
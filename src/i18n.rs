@@ -0,0 +1,209 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+/// 日本語のメッセージカタログ
+const JAPANESE: &str = include_str!("locales/ja.txt");
+
+/// 英語のメッセージカタログ
+const ENGLISH: &str = include_str!("locales/en.txt");
+
+/// 表示する言語
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    Japanese,
+    English,
+}
+
+thread_local! {
+    /// 今使っている言語
+    static LANGUAGE: Cell<Language> = const { Cell::new(Language::Japanese) };
+}
+
+impl Language {
+    /// `ja`や`en_US.UTF-8`のような名前から言語を決める
+    pub fn parse(name: &str) -> Option<Language> {
+        let name = name.to_ascii_lowercase();
+        if name.starts_with("ja") {
+            Some(Language::Japanese)
+        } else if name.starts_with("en") {
+            Some(Language::English)
+        } else {
+            None
+        }
+    }
+
+    /// 言語のメッセージカタログ
+    fn catalog(self) -> &'static HashMap<String, String> {
+        static CATALOGS: OnceLock<[HashMap<String, String>; 2]> = OnceLock::new();
+        let catalogs = CATALOGS.get_or_init(|| [parse_catalog(JAPANESE), parse_catalog(ENGLISH)]);
+        match self {
+            Language::Japanese => &catalogs[0],
+            Language::English => &catalogs[1],
+        }
+    }
+}
+
+/// 表示する言語を決める
+pub fn set_language(language: Language) {
+    LANGUAGE.with(|current| current.set(language));
+}
+
+/// 今使っている言語
+pub fn language() -> Language {
+    LANGUAGE.with(Cell::get)
+}
+
+/// コマンドライン引数の`--lang`か環境変数`LANG`から言語を決める (どちらもなければ日本語)
+pub fn detect(args: &[String]) -> Language {
+    let option = (args.iter())
+        .position(|arg| arg == "--lang")
+        .and_then(|index| args.get(index + 1))
+        .and_then(|name| Language::parse(name));
+    option
+        .or_else(|| {
+            std::env::var("LANG")
+                .ok()
+                .and_then(|name| Language::parse(&name))
+        })
+        .unwrap_or(Language::Japanese)
+}
+
+/// メッセージを今の言語で組み立てる ({0}・{1}…を引数に置き換える)
+pub fn message(key: &str, args: &[&dyn Display]) -> String {
+    let template = (language().catalog().get(key))
+        .or_else(|| Language::Japanese.catalog().get(key))
+        .map_or(key, String::as_str);
+    let mut text = template.to_string();
+    for (i, arg) in args.iter().enumerate() {
+        text = text.replace(&format!("{{{i}}}"), &arg.to_string());
+    }
+    text
+}
+
+/// メッセージカタログを読む
+/// (`キー = 文`の形式で、値を省略した次の行から`|`で始まる行は複数行の文として続ける)
+fn parse_catalog(text: &str) -> HashMap<String, String> {
+    let mut catalog = HashMap::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix('|') {
+            if let Some((_, lines)) = &mut current {
+                lines.push(rest.strip_prefix(' ').unwrap_or(rest));
+            }
+            continue;
+        }
+        if let Some((key, lines)) = current.take() {
+            catalog.insert(key, lines.join("\n"));
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(" =") {
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            if value.is_empty() {
+                current = Some((key.trim().to_string(), Vec::new()));
+            } else {
+                catalog.insert(key.trim().to_string(), value.to_string());
+            }
+        }
+    }
+    if let Some((key, lines)) = current {
+        catalog.insert(key, lines.join("\n"));
+    }
+    catalog
+}
+
+/// メッセージカタログから今の言語のメッセージを組み立てる
+#[macro_export]
+macro_rules! msg {
+    ($key:expr $(, $arg:expr)* $(,)?) => {
+        $crate::i18n::message($key, &[$(&$arg as &dyn std::fmt::Display),*])
+    };
+}
+
+#[cfg(test)]
+mod test_i18n {
+    use crate::i18n::{self, parse_catalog, Language, ENGLISH, JAPANESE};
+
+    /// {0}のような置き換える場所を並べる
+    fn placeholders(text: &str) -> Vec<String> {
+        (0..10)
+            .map(|i| format!("{{{i}}}"))
+            .filter(|placeholder| text.contains(placeholder.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_catalogs_match() {
+        let japanese = parse_catalog(JAPANESE);
+        let english = parse_catalog(ENGLISH);
+        let mut missing: Vec<&String> = (japanese.keys())
+            .filter(|key| !english.contains_key(*key))
+            .chain(english.keys().filter(|key| !japanese.contains_key(*key)))
+            .collect();
+        missing.sort();
+        assert!(missing.is_empty(), "片方にしかないキー: {missing:?}");
+        for (key, text) in &japanese {
+            assert_eq!(placeholders(text), placeholders(&english[key]), "{key}");
+        }
+    }
+
+    /// ソースのmsg!で使っているキー (テストのモジュールは除く)
+    fn used_keys() -> Vec<String> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        let mut keys = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some("rs".as_ref()) {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).unwrap();
+            let code = text.split("#[cfg(test)]").next().unwrap_or_default();
+            for (start, _) in code.match_indices("msg!(") {
+                let rest = code[start + 5..].trim_start();
+                if let Some(key) = rest
+                    .strip_prefix('"')
+                    .and_then(|rest| rest.split('"').next())
+                {
+                    keys.push(key.to_string());
+                }
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    #[test]
+    fn test_used_keys_exist() {
+        let japanese = parse_catalog(JAPANESE);
+        let english = parse_catalog(ENGLISH);
+        let keys = used_keys();
+        assert!(keys.len() > 100);
+        let missing: Vec<&String> = (keys.iter())
+            .filter(|key| !japanese.contains_key(*key) || !english.contains_key(*key))
+            .collect();
+        assert!(missing.is_empty(), "カタログにないキー: {missing:?}");
+    }
+
+    #[test]
+    fn test_message_and_language() {
+        assert_eq!(Language::parse("en_US.UTF-8"), Some(Language::English));
+        assert_eq!(Language::parse("ja_JP"), Some(Language::Japanese));
+        assert_eq!(Language::parse("C"), None);
+        let args = ["run".to_string(), "--lang".to_string(), "en".to_string()];
+        assert_eq!(i18n::detect(&args), Language::English);
+
+        assert_eq!(msg!("vm.fault.divide_by_zero"), "0で割ることはできません");
+        i18n::set_language(Language::English);
+        assert_eq!(msg!("vm.log.add", 1, 2), "Adding 1 and 2");
+        assert!(msg!("cli.help").lines().count() > 10);
+        // 複数行の文は途中で切れずに最後まで読み込まれる
+        assert!(msg!("cli.help").trim_end().ends_with("show the version"));
+        assert_eq!(msg!("no.such.key"), "no.such.key");
+        i18n::set_language(Language::Japanese);
+        assert_eq!(msg!("vm.log.add", 1, 2), "1と2を足します");
+    }
+}
@@ -2,25 +2,114 @@
 #[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     Nop,
-    Add,        // 足し算する
-    Sub,        // 引き算する
-    Mul,        // 掛け算する
-    Div,        // 割り算する
-    Mod,        // 割り算の余り
-    Push(i32),  // スタックに値をプッシュ
-    Pop,        // スタックの値をポップ
-    Equal,      // 等しいか判断
-    LessThan,   // 未満か判断
-    And,        // AND演算を行う
-    Or,         // OR演算を行う
-    Not,        // NOT演算を行う
-    JumpIfZero, // 値が0の場合ジャンプする
-    Load,       // メモリの値を読み込む
-    Store,      // メモリに値を保存する
-    Input,      // 入力を受け付ける
-    Output,     // UTF-8で出力する
-    Read,       // ストレージを読み込む
-    Write,      // ストレージに書き込む
-    Halt,       // プログラムを終了する
-    WinAPI,     // Windows APIを呼び出す
+    Add,                 // 足し算する
+    Sub,                 // 引き算する
+    Mul,                 // 掛け算する
+    Div,                 // 割り算する
+    Mod,                 // 割り算の余り
+    Push(i32),           // スタックに値をプッシュ
+    Pop,                 // スタックの値をポップ
+    Equal,               // 等しいか判断
+    LessThan,            // 未満か判断
+    And,                 // AND演算を行う
+    Or,                  // OR演算を行う
+    Not,                 // NOT演算を行う
+    JumpIfZero,          // 値が0の場合ジャンプする
+    Load,                // メモリの値を読み込む
+    Store,               // メモリに値を保存する
+    Input,               // 入力を受け付ける
+    Output,              // UTF-8で出力する
+    Read,                // ストレージを読み込む
+    Write,               // ストレージに書き込む
+    Halt,                // プログラムを終了する
+    WinAPI,              // Windows APIを呼び出す
+    InputChar,           // 入力から1文字を読み込む
+    InputString,         // 入力から1行をメモリに読み込む
+    OutputNumber,        // 数値を10進数で出力する
+    OutputString,        // メモリの0で終わる文字列を出力する
+    EndOfInput,          // 入力が終わったか判断する
+    EnableInterrupts,    // 割り込みを受け付ける
+    DisableInterrupts,   // 割り込みを受け付けない
+    ReturnFromInterrupt, // 割り込みの処理から戻る
+    SystemCall,          // システムコールを呼び出す
+}
+
+impl Instruction {
+    /// メモリから命令を読み取る (命令とその長さを返す)
+    pub fn decode(memory: &[i32], address: usize) -> Option<(Instruction, usize)> {
+        let instruction = match *memory.get(address)? {
+            0 => Instruction::Nop,
+            1 => Instruction::Add,
+            2 => Instruction::Sub,
+            3 => Instruction::Mul,
+            4 => Instruction::Div,
+            5 => Instruction::Mod,
+            6 => return Some((Instruction::Push(*memory.get(address + 1)?), 2)),
+            7 => Instruction::Pop,
+            8 => Instruction::Equal,
+            9 => Instruction::LessThan,
+            10 => Instruction::And,
+            11 => Instruction::Or,
+            12 => Instruction::Not,
+            13 => Instruction::JumpIfZero,
+            14 => Instruction::Load,
+            15 => Instruction::Store,
+            16 => Instruction::Input,
+            17 => Instruction::Output,
+            18 => Instruction::Read,
+            19 => Instruction::Write,
+            20 => Instruction::Halt,
+            21 => Instruction::WinAPI,
+            22 => Instruction::InputChar,
+            23 => Instruction::InputString,
+            24 => Instruction::OutputNumber,
+            25 => Instruction::OutputString,
+            26 => Instruction::EndOfInput,
+            27 => Instruction::EnableInterrupts,
+            28 => Instruction::DisableInterrupts,
+            29 => Instruction::ReturnFromInterrupt,
+            30 => Instruction::SystemCall,
+            _ => return None,
+        };
+        Some((instruction, 1))
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mnemonic = match self {
+            Instruction::Nop => "nop",
+            Instruction::Add => "add",
+            Instruction::Sub => "sub",
+            Instruction::Mul => "mul",
+            Instruction::Div => "div",
+            Instruction::Mod => "mod",
+            Instruction::Push(value) => return write!(f, "push {value}"),
+            Instruction::Pop => "pop",
+            Instruction::Equal => "equal",
+            Instruction::LessThan => "lessthan",
+            Instruction::And => "and",
+            Instruction::Or => "or",
+            Instruction::Not => "not",
+            Instruction::JumpIfZero => "jump",
+            Instruction::Load => "load",
+            Instruction::Store => "store",
+            Instruction::Input => "input",
+            Instruction::Output => "output",
+            Instruction::Read => "read",
+            Instruction::Write => "write",
+            Instruction::Halt => "halt",
+            Instruction::WinAPI => "winapi",
+            Instruction::InputChar => "inputchar",
+            Instruction::InputString => "inputstr",
+            Instruction::OutputNumber => "outputnum",
+            Instruction::OutputString => "outputstr",
+            Instruction::EndOfInput => "eof",
+            Instruction::EnableInterrupts => "ei",
+            Instruction::DisableInterrupts => "di",
+            Instruction::ReturnFromInterrupt => "iret",
+            Instruction::SystemCall => "syscall",
+        };
+        write!(f, "{mnemonic}")
+    }
 }
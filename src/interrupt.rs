@@ -0,0 +1,249 @@
+use std::collections::BTreeSet;
+
+use crate::msg;
+
+/// タイマーの割り込み番号
+pub const TIMER: usize = 0;
+
+/// キーボードの割り込み番号
+pub const KEYBOARD: usize = 1;
+
+/// 割り込みコントローラ
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Controller {
+    enabled: bool,            // 割り込みを受け付けるか
+    table: Option<usize>,     // 割り込みベクタテーブルの番地 (eiするまではNone)
+    pending: BTreeSet<usize>, // まだ受け付けていない割り込みの番号
+    frames: Vec<usize>,       // 割り込みから戻る番地
+}
+
+impl Controller {
+    /// 割り込みを受け付けるか
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// 割り込みの処理中か
+    pub fn in_handler(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// ベクタテーブルの番地を決めて割り込みを受け付ける
+    pub fn enable(&mut self, table: usize) {
+        self.table = Some(table);
+        self.enabled = true;
+    }
+
+    /// 割り込みを受け付けない (起きた割り込みは受け付けるまで残る)
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    /// 割り込みを起こす
+    pub fn raise(&mut self, number: usize) {
+        self.pending.insert(number);
+    }
+
+    /// 割り込みを処理する番地 (ベクタテーブルがないか値が0ならNone、メモリの範囲外ならエラー)
+    pub fn handler(&self, number: usize, memory: &[i32]) -> Result<Option<usize>, String> {
+        let Some(&handler) = self.table.and_then(|table| memory.get(table + number)) else {
+            return Ok(None);
+        };
+        if handler <= 0 {
+            return Ok(None);
+        }
+        if handler as usize >= memory.len() {
+            return Err(msg!("vm.fault.bad_vector", number, handler));
+        }
+        Ok(Some(handler as usize))
+    }
+
+    /// 起きている割り込みを取り除く (起きていればtrue)
+    pub fn take(&mut self, number: usize) -> bool {
+        self.pending.remove(&number)
+    }
+
+    /// 割り込みを受け付けるなら番号と処理の番地を返す (番号の小さいものを優先する)
+    /// (ベクタテーブルの値が0の割り込みは捨てる)
+    pub fn dispatch(
+        &mut self,
+        pc: usize,
+        memory: &[i32],
+    ) -> Result<Option<(usize, usize)>, String> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let Some(number) = self.pending.pop_first() else {
+            return Ok(None);
+        };
+        let Some(handler) = self.handler(number, memory)? else {
+            return Ok(None);
+        };
+        // 処理の間は割り込みを受け付けない
+        self.frames.push(pc);
+        self.enabled = false;
+        Ok(Some((number, handler)))
+    }
+
+    /// 割り込みの処理から戻る番地を返す
+    pub fn ret(&mut self) -> Result<usize, String> {
+        let pc = self.frames.pop().ok_or(msg!("vm.fault.not_in_interrupt"))?;
+        self.enabled = true;
+        Ok(pc)
+    }
+
+    /// スナップショットに書く形式にする (受け付けるか・ベクタテーブル・起きている割り込み・戻る番地)
+    pub fn to_text(&self) -> String {
+        let list = |values: Vec<String>| {
+            if values.is_empty() {
+                "-".to_string()
+            } else {
+                values.join(",")
+            }
+        };
+        format!(
+            "{} {} {} {}",
+            self.enabled as u8,
+            self.table
+                .map_or("-".to_string(), |table| table.to_string()),
+            list(self.pending.iter().map(usize::to_string).collect()),
+            list(self.frames.iter().map(usize::to_string).collect())
+        )
+    }
+
+    /// to_textの形式から読み込む (正しくなければNone)
+    pub fn parse(text: &str) -> Option<Controller> {
+        let [enabled, table, pending, frames] = text.split_whitespace().collect::<Vec<_>>()[..]
+        else {
+            return None;
+        };
+        let list = |text: &str| -> Option<Vec<usize>> {
+            match text {
+                "-" => Some(Vec::new()),
+                text => text.split(',').map(|value| value.parse().ok()).collect(),
+            }
+        };
+        Some(Controller {
+            enabled: match enabled {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            },
+            table: match table {
+                "-" => None,
+                table => Some(table.parse().ok()?),
+            },
+            pending: list(pending)?.into_iter().collect(),
+            frames: list(frames)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_interrupt {
+    use crate::device;
+    use crate::interrupt::{Controller, KEYBOARD, TIMER};
+    use crate::testing;
+    use crate::vm::{State, VirtualMachine};
+
+    fn machine(code: &str) -> VirtualMachine {
+        let mut vm = testing::machine("interrupt.asm", code);
+        for name in ["console", "timer"] {
+            let (base, device) = device::create(name).unwrap();
+            vm.map_device(base, device).unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        // 20命令ごとのタイマー割り込みでcountを増やし、3になるまで待つ
+        let code = "push\ntable\nei\npush\n20\npush\n1012\nstore\n\
+                    loop: push\nloop\npush\ncount\nload\npush\n3\nequal\njump\ndi\nhalt\n\
+                    handler: push\ncount\nload\npush\n1\nadd\npush\ncount\nstore\niret\n\
+                    table: .word handler, 0\ncount: .word 0\n";
+        let mut vm = machine(code);
+        vm.run();
+        assert_eq!(vm.state(), &State::Halted);
+        let count = vm.debug_info().address("count").unwrap();
+        assert_eq!(vm.memory()[count], 3);
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn test_keyboard_interrupt() {
+        // キーボード割り込みで1行を読んで出力する (2行読んだら終わる)
+        let code = "push\ntable\nei\npush\n1\npush\n1003\nstore\n\
+                    loop: push\nloop\npush\nlines\nload\npush\n2\nequal\njump\nhalt\n\
+                    handler: push\nbuf\ninputstr\npop\npush\nbuf\noutputstr\n\
+                    push\nlines\nload\npush\n1\nadd\npush\nlines\nstore\niret\n\
+                    table: .word 0, handler\nlines: .word 0\nbuf: .zero 8\n";
+        let mut vm = machine(code);
+        vm.push_input("ab".to_string());
+        vm.push_input("cd".to_string());
+        vm.run();
+        assert_eq!(vm.state(), &State::Halted);
+        assert_eq!(vm.output(), "abcd");
+    }
+
+    #[test]
+    fn test_vector_out_of_range() {
+        // ベクタテーブルの処理の番地がメモリの外なら、タイマー割り込みでエラーになる
+        let code = "push\ntable\nei\npush\n5\npush\n1012\nstore\n\
+                    loop: push\nloop\npush\n0\njump\n\
+                    table: .word 9999, 0\n";
+        let mut vm = machine(code);
+        vm.run();
+        assert!(matches!(vm.state(), State::Fault(message) if message.contains("9999")));
+    }
+
+    #[test]
+    fn test_controller() {
+        let mut memory = vec![0; 40];
+        memory[1..3].copy_from_slice(&[20, 30]);
+        let mut controller = Controller::default();
+        controller.raise(KEYBOARD);
+        controller.raise(TIMER);
+        assert_eq!(controller.dispatch(7, &memory), Ok(None));
+        controller.enable(1);
+        assert_eq!(controller.dispatch(7, &memory), Ok(Some((TIMER, 20))));
+        assert!(controller.in_handler() && !controller.is_enabled());
+        assert_eq!(controller.ret(), Ok(7));
+        assert_eq!(controller.dispatch(9, &memory), Ok(Some((KEYBOARD, 30))));
+        assert_eq!(controller.ret(), Ok(9));
+        assert!(controller.ret().is_err());
+        // 処理の番地がメモリの範囲外ならエラー
+        controller.raise(TIMER);
+        assert!(controller.dispatch(9, &[0, 3, 30]).is_err());
+    }
+
+    #[test]
+    fn test_controller_text() {
+        let mut controller = Controller::default();
+        assert_eq!(controller.to_text(), "0 - - -");
+        controller.enable(4);
+        controller.raise(KEYBOARD);
+        controller.raise(TIMER);
+        assert_eq!(controller.dispatch(7, &[0; 8]), Ok(None));
+        controller.raise(TIMER);
+        let text = controller.to_text();
+        assert_eq!(text, "1 4 0,1 -");
+        assert_eq!(Controller::parse(&text), Some(controller));
+        assert_eq!(Controller::parse("2 - - -"), None);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_interrupts() {
+        // eiした後のスナップショットから復元しても割り込みを受け付ける
+        let mut vm = testing::machine(
+            "interrupt.asm",
+            "push\ntable\nei\nhalt\ntable: .word 0, 0\n",
+        );
+        vm.run();
+        let snapshot = vm.snapshot().unwrap();
+        let (_, storage) = testing::storage("interrupt.txt", "");
+        let restored = VirtualMachine::restore(&snapshot, storage).unwrap();
+        assert!(restored.interrupts().is_enabled());
+        assert_eq!(restored.interrupts(), vm.interrupts());
+    }
+}
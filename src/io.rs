@@ -1,5 +1,7 @@
 use std::fs::File;
-use std::io::{self, BufRead, Error, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, Error, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
 
 pub fn input(prompt: &str) -> String {
     print!("{}", prompt.to_string());
@@ -9,6 +11,32 @@ pub fn input(prompt: &str) -> String {
     return result.trim().parse().ok().unwrap();
 }
 
+/// 1行を読み込む (入力が終わっていればNone、プロンプトは端末のときだけ表示する)
+pub fn read_line(prompt: &str) -> Option<String> {
+    if io::stdin().is_terminal() {
+        print!("{prompt}");
+        io::stdout().flush().ok();
+    }
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+    }
+}
+
+/// 標準入力を別のスレッドで1行ずつ読む (入力が終わるとNoneを送る)
+pub fn spawn_reader() -> Receiver<Option<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        let line = read_line("");
+        let end = line.is_none();
+        if sender.send(line).is_err() || end {
+            break;
+        }
+    });
+    receiver
+}
+
 /// ファイルを開く
 pub fn open_file(name: String) -> Result<File, Error> {
     let mut binding = std::fs::OpenOptions::new();
@@ -17,6 +45,9 @@ pub fn open_file(name: String) -> Result<File, Error> {
 }
 
 pub fn read_specific_line(mut file: &File, line_number: usize) -> io::Result<String> {
+    if line_number == 0 {
+        return Err(out_of_range());
+    }
     file.seek(SeekFrom::Start(0))?;
     let reader = io::BufReader::new(file);
 
@@ -27,10 +58,7 @@ pub fn read_specific_line(mut file: &File, line_number: usize) -> io::Result<Str
         }
     }
 
-    Err(io::Error::new(
-        io::ErrorKind::InvalidInput,
-        "Line number out of range",
-    ))
+    Err(out_of_range())
 }
 
 pub fn write_specific_line(mut file: &File, line_number: usize, text: &str) -> io::Result<()> {
@@ -39,8 +67,8 @@ pub fn write_specific_line(mut file: &File, line_number: usize, text: &str) -> i
 
     let lines: Vec<_> = reader.lines().collect::<io::Result<_>>()?;
     // dbg!(line_number, lines.len());
-    if line_number > lines.len() {
-        panic!("Line number out of range");
+    if line_number == 0 || line_number > lines.len() {
+        return Err(out_of_range());
     }
 
     let mut contents = String::new();
@@ -69,6 +97,27 @@ pub fn write_specific_line(mut file: &File, line_number: usize, text: &str) -> i
     Ok(())
 }
 
+/// 行番号がファイルの範囲外のときのエラー
+fn out_of_range() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Line number out of range")
+}
+
+/// ファイル全体を読み込む
+pub fn read_all(mut file: &File) -> io::Result<String> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// ファイル全体を書き換える
+pub fn write_all(mut file: &File, text: &str) -> io::Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()
+}
+
 #[cfg(test)]
 mod test_file {
     use std::fs::{self, File};
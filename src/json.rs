@@ -0,0 +1,328 @@
+use std::fmt;
+
+use crate::msg;
+
+/// JSONの値 (数値は整数だけを扱う)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// JSONの文字列を読み込む
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value()?;
+        if parser.peek().is_some() {
+            return Err(msg!("json.trailing", parser.pos + 1));
+        }
+        Ok(value)
+    }
+
+    /// 項目と値の組からオブジェクトを作る
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// オブジェクトの項目の値
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// 数値を取り出す (数値でなければエラー)
+    pub fn number(&self) -> Result<i64, String> {
+        self.as_i64().ok_or(msg!("json.not_number"))
+    }
+
+    /// 配列を取り出す (配列でなければエラー)
+    pub fn into_array(self) -> Result<Vec<Json>, String> {
+        match self {
+            Json::Array(values) => Ok(values),
+            _ => Err(msg!("json.not_array")),
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Json {
+        Json::Number(value)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(value: i32) -> Json {
+        Json::Number(value as i64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Json {
+        Json::Number(value as i64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Json {
+        Json::String(value)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Json {
+        Json::Array(values)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{value}"),
+            Json::Number(value) => write!(f, "{value}"),
+            Json::String(text) => write_string(f, text),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// 文字列をエスケープして書く
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// JSONパーサ
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(msg!("json.expected", self.pos + 1, c))
+        }
+    }
+
+    /// 区切り文字で囲まれたカンマ区切りの要素を読む
+    fn list<T>(
+        &mut self,
+        end: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let mut items = Vec::new();
+        if self.peek() == Some(end) {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.peek() == Some(',') {
+                self.pos += 1;
+            } else {
+                self.expect(end)?;
+                return Ok(items);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let fields = self.list('}', |parser| {
+                    let key = parser.string()?;
+                    parser.expect(':')?;
+                    Ok((key, parser.value()?))
+                })?;
+                Ok(Json::Object(fields))
+            }
+            Some('[') => {
+                self.pos += 1;
+                Ok(Json::Array(self.list(']', Self::value)?))
+            }
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some(_) => {
+                let rest = &self.text[self.pos..];
+                let length = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c)))
+                    .unwrap_or(rest.len());
+                let token = &rest[..length];
+                let start = self.pos;
+                self.pos += length;
+                match token {
+                    "null" => Ok(Json::Null),
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    _ => (token.parse().ok())
+                        .or_else(|| token.parse::<f64>().ok().map(|value| value as i64))
+                        .map(Json::Number)
+                        .ok_or(msg!("json.not_value", start + 1)),
+                }
+            }
+            None => Err(msg!("json.missing_value")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        let mut chars = self.text[self.pos..].chars();
+        while let Some(c) = chars.next() {
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(result),
+                '\\' => {
+                    let escaped = chars.next().ok_or(msg!("json.unterminated"))?;
+                    self.pos += 1;
+                    match escaped {
+                        'n' => result.push('\n'),
+                        'r' => result.push('\r'),
+                        't' => result.push('\t'),
+                        'b' => result.push('\u{8}'),
+                        'f' => result.push('\u{c}'),
+                        'u' => {
+                            let mut code = hex4(&mut chars)?;
+                            self.pos += 4;
+                            // サロゲートペアは2つ合わせて1文字にする
+                            if (0xd800..0xdc00).contains(&code) && chars.as_str().starts_with("\\u")
+                            {
+                                chars.nth(1);
+                                let low = hex4(&mut chars)?;
+                                self.pos += 6;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            result.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        c => result.push(c),
+                    }
+                }
+                c => result.push(c),
+            }
+        }
+        Err(msg!("json.unterminated"))
+    }
+}
+
+/// \uの後の4桁の16進数を読む
+fn hex4(chars: &mut std::str::Chars) -> Result<u32, String> {
+    let code: String = chars.by_ref().take(4).collect();
+    u32::from_str_radix(&code, 16).map_err(|_| msg!("json.bad_escape"))
+}
+
+#[cfg(test)]
+mod test_json {
+    use crate::json::Json;
+
+    #[test]
+    fn test_parse_and_print() {
+        let text = r#"{"seq":1,"arguments":{"lines":[3,-4],"ok":true,"name":"a\"bあ\ud83d\ude00"},"x":null}"#;
+        let json = Json::parse(text).unwrap();
+        let arguments = json.get("arguments").unwrap();
+        assert_eq!(json.get("seq").and_then(Json::as_i64), Some(1));
+        assert_eq!(
+            arguments.get("name").and_then(Json::as_str),
+            Some("a\"bあ😀")
+        );
+        assert_eq!(arguments.get("ok").and_then(Json::as_bool), Some(true));
+        assert_eq!(Json::parse(&json.to_string()), Ok(json));
+        assert!(Json::parse("[1,").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use crate::msg;
+use crate::object::Object;
+
+/// オブジェクトモジュールを順番に並べて1つのメモリイメージにする
+pub fn link(objects: &[Object]) -> Result<Object, Vec<String>> {
+    let mut program = Object::default();
+    let memory = &mut program.code;
+    let mut symbols: HashMap<&str, i32> = HashMap::new();
+    let mut errors: Vec<String> = Vec::new();
+    let mut bases: Vec<usize> = Vec::new();
+
+    // 各モジュールの配置先を決めて公開シンボルを集める
+    for (index, object) in objects.iter().enumerate() {
+        let base = memory.len();
+        bases.push(base);
+        for (name, offset) in &object.exports {
+            if symbols.insert(name, (base + offset) as i32).is_some() {
+                errors.push(msg!("linker.duplicate_symbol", index + 1, name));
+            }
+        }
+        memory.extend(&object.code);
+        program.debug.append(&object.debug, base);
+    }
+
+    // モジュール内のアドレスを再配置して外部シンボルを解決する
+    for (object, base) in objects.iter().zip(bases) {
+        for offset in &object.relocations {
+            memory[base + offset] += base as i32;
+        }
+        for (offset, name) in &object.imports {
+            match symbols.get(name.as_str()) {
+                Some(address) => memory[base + offset] = *address,
+                None => errors.push(msg!("linker.undefined_symbol", name)),
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod test_linker {
+    use crate::linker::link;
+    use crate::object::Object;
+
+    #[test]
+    fn test_link_relocates_and_resolves() {
+        let main = Object {
+            code: vec![6, 0, 13, 6, 3],
+            imports: vec![(1, "lib".to_string())],
+            relocations: vec![4],
+            ..Object::default()
+        };
+        let lib = Object {
+            code: vec![20, 6, 0],
+            exports: vec![("lib".to_string(), 0)],
+            relocations: vec![2],
+            ..Object::default()
+        };
+        let program = link(&[main, lib]).unwrap();
+        assert_eq!(program.code, vec![6, 5, 13, 6, 3, 20, 6, 5]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let a = Object {
+            exports: vec![("dup".to_string(), 0)],
+            imports: vec![(0, "missing".to_string())],
+            code: vec![0],
+            ..Object::default()
+        };
+        let errors = link(&[a.clone(), a]).unwrap_err();
+        assert_eq!(errors.len(), 3);
+    }
+}
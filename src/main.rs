@@ -1,38 +1,556 @@
 mod assembly;
+mod block;
+mod cli;
+mod coverage;
+mod dap;
+mod debug_info;
+mod debugger;
+mod device;
+mod filesystem;
+mod framebuffer;
+mod gdb;
+mod i18n;
 mod instruction;
+mod interrupt;
 mod io;
+mod json;
+mod linker;
+mod object;
+mod preprocessor;
+mod process;
+mod profile;
+mod repl;
+mod replay;
+#[cfg(test)]
+mod testing;
+mod trace;
+mod tui;
 mod vm;
 
+use block::{ImageFile, BLOCK_SIZE};
+use cli::{Command, Options};
+use coverage::Coverage;
+use debugger::Debugger;
+use filesystem::{Disk, FileSystem, Kind, MAX_FILE_SIZE};
+use instruction::Instruction;
+use object::Object;
+use profile::Profile;
+use replay::Replay;
 use std::env;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use trace::{Filter, Format, Tracer};
 use vm::Mode;
+use vm::State;
 use vm::VirtualMachine;
 
 fn main() {
-    println!("Simple 仮想マシン");
-    println!("コンピュータの動作原理を深く学ぶ仮想マシン");
-    println!("(c) 2023 梶塚太智. All right reserved");
-    let args = env::args().collect::<Vec<_>>();
-
-    if args.len() > 1 {
-        match io::open_file(args[1].clone()) {
-            Ok(file) => {
-                let mode = if args.len() > 2 {
-                    if args[2].contains("e") {
-                        Mode::Execute
-                    } else {
-                        Mode::Debug
-                    }
-                } else {
-                    Mode::Debug
-                };
-                let mut vm = VirtualMachine::new(file, mode);
-                vm.run();
-            }
-            Err(e) => {
-                println!("エラー {e}")
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    // 引数の誤りも選んだ言語で表示できるように最初に言語を決める
+    i18n::set_language(i18n::detect(&args));
+    let options = match cli::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", msg!("main.error", e));
+            eprintln!("{}", msg!("main.help_hint"));
+            std::process::exit(2);
+        }
+    };
+    // 対話的に使う場合だけバナーを表示する
+    if options.is_interactive() && !options.quiet && std::io::stdin().is_terminal() {
+        println!("{}", msg!("main.banner.title"));
+        println!("{}", msg!("main.banner.description"));
+        println!("(c) 2023 梶塚太智. All right reserved");
+    }
+
+    let result = match options.command {
+        Command::Help => {
+            println!("{}", msg!("cli.help"));
+            Ok(())
+        }
+        Command::Version => {
+            println!("simple_vm {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Command::Run => run(&options),
+        Command::Debug => debug(&options),
+        Command::Asm => asm(&options),
+        Command::Disasm => disasm(&options),
+        Command::Check => check(&options),
+        Command::Trace => trace(&options),
+        Command::Link => link(&options),
+        Command::Repl => repl(&options),
+        Command::Disk => disk(&options),
+        // 標準出力はエディタとの通信に使う
+        Command::Dap => dap::serve().map_err(|e| e.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", msg!("main.error", e));
+        std::process::exit(1);
+    }
+}
+
+/// アセンブラのメッセージを表示しながら処理する (エラーの数も返す)
+fn assemble_with<T>(options: &Options, f: impl FnOnce() -> T) -> (T, usize) {
+    let (result, messages) = assembly::capture(f);
+    let mut errors = 0;
+    for message in messages {
+        if assembly::is_error(&message) {
+            errors += 1;
+        } else if options.quiet {
+            continue;
+        }
+        println!("{message}");
+    }
+    (result, errors)
+}
+
+/// ソースのファイルを読み込む
+fn read_source(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| msg!("common.read_failed", path, e))
+}
+
+/// プログラムをアセンブルして仮想マシンを用意する
+fn load(options: &Options, mode: Mode) -> Result<VirtualMachine, String> {
+    let path = options.file()?;
+    let code = read_source(path)?;
+    let (program, _) = assemble_with(options, || {
+        assembly::assembly(code, Path::new(path), &options.defines)
+    });
+    // メモリがプログラムより小さければ切り詰めずに誤りにする
+    if let Some(size) = options.mem_size.filter(|size| *size < program.code.len()) {
+        return Err(msg!("main.mem_size_too_small", size, program.code.len()));
+    }
+    // ストレージを指定しなければソースのファイルを使う
+    let storage_path = options.storage.as_deref().unwrap_or(path);
+    let storage = open_storage(Path::new(storage_path))?;
+    let mut vm = VirtualMachine::new(storage, program, mode);
+    if let Some(size) = options.mem_size {
+        vm.set_memory_size(size);
+    }
+    vm.set_max_steps(options.max_steps);
+    if let Some(quantum) = options.quantum {
+        vm.set_quantum(quantum);
+    }
+    if let Some(input) = &options.input_file {
+        let text = read_source(input)?;
+        vm.set_input(text.lines().map(String::from).collect());
+    }
+    if let Some(path) = &options.trace {
+        vm.set_trace(Tracer::create(path, Format::from_path(path))?);
+    }
+    for spec in &options.devices {
+        let (base, device) = device::create(spec)?;
+        vm.map_device(base, device)?;
+    }
+    if let Some(path) = &options.disk {
+        vm.set_disk(open_disk(path)?);
+    }
+    if let Some(path) = &options.record {
+        vm.set_replay(Replay::record(path)?);
+    }
+    if let Some(path) = &options.replay {
+        vm.set_replay(Replay::play(path)?);
+    }
+    Ok(vm)
+}
+
+/// 実行を終えてトレースと装置の内容を書き出す (エラーで停止した場合は終了コード1で終わる)
+fn finish(mut vm: VirtualMachine) -> Result<(), String> {
+    vm.flush_trace();
+    vm.finish_devices()?;
+    if let State::Fault(_) = vm.state() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// プログラムを最後まで実行する
+fn run(options: &Options) -> Result<(), String> {
+    let mut vm = load(options, Mode::Execute)?;
+    if !options.quiet {
+        println!("{}", msg!("common.running"));
+    }
+    // プロファイルやカバレッジを取る場合は回数を数えながら実行する
+    let outputs = [
+        &options.profile,
+        &options.folded,
+        &options.coverage,
+        &options.lcov,
+    ];
+    if outputs.iter().any(|path| path.is_some()) {
+        let result = Profile::run(&mut vm);
+        let debug = vm.debug_info();
+        let lines = Coverage::new(&result, debug, vm.memory());
+        let texts = [
+            result.report(debug),
+            result.folded(debug),
+            lines.listing(),
+            lines.lcov(),
+        ];
+        for (path, text) in outputs.into_iter().zip(texts) {
+            if let Some(path) = path {
+                fs::write(path, text).map_err(|e| msg!("common.write_failed", path, e))?;
             }
         }
     } else {
-        println!("アセンブリのファイルを指定してください")
+        vm.run();
+    }
+    finish(vm)
+}
+
+/// デバッグする (全画面のデバッガやGDBからも操作できる)
+fn debug(options: &Options) -> Result<(), String> {
+    if let Some(port) = options.gdb {
+        let mut vm = load(options, Mode::Execute)?;
+        gdb::serve(&mut vm, port)?;
+        return finish(vm);
+    }
+    if options.tui {
+        let mut vm = load(options, Mode::Embedded)?;
+        tui::run(&mut vm).map_err(|e| e.to_string())?;
+        return finish(vm);
+    }
+    let mut vm = load(options, Mode::Debug)?;
+    Debugger::new().run(&mut vm);
+    finish(vm)
+}
+
+/// 命令を1行ずつ実行する
+fn repl(options: &Options) -> Result<(), String> {
+    // ストレージを指定しなければ一時ディレクトリのファイルを使う
+    let path = match &options.storage {
+        Some(path) => path.into(),
+        None => env::temp_dir().join("simple_vm_repl_storage.txt"),
+    };
+    let storage = open_storage(&path)?;
+    let repl = repl::Repl::new(storage, options.defines.clone(), options.mem_size)?;
+    repl::run(repl);
+    Ok(())
+}
+
+/// ストレージのファイルを開く (なければ空のファイルを作る)
+fn open_storage(path: &Path) -> Result<fs::File, String> {
+    (fs::OpenOptions::new().read(true).write(true).create(true))
+        .truncate(false)
+        .open(path)
+        .map_err(|e| msg!("common.open_failed", path.display(), e))
+}
+
+/// 結果を出力先のファイルか標準出力に書く
+fn write_output(options: &Options, text: &str) -> Result<(), String> {
+    match &options.output {
+        Some(path) => fs::write(path, text).map_err(|e| msg!("common.write_failed", path, e)),
+        None => {
+            print!("{text}");
+            Ok(())
+        }
+    }
+}
+
+/// プログラムを数値の並びにする
+fn code_text(program: &Object) -> String {
+    let code: Vec<String> = program.code.iter().map(|value| value.to_string()).collect();
+    code.join("\n") + "\n"
+}
+
+/// アセンブルしてオブジェクトファイル・プログラム・展開したソースのどれかを出力する
+fn asm(options: &Options) -> Result<(), String> {
+    let path = options.file()?;
+    let code = read_source(path)?;
+    let (text, errors) = match options.format.as_deref().unwrap_or("object") {
+        "object" => assemble_with(options, || {
+            assembly::assemble_object(&code, Path::new(path), &options.defines).to_text()
+        }),
+        "code" => assemble_with(options, || {
+            code_text(&assembly::assembly(
+                code.clone(),
+                Path::new(path),
+                &options.defines,
+            ))
+        }),
+        // マクロを展開したソース
+        "expand" => assemble_with(options, || {
+            (preprocessor::preprocess(&code, Path::new(path), &options.defines).iter())
+                .map(|line| line.text.clone() + "\n")
+                .collect()
+        }),
+        format => {
+            return Err(msg!(
+                "main.unknown_format",
+                "asm",
+                format,
+                "object / code / expand"
+            ))
+        }
+    };
+    if errors > 0 {
+        return Err(msg!("main.error_count", errors));
+    }
+    write_output(options, &text)
+}
+
+/// ソース・オブジェクトファイル・プログラムのどれかを逆アセンブルする
+fn disasm(options: &Options) -> Result<(), String> {
+    let path = options.file()?;
+    let text = read_source(path)?;
+    let program = if text.starts_with(object::MAGIC) {
+        linker::link(&[Object::parse(&text)?]).map_err(|errors| errors.join("\n"))?
+    } else if let Ok(code) = (text.lines())
+        .map(|line| line.trim().parse())
+        .collect::<Result<Vec<i32>, _>>()
+    {
+        Object {
+            code,
+            ..Object::default()
+        }
+    } else {
+        let (program, errors) = assemble_with(options, || {
+            assembly::assembly(text.clone(), Path::new(path), &options.defines)
+        });
+        if errors > 0 {
+            return Err(msg!("main.error_count", errors));
+        }
+        program
+    };
+    let listing = match options.format.as_deref().unwrap_or("text") {
+        "text" => disassemble(&program, false),
+        "asm" => disassemble(&program, true),
+        format => return Err(msg!("main.unknown_format", "disasm", format, "text / asm")),
+    };
+    write_output(options, &listing)
+}
+
+/// プログラムを逆アセンブルする (asmならアセンブルし直せるソースにする)
+fn disassemble(program: &Object, asm: bool) -> String {
+    let mut text = String::new();
+    let mut address = 0;
+    while address < program.code.len() {
+        for (label, _) in (program.debug.labels.iter()).filter(|(_, a)| *a == address) {
+            text += &format!("{label}:\n");
+        }
+        let (line, size) = match Instruction::decode(&program.code, address) {
+            // アセンブラは1行に1つの値を置くのでオペランドは次の行に書く
+            Some((Instruction::Push(value), size)) if asm => (format!("push\n{value}"), size),
+            Some((Instruction::Nop, size)) if asm => (".word 0".to_string(), size),
+            Some((instruction, size)) => (instruction.to_string(), size),
+            None => (format!(".word {}", program.code[address]), 1),
+        };
+        if asm {
+            text += &format!("{line}\n");
+        } else {
+            let source = (program.debug.lines.get(&address))
+                .map(|location| format!("  ; {}:{}", location.file, location.line))
+                .unwrap_or_default();
+            text += &format!("{address:0>3} :  {line}{source}\n");
+        }
+        address += size;
+    }
+    text
+}
+
+/// アセンブルしてエラーだけを表示する
+fn check(options: &Options) -> Result<(), String> {
+    let path = options.file()?;
+    let code = read_source(path)?;
+    let (_, errors) = assemble_with(options, || {
+        assembly::assembly(code, Path::new(path), &options.defines)
+    });
+    if errors > 0 {
+        return Err(msg!("main.error_count", errors));
+    }
+    if !options.quiet {
+        println!("{}", msg!("main.no_errors", path));
+    }
+    Ok(())
+}
+
+/// トレースを記録しながら実行する (showとdiffはトレースを表示・比較する)
+fn trace(options: &Options) -> Result<(), String> {
+    match options.args.first().map(String::as_str) {
+        Some("show") => return show_trace(&options.args[1], &options.args[2..]),
+        Some("diff") => return diff_traces(&options.args[1], &options.args[2]),
+        _ => {}
+    }
+    let path = options.file()?;
+    let format = match options.format.as_deref() {
+        Some("json") => Format::Json,
+        Some("binary") => Format::Binary,
+        Some(format) => {
+            return Err(msg!(
+                "main.unknown_format",
+                "trace",
+                format,
+                "json / binary"
+            ));
+        }
+        None => (options.output.as_deref()).map_or(Format::Json, Format::from_path),
+    };
+    let output = options.output.clone().unwrap_or_else(|| {
+        let stem = Path::new(path).with_extension("");
+        match format {
+            Format::Json => format!("{}.jsonl", stem.display()),
+            Format::Binary => format!("{}.trace", stem.display()),
+        }
+    });
+    let mut vm = load(options, Mode::Execute)?;
+    vm.set_trace(Tracer::create(&output, format)?);
+    if !options.quiet {
+        println!("{}", msg!("common.running"));
+    }
+    vm.run();
+    if !options.quiet {
+        println!("{}", msg!("main.trace_recorded", output));
+    }
+    finish(vm)
+}
+
+/// トレースを条件で絞り込んで表示する
+fn show_trace(path: &str, args: &[String]) -> Result<(), String> {
+    let filter = Filter::parse(args)?;
+    for step in trace::load(path)?
+        .iter()
+        .filter(|step| filter.matches(step))
+    {
+        println!("{}", step.pretty());
+    }
+    Ok(())
+}
+
+/// 2つのトレースを比べて最初に違う命令を表示する
+fn diff_traces(a: &str, b: &str) -> Result<(), String> {
+    let (steps_a, steps_b) = (trace::load(a)?, trace::load(b)?);
+    let Some(index) = trace::diff(&steps_a, &steps_b) else {
+        println!("{}", msg!("main.trace_same", steps_a.len()));
+        return Ok(());
+    };
+    println!("{}", msg!("main.trace_differs", index));
+    for (path, steps) in [(a, &steps_a), (b, &steps_b)] {
+        match steps.get(index) {
+            Some(step) => println!("{path}: {}", step.pretty()),
+            None => println!("{path}: {}", msg!("main.trace_end")),
+        }
+    }
+    std::process::exit(1);
+}
+
+/// オブジェクトファイルをリンクして実行できるプログラムを書き出す
+fn link(options: &Options) -> Result<(), String> {
+    // -oがなければ最初の引数を出力先にする
+    let (output, inputs) = match &options.output {
+        Some(output) => (output.as_str(), &options.args[..]),
+        None => match options.args.split_first() {
+            Some((output, inputs)) => (output.as_str(), inputs),
+            None => return Err(msg!("main.link_missing_output")),
+        },
+    };
+    if inputs.is_empty() {
+        return Err(msg!("main.link_missing_objects"));
+    }
+    let mut objects = Vec::new();
+    for input in inputs {
+        let text = read_source(input)?;
+        objects.push(Object::parse(&text).map_err(|e| format!("{input}: {e}"))?);
+    }
+    let program = linker::link(&objects).map_err(|errors| errors.join("\n"))?;
+    fs::write(output, code_text(&program)).map_err(|e| msg!("common.write_failed", output, e))
+}
+
+/// ディスクイメージを開く
+fn open_disk(path: &str) -> Result<Disk, String> {
+    let fs = FileSystem::mount(Box::new(ImageFile::open(path)?));
+    Ok(Disk::new(fs.map_err(|e| format!("{path}: {e}"))?))
+}
+
+/// ディスクイメージを作り、中身を表示し、ファイルを出し入れする
+fn disk(options: &Options) -> Result<(), String> {
+    let (command, image) = (options.args[0].as_str(), options.args[1].as_str());
+    let rest = &options.args[2..];
+    let argument = |index: usize| {
+        (rest.get(index).map(String::as_str)).ok_or(msg!("disk.missing_argument", command))
+    };
+    match command {
+        "create" => {
+            let blocks = match rest.first() {
+                Some(blocks) => {
+                    (blocks.parse()).map_err(|_| msg!("cli.not_a_number", command, blocks))?
+                }
+                None => 1024,
+            };
+            // 平均で8ブロックに1つのファイルを置けるようにする
+            let device = ImageFile::create(image, blocks)?;
+            FileSystem::format(Box::new(device), (blocks / 8).clamp(16, 4096))?;
+            if !options.quiet {
+                println!("{}", msg!("disk.created", image, blocks));
+            }
+            return Ok(());
+        }
+        "info" | "ls" | "put" | "get" | "mkdir" | "rm" => {}
+        _ => return Err(msg!("disk.unknown_command", command)),
+    }
+    let mut disk = open_disk(image)?;
+    match command {
+        "info" => {
+            let usage = disk.fs().usage()?;
+            let bytes = usage.used_blocks as usize * BLOCK_SIZE;
+            println!(
+                "{}",
+                msg!(
+                    "disk.usage",
+                    usage.used_blocks,
+                    usage.blocks,
+                    bytes,
+                    usage.used_inodes,
+                    usage.inodes
+                )
+            );
+        }
+        "ls" => list_disk(&mut disk, argument(0).unwrap_or("/"))?,
+        "put" => {
+            let source = argument(0)?;
+            let data = fs::read(source).map_err(|e| msg!("common.read_failed", source, e))?;
+            // パスを省略するとルートに同じ名前で入れる
+            let path = match argument(1) {
+                Ok(path) => path.to_string(),
+                Err(_) => {
+                    let name = Path::new(source).file_name().unwrap_or_default();
+                    format!("/{}", name.to_string_lossy())
+                }
+            };
+            let fd = disk.open(&path, 1)?;
+            disk.write(fd, &data)?;
+            disk.close(fd)?;
+        }
+        "get" => {
+            let fd = disk.open(argument(0)?, 0)?;
+            let data = disk.read(fd, MAX_FILE_SIZE)?;
+            match argument(1) {
+                Ok(output) => {
+                    fs::write(output, data).map_err(|e| msg!("common.write_failed", output, e))?
+                }
+                Err(_) => std::io::stdout()
+                    .write_all(&data)
+                    .map_err(|e| e.to_string())?,
+            }
+        }
+        "mkdir" => disk.mkdir(argument(0)?)?,
+        _ => disk.delete(argument(0)?)?,
+    }
+    Ok(())
+}
+
+/// ディレクトリの中を大きさとパスで再帰的に表示する
+fn list_disk(disk: &mut Disk, path: &str) -> Result<(), String> {
+    for entry in disk.fs().list(path)? {
+        let child = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+        match entry.kind {
+            Kind::File => println!("{:>8}  {child}", entry.size),
+            Kind::Directory => {
+                println!("{:>8}  {child}/", "-");
+                list_disk(disk, &child)?;
+            }
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,111 @@
+use crate::debug_info::{DebugInfo, Location};
+use crate::msg;
+
+/// オブジェクトファイルの先頭に書くマジックナンバー
+pub const MAGIC: &str = "SIMPLE-OBJECT 1";
+
+/// 再配置可能なオブジェクトモジュール
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub code: Vec<i32>,                // モジュール内のコード
+    pub exports: Vec<(String, usize)>, // 公開するシンボルとその位置
+    pub imports: Vec<(usize, String)>, // 外部シンボルを参照する位置とシンボル名
+    pub relocations: Vec<usize>,       // モジュール内のアドレスを参照する位置
+    pub debug: DebugInfo,              // ソースとの対応
+}
+
+impl Object {
+    /// オブジェクトファイルの形式に変換する
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{MAGIC}\n");
+        let code: Vec<String> = self.code.iter().map(|value| value.to_string()).collect();
+        text += &format!("code {}\n", code.join(" "));
+        for (name, offset) in &self.exports {
+            text += &format!("export {name} {offset}\n");
+        }
+        for (offset, name) in &self.imports {
+            text += &format!("import {offset} {name}\n");
+        }
+        for offset in &self.relocations {
+            text += &format!("reloc {offset}\n");
+        }
+        for (name, offset) in &self.debug.labels {
+            text += &format!("label {name} {offset}\n");
+        }
+        // ファイル名や行の内容は空白を含むのでタブで区切る
+        for (offset, location) in &self.debug.lines {
+            text += &format!(
+                "line\t{offset}\t{}\t{}\t{}\t{}\n",
+                location.line, location.column, location.file, location.text
+            );
+        }
+        text
+    }
+
+    /// オブジェクトファイルを読み込む
+    pub fn parse(text: &str) -> Result<Object, String> {
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some(MAGIC) {
+            return Err(msg!("object.invalid"));
+        }
+
+        let mut object = Object::default();
+        for (i, line) in lines.enumerate() {
+            let number = |field: &str| -> Result<usize, String> {
+                field
+                    .parse()
+                    .map_err(|_| msg!("object.invalid_position", i + 2, field))
+            };
+            if let Some(record) = line.strip_prefix("line\t") {
+                let fields: Vec<&str> = record.splitn(5, '\t').collect();
+                let [offset, row, column, file, text] = fields[..] else {
+                    return Err(msg!("object.bad_record", i + 2, line));
+                };
+                let location = Location {
+                    file: file.to_string(),
+                    line: number(row)?,
+                    column: number(column)?,
+                    text: text.to_string(),
+                };
+                object.debug.lines.insert(number(offset)?, location);
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                ["code", values @ ..] => {
+                    for value in values {
+                        object.code.push(
+                            value
+                                .parse()
+                                .map_err(|_| msg!("object.not_a_number", i + 2, value))?,
+                        );
+                    }
+                }
+                ["export", name, offset] => {
+                    object.exports.push((name.to_string(), number(offset)?))
+                }
+                ["import", offset, name] => {
+                    object.imports.push((number(offset)?, name.to_string()))
+                }
+                ["reloc", offset] => object.relocations.push(number(offset)?),
+                ["label", name, offset] => object
+                    .debug
+                    .labels
+                    .push((name.to_string(), number(offset)?)),
+                _ => return Err(msg!("object.bad_record", i + 2, line)),
+            }
+        }
+
+        // ラベルはコードの末尾を指すこともある
+        let size = object.code.len();
+        let out_of_range = object.exports.iter().any(|(_, offset)| *offset > size)
+            || object.imports.iter().any(|(offset, _)| *offset >= size)
+            || object.relocations.iter().any(|offset| *offset >= size);
+        if out_of_range {
+            return Err(msg!("object.out_of_range"));
+        }
+        Ok(object)
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assembly::{parse_string, report, strip_comment};
+use crate::msg;
+
+/// マクロ展開とファイル読み込みの深さの上限
+const MAX_DEPTH: usize = 64;
+
+/// プリプロセス後のソースの1行
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub file: String,  // 元のソースのファイル名
+    pub number: usize, // 元のソースでの行番号
+    pub text: String,  // 行の内容
+}
+
+/// マクロ定義
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>, // 引数名
+    body: Vec<Line>,     // 本体
+}
+
+/// 条件付きアセンブルの状態
+struct Condition {
+    line: Line,   // ifの行
+    active: bool, // 今の部分をアセンブルするか
+    taken: bool,  // どれかの部分をアセンブルしたか
+    other: bool,  // elseの後か
+}
+
+/// プリプロセッサ
+pub struct Preprocessor {
+    defines: HashMap<String, String>, // 定義されたシンボル
+    macros: HashMap<String, Macro>,   // 定義されたマクロ
+    expansions: usize,                // マクロを展開した回数
+    files: Vec<PathBuf>,              // 読み込み中のファイル
+    output: Vec<Line>,                // 展開後のソース
+}
+
+/// ソースのマクロ・ファイルの読み込み・条件付きアセンブルを展開する
+pub fn preprocess(asm: &str, path: &Path, defines: &HashMap<String, String>) -> Vec<Line> {
+    let mut preprocessor = Preprocessor {
+        defines: defines.clone(),
+        macros: HashMap::new(),
+        expansions: 0,
+        files: vec![path.to_path_buf()],
+        output: Vec::new(),
+    };
+    preprocessor.process(split_lines(asm, path), 0);
+    preprocessor.output
+}
+
+/// ソースを行に分ける
+fn split_lines(asm: &str, path: &Path) -> Vec<Line> {
+    asm.split("\n")
+        .enumerate()
+        .map(|(i, text)| Line {
+            file: path.display().to_string(),
+            number: i + 1,
+            text: text.trim_end_matches('\r').to_string(),
+        })
+        .collect()
+}
+
+impl Preprocessor {
+    fn process(&mut self, lines: Vec<Line>, depth: usize) {
+        let mut conditions: Vec<Condition> = Vec::new();
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            let code = strip_comment(&line.text).trim().to_string();
+            let (name, operand) = split_first(&code);
+            let active = conditions.iter().all(|condition| condition.active);
+
+            if matches!(name, "if" | "ifdef" | "ifndef") {
+                // 外側がアセンブルされないときは条件を評価しない
+                let value = active
+                    && match name {
+                        "ifdef" => self.defines.contains_key(operand),
+                        "ifndef" => !self.defines.contains_key(operand),
+                        _ => self.evaluate(operand).unwrap_or_else(|e| {
+                            error(&line, &e);
+                            false
+                        }),
+                    };
+                conditions.push(Condition {
+                    line: line.clone(),
+                    active: value,
+                    taken: value || !active,
+                    other: false,
+                });
+            } else if name == "else" {
+                match conditions.last_mut() {
+                    Some(condition) if !condition.other => {
+                        condition.active = !condition.taken;
+                        condition.taken = true;
+                        condition.other = true;
+                    }
+                    Some(_) => error(&line, &msg!("preprocessor.duplicate_else")),
+                    None => error(&line, &msg!("preprocessor.missing_if")),
+                }
+            } else if name == "endif" {
+                if conditions.pop().is_none() {
+                    error(&line, &msg!("preprocessor.missing_if"));
+                }
+            } else if !active {
+                continue;
+            } else if name == "define" {
+                // シンボルを定義する (値を省略すると1になる)
+                let (symbol, value) = split_first(operand);
+                let value = if value.is_empty() { "1" } else { value };
+                self.defines.insert(symbol.to_string(), value.to_string());
+            } else if name == "macro" {
+                // endmまでをマクロの本体として登録する
+                let mut header = operand.split_whitespace();
+                let Some(macro_name) = header.next() else {
+                    error(&line, &msg!("preprocessor.missing_macro_name"));
+                    continue;
+                };
+                let params = split_args(&header.collect::<Vec<_>>().join(" "));
+                let mut body = Vec::new();
+                let mut closed = false;
+                for inner in lines.by_ref() {
+                    match split_first(strip_comment(&inner.text).trim()).0 {
+                        "endm" => {
+                            closed = true;
+                            break;
+                        }
+                        "macro" => error(&inner, &msg!("preprocessor.nested_macro")),
+                        _ => body.push(inner),
+                    }
+                }
+                if !closed {
+                    error(&line, &msg!("preprocessor.missing_endm", macro_name));
+                }
+                self.macros
+                    .insert(macro_name.to_string(), Macro { params, body });
+            } else if name == "include" {
+                if let Err(e) = self.include(&line, operand, depth) {
+                    error(&line, &e);
+                }
+            } else if name == "endm" {
+                error(&line, &msg!("preprocessor.missing_macro"));
+            } else if let Some(definition) = self.macros.get(name).cloned() {
+                if depth >= MAX_DEPTH {
+                    error(&line, &msg!("preprocessor.too_deep", name));
+                    continue;
+                }
+                let args = split_args(operand);
+                if args.len() != definition.params.len() {
+                    error(
+                        &line,
+                        &msg!(
+                            "preprocessor.argument_count",
+                            name,
+                            definition.params.len(),
+                            args.len()
+                        ),
+                    );
+                    continue;
+                }
+                let expanded = self.expand(&definition, args, &line);
+                self.process(expanded, depth + 1);
+            } else if self.defines.is_empty() {
+                self.output.push(line);
+            } else {
+                // 定義されたシンボルを値に置き換える
+                let comment = &line.text[strip_comment(&line.text).len()..];
+                let text = substitute(&code, &self.defines) + comment;
+                self.output.push(Line { text, ..line });
+            }
+        }
+
+        for condition in conditions {
+            error(&condition.line, &msg!("preprocessor.missing_endif"));
+        }
+    }
+
+    /// 条件式を評価する
+    fn evaluate(&self, expr: &str) -> Result<bool, String> {
+        for op in ["==", "!=", "<=", ">=", "<", ">"] {
+            if let Some((a, b)) = expr.split_once(op) {
+                let (a, b) = (self.value(a)?, self.value(b)?);
+                return Ok(match op {
+                    "==" => a == b,
+                    "!=" => a != b,
+                    "<=" => a <= b,
+                    ">=" => a >= b,
+                    "<" => a < b,
+                    _ => a > b,
+                });
+            }
+        }
+        Ok(self.value(expr)? != 0)
+    }
+
+    /// 条件式の項の値を求める
+    fn value(&self, term: &str) -> Result<i32, String> {
+        let term = term.trim();
+        let value = self.defines.get(term).map(String::as_str).unwrap_or(term);
+        value
+            .parse()
+            .map_err(|_| msg!("preprocessor.undefined_symbol", term))
+    }
+
+    /// 指定したファイルを読み込んでその位置に展開する
+    fn include(&mut self, line: &Line, operand: &str, depth: usize) -> Result<(), String> {
+        let name = parse_string(operand)?;
+        let path = Path::new(&line.file)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(name);
+        if depth >= MAX_DEPTH || self.files.contains(&path) {
+            return Err(msg!("preprocessor.recursive_include", path.display()));
+        }
+        let asm =
+            fs::read_to_string(&path).map_err(|e| msg!("common.read_failed", path.display(), e))?;
+
+        self.files.push(path.clone());
+        self.process(split_lines(&asm, &path), depth + 1);
+        self.files.pop();
+        Ok(())
+    }
+
+    /// 引数とローカルラベルを置き換えてマクロの本体を展開する
+    fn expand(&mut self, definition: &Macro, args: Vec<String>, at: &Line) -> Vec<Line> {
+        self.expansions += 1;
+        let mut names: HashMap<String, String> =
+            definition.params.iter().cloned().zip(args).collect();
+
+        // 本体で定義されたラベルは展開ごとに別の名前にする
+        for line in &definition.body {
+            let (label, _) = split_first(strip_comment(&line.text).trim());
+            if let Some(label) = label.strip_suffix(':') {
+                names.insert(label.to_string(), format!("{label}@{}", self.expansions));
+            }
+        }
+
+        definition
+            .body
+            .iter()
+            .map(|line| Line {
+                file: at.file.clone(),
+                number: at.number,
+                text: substitute(strip_comment(&line.text).trim(), &names),
+            })
+            .collect()
+    }
+}
+
+/// エラーを表示する
+fn error(line: &Line, message: &str) {
+    report(msg!("assembly.error", line.file, line.number, message));
+}
+
+/// 最初の単語と残りに分ける
+fn split_first(code: &str) -> (&str, &str) {
+    match code.find(char::is_whitespace) {
+        Some(i) => (&code[..i], code[i..].trim()),
+        None => (code, ""),
+    }
+}
+
+/// カンマ区切りの引数を分ける
+fn split_args(operand: &str) -> Vec<String> {
+    if operand.trim().is_empty() {
+        return Vec::new();
+    }
+    // 文字列リテラルと文字リテラルの中のカンマでは区切らない
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for c in operand.chars() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                arg.push(c);
+            }
+            None if c == ',' => args.push(std::mem::take(&mut arg).trim().to_string()),
+            None => {
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+                arg.push(c);
+            }
+        }
+    }
+    args.push(arg.trim().to_string());
+    args
+}
+
+/// 文字列リテラルの外にある識別子を置き換える
+fn substitute(code: &str, names: &HashMap<String, String>) -> String {
+    let mut result = String::new();
+    let mut token = String::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in code.chars() {
+        if in_string {
+            result.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c.is_alphanumeric() || c == '_' || c == '@' {
+            token.push(c);
+        } else {
+            result.push_str(names.get(&token).unwrap_or(&token));
+            token.clear();
+            in_string = c == '"';
+            result.push(c);
+        }
+    }
+    result.push_str(names.get(&token).unwrap_or(&token));
+    result
+}
+
+#[cfg(test)]
+mod test_preprocessor {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::preprocessor::preprocess;
+
+    fn expand(asm: &str) -> Vec<String> {
+        preprocess(asm, Path::new("test.asm"), &HashMap::new())
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_macro_arguments() {
+        let asm = "macro put c\npush\nc\noutput\nendm\nput 65\nput \"c\"";
+        assert_eq!(
+            expand(asm),
+            vec!["push", "65", "output", "push", "\"c\"", "output"]
+        );
+    }
+
+    #[test]
+    fn test_quoted_commas_in_arguments() {
+        let asm =
+            "macro pair a, b\npush\na\npush\nb\nendm\npair \"a,b\", 'c'\npair \"x\\\",\", ','";
+        assert_eq!(
+            expand(asm),
+            vec![
+                "push",
+                "\"a,b\"",
+                "push",
+                "'c'",
+                "push",
+                "\"x\\\",\"",
+                "push",
+                "','"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_macro_and_local_labels() {
+        let asm = "macro inner\nloop:\npush\nloop\nendm\nmacro outer\ninner\ninner\nendm\nouter";
+        assert_eq!(
+            expand(asm),
+            vec!["loop@2:", "push", "loop@2", "loop@3:", "push", "loop@3"]
+        );
+    }
+
+    #[test]
+    fn test_conditional_assembly() {
+        let asm = "define OS 2\nifdef OS\nif OS == 1\nwinapi\nelse\npush\nOS\nendif\nendif\nifndef OS\nhalt\nendif";
+        assert_eq!(expand(asm), vec!["push", "2"]);
+    }
+}
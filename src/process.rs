@@ -0,0 +1,287 @@
+use crate::msg;
+
+/// 最初のプロセスのID
+pub const MAIN: usize = 1;
+
+/// プロセスの状態
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ready,          // 実行できる
+    Waiting(usize), // 指定したプロセスが終わるのを待っている
+    Exited(i32),    // 終了した (終了コード)
+}
+
+/// プロセス (実行していない間はレジスタとスタックをここに保存する)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Process {
+    pub id: usize,             // プロセスID
+    pub parent: Option<usize>, // 作ったプロセスのID
+    pub base: usize,           // メモリの領域の先頭の物理番地
+    pub size: usize,           // メモリの領域の大きさ
+    pub pc: usize,             // 保存したプログラムカウンタ
+    pub stack: Vec<i32>,       // 保存したスタック
+    pub status: Status,        // 状態
+    pub steps: u64,            // 実行した命令の数
+}
+
+/// ラウンドロビンでプロセスを切り替えるスケジューラ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheduler {
+    processes: Vec<Process>, // プロセスの一覧 (IDの順)
+    current: usize,          // 実行中のプロセスのID
+    quantum: u64,            // 続けて実行できる命令の数 (0なら自分から譲るまで切り替えない)
+    used: u64,               // 実行中のプロセスが続けて実行した命令の数
+    next_id: usize,          // 次に作るプロセスのID
+    free: Vec<usize>,        // 終了して使わなくなったメモリの領域の先頭
+    finished: Vec<usize>,    // 終了を待たれたので切り替えた後に取り除くプロセス
+}
+
+impl Scheduler {
+    /// メモリ全体を領域にした最初のプロセスだけのスケジューラを作る
+    pub fn new(size: usize, quantum: u64) -> Scheduler {
+        let main = Process {
+            id: MAIN,
+            parent: None,
+            base: 0,
+            size,
+            pc: 0,
+            stack: Vec::new(),
+            status: Status::Ready,
+            steps: 0,
+        };
+        Scheduler {
+            processes: vec![main],
+            current: MAIN,
+            quantum,
+            used: 0,
+            next_id: MAIN + 1,
+            free: Vec::new(),
+            finished: Vec::new(),
+        }
+    }
+
+    /// プロセスの一覧
+    pub fn processes(&self) -> &[Process] {
+        &self.processes
+    }
+
+    /// 実行中のプロセス
+    pub fn current(&self) -> &Process {
+        self.get(self.current).expect("current process")
+    }
+
+    fn current_mut(&mut self) -> &mut Process {
+        let id = self.current;
+        (self.processes.iter_mut())
+            .find(|process| process.id == id)
+            .expect("current process")
+    }
+
+    fn get(&self, id: usize) -> Option<&Process> {
+        self.processes.iter().find(|process| process.id == id)
+    }
+
+    /// 終了したプロセスが使っていた領域 (なければNone)
+    pub fn free_region(&mut self) -> Option<usize> {
+        self.free.pop()
+    }
+
+    /// プロセスを作ってIDを返す (スタックとプログラムカウンタを与える)
+    pub fn spawn(&mut self, base: usize, size: usize, pc: usize, stack: Vec<i32>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.processes.push(Process {
+            id,
+            parent: Some(self.current),
+            base,
+            size,
+            pc,
+            stack,
+            status: Status::Ready,
+            steps: 0,
+        });
+        id
+    }
+
+    /// 実行中のプロセスが量子を使い切ったか判断する
+    pub fn expired(&self) -> bool {
+        self.quantum > 0 && self.used >= self.quantum
+    }
+
+    /// 実行中のプロセスが命令を1つ実行したことを数える
+    pub fn tick(&mut self) {
+        self.used += 1;
+        self.current_mut().steps += 1;
+    }
+
+    /// 実行中のプロセスを終了し、待っていたプロセスを再開できるようにする
+    pub fn exit(&mut self, code: i32) {
+        let id = self.current;
+        self.current_mut().status = Status::Exited(code);
+        let mut waited = false;
+        for process in &mut self.processes {
+            if process.status == Status::Waiting(id) {
+                process.stack.push(code);
+                process.status = Status::Ready;
+                waited = true;
+            }
+        }
+        // 待っていたプロセスがあれば、切り替えた後に取り除く
+        if waited {
+            self.finished.push(id);
+        }
+    }
+
+    /// プロセスの終了を待つ (終了していれば終了コードを返して取り除き、そうでなければ待つ状態にする)
+    pub fn wait(&mut self, id: usize) -> Result<Option<i32>, String> {
+        let target = (self.get(id))
+            .filter(|_| id != self.current)
+            .ok_or(msg!("process.not_found", id))?;
+        if let Status::Exited(code) = target.status {
+            self.remove(id);
+            return Ok(Some(code));
+        }
+        self.current_mut().status = Status::Waiting(id);
+        Ok(None)
+    }
+
+    /// 次に実行できるプロセスに切り替える (レジスタとスタックを入れ替える)
+    /// 全てのプロセスが終了していればNone、どれも実行できなければエラー
+    pub fn switch(
+        &mut self,
+        pc: &mut usize,
+        stack: &mut Vec<i32>,
+    ) -> Result<Option<usize>, String> {
+        let position = (self.processes.iter())
+            .position(|process| process.id == self.current)
+            .unwrap_or(0);
+        // 今のプロセスの次から順番に探す
+        let count = self.processes.len();
+        let next = (1..=count)
+            .map(|offset| &self.processes[(position + offset) % count])
+            .find(|process| process.status == Status::Ready)
+            .map(|process| process.id);
+        let Some(next) = next else {
+            if (self.processes.iter()).all(|process| matches!(process.status, Status::Exited(_))) {
+                return Ok(None);
+            }
+            return Err(msg!("process.deadlock"));
+        };
+        let current = self.current_mut();
+        current.pc = *pc;
+        current.stack = std::mem::take(stack);
+        self.used = 0;
+        // 終了を待たれていたプロセスを取り除く
+        for id in std::mem::take(&mut self.finished) {
+            self.remove(id);
+        }
+        self.current = next;
+        let process = self.current_mut();
+        *pc = process.pc;
+        *stack = std::mem::take(&mut process.stack);
+        Ok(Some(next))
+    }
+
+    /// 終了したプロセスを取り除いて領域を再利用できるようにする
+    fn remove(&mut self, id: usize) {
+        if let Some(index) = self.processes.iter().position(|process| process.id == id) {
+            let process = self.processes.remove(index);
+            if process.base != 0 {
+                self.free.push(process.base);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_process {
+    use crate::process::{Scheduler, Status, MAIN};
+    use crate::testing;
+    use crate::vm::{State, VirtualMachine};
+
+    /// 子プロセスを2つ作って文字を3回ずつ出力させ、2つの終了コードを足して出力する
+    const CODE: &str = "push\nchild\npush\n97\npush\n10\nsyscall\npush\npid1\nstore\n\
+                        push\nchild\npush\n98\npush\n10\nsyscall\npush\npid2\nstore\n\
+                        push\npid1\nload\npush\n12\nsyscall\n\
+                        push\npid2\nload\npush\n12\nsyscall\nadd\noutputnum\nhalt\n\
+                        child: push\nch\nstore\n\
+                        loop: push\nch\nload\noutput\npush\n13\nsyscall\npop\n\
+                        push\ntimes\nload\npush\n1\nadd\npush\ntimes\nstore\n\
+                        push\nloop\npush\ntimes\nload\npush\n3\nequal\njump\n\
+                        push\ntimes\nload\npush\n11\nsyscall\n\
+                        ch: .word 0\ntimes: .word 0\npid1: .word 0\npid2: .word 0\n";
+
+    fn machine(quantum: u64) -> VirtualMachine {
+        let mut vm = testing::machine("process.asm", CODE);
+        vm.set_quantum(quantum);
+        vm
+    }
+
+    #[test]
+    fn test_spawn_yield_and_wait() {
+        // yieldで交互に出力し、それぞれのメモリの領域で3まで数えて終了する
+        let mut vm = machine(0);
+        vm.run();
+        assert_eq!(vm.state(), &State::Halted);
+        assert_eq!(vm.output(), "ababab6");
+        assert_eq!(vm.scheduler().map(|s| s.processes().len()), Some(1));
+        // 親の領域は子プロセスに書き換えられない
+        let ch = vm.debug_info().address("ch").unwrap();
+        assert_eq!(vm.memory()[ch], 0);
+    }
+
+    #[test]
+    fn test_quantum_and_step_back() {
+        let mut vm = machine(3);
+        vm.set_history_size(1000);
+        vm.run();
+        assert_eq!(vm.state(), &State::Halted);
+        assert!(vm.output().ends_with('6'));
+        // 全ての命令を取り消すと最初の状態に戻る
+        let before = testing::machine("process.asm", CODE).memory().to_vec();
+        while vm.step_back().is_ok() {}
+        assert_eq!(vm.memory(), before.as_slice());
+        assert_eq!(vm.pc(), 0);
+        assert!(vm.stack().is_empty());
+        assert!(vm.scheduler().is_none());
+    }
+
+    #[test]
+    fn test_step_back_over_spawn() {
+        let mut vm = machine(0);
+        vm.set_history_size(100);
+        let before = vm.memory().to_vec();
+        while vm.scheduler().is_none() {
+            vm.step();
+        }
+        // 子プロセスのために広げたメモリと写した領域も取り消す
+        vm.step_back().unwrap();
+        assert!(vm.scheduler().is_none());
+        assert_eq!(vm.memory().len(), before.len());
+        assert_eq!(vm.memory(), before.as_slice());
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mut scheduler = Scheduler::new(10, 2);
+        let child = scheduler.spawn(10, 10, 5, vec![1]);
+        let (mut pc, mut stack) = (3, vec![7]);
+        scheduler.tick();
+        assert!(!scheduler.expired());
+        scheduler.tick();
+        assert!(scheduler.expired());
+        assert_eq!(scheduler.switch(&mut pc, &mut stack), Ok(Some(child)));
+        assert_eq!((pc, stack.clone()), (5, vec![1]));
+        assert!(scheduler.wait(child).is_err());
+        scheduler.exit(4);
+        assert_eq!(scheduler.switch(&mut pc, &mut stack), Ok(Some(MAIN)));
+        assert_eq!((pc, stack.clone()), (3, vec![7]));
+        // 終了したプロセスは待つと取り除かれる
+        assert_eq!(scheduler.wait(child), Ok(Some(4)));
+        assert_eq!(scheduler.processes().len(), 1);
+        assert_eq!(scheduler.free_region(), Some(10));
+        assert_eq!(scheduler.current().status, Status::Ready);
+        scheduler.exit(0);
+        assert_eq!(scheduler.switch(&mut pc, &mut stack), Ok(None));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::debug_info::DebugInfo;
+use crate::instruction::Instruction;
+use crate::msg;
+use crate::vm::VirtualMachine;
+
+/// レポートに載せるループの数
+const TOP_LOOPS: usize = 10;
+
+/// 実行したアドレスと命令の回数
+#[derive(Debug, Default)]
+pub struct Profile {
+    counts: Vec<u64>,                              // アドレスごとの実行回数
+    instructions: HashMap<usize, (String, usize)>, // アドレスで実行した命令の名前と長さ
+    branches: BTreeMap<usize, (u64, u64)>,         // 条件ジャンプが飛んだ回数と飛ばなかった回数
+    opcodes: BTreeMap<String, u64>,                // 命令ごとの実行回数
+    back_edges: HashMap<(usize, usize), u64>,      // 後ろに戻るジャンプ(元と先)の回数
+    storage_reads: u64,                            // ストレージを読んだ回数
+    storage_writes: u64,                           // ストレージに書いた回数
+    steps: u64,                                    // 実行した命令の数
+    elapsed: Duration,                             // 実行にかかった時間
+}
+
+impl Profile {
+    /// 回数を数えながらプログラムを最後まで実行する
+    pub fn run(vm: &mut VirtualMachine) -> Profile {
+        let mut profile = Profile {
+            counts: vec![0; vm.memory().len()],
+            ..Profile::default()
+        };
+        let start = Instant::now();
+        while vm.is_running() {
+            // 割り込みやプロセスの切り替えで実行する番地が変わるので、実行した命令を数える
+            if let Some((address, instruction, size)) = vm.step() {
+                profile.count(address, instruction, size, vm.pc());
+            }
+        }
+        profile.elapsed = start.elapsed();
+        vm.report_fault();
+        profile
+    }
+
+    /// 実行した命令を1つ数える (nextは実行した後のプログラムカウンタ)
+    fn count(&mut self, address: usize, instruction: Instruction, size: usize, next: usize) {
+        self.steps += 1;
+        if let Some(count) = self.counts.get_mut(address) {
+            *count += 1;
+        }
+        let name = mnemonic(instruction);
+        *self.opcodes.entry(name.clone()).or_default() += 1;
+        self.instructions.entry(address).or_insert((name, size));
+        match instruction {
+            Instruction::JumpIfZero => {
+                let branch = self.branches.entry(address).or_default();
+                // 次の命令以外に進んだら飛んだとする (次の命令へのジャンプは飛ばなかったと数える)
+                if next != address + size {
+                    branch.0 += 1;
+                    if next <= address {
+                        *self.back_edges.entry((address, next)).or_default() += 1;
+                    }
+                } else {
+                    branch.1 += 1;
+                }
+            }
+            Instruction::Read => self.storage_reads += 1,
+            Instruction::Write => self.storage_writes += 1,
+            _ => {}
+        }
+    }
+
+    /// ソースと対応付けたレポートを作る
+    pub fn report(&self, debug: &DebugInfo) -> String {
+        let mut text = format!("== {}\n", msg!("profile.summary"));
+        let seconds = self.elapsed.as_secs_f64();
+        text += &format!("{}\n", msg!("profile.elapsed", format!("{seconds:.6}")));
+        text += &format!("{}\n", msg!("profile.steps", self.steps));
+        if seconds > 0.0 {
+            let rate = format!("{:.0}", self.steps as f64 / seconds);
+            text += &format!("{}\n", msg!("profile.rate", rate));
+        }
+        text += &format!(
+            "{}\n",
+            msg!("profile.storage", self.storage_reads, self.storage_writes)
+        );
+
+        text += &format!("\n== {}\n", msg!("profile.opcodes"));
+        let mut opcodes: Vec<(&String, &u64)> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1));
+        for (name, count) in opcodes {
+            text += &format!("{count:>10} {:>6.2}%  {name}\n", self.percent(*count));
+        }
+
+        text += &format!("\n== {}\n", msg!("profile.loops"));
+        let mut loops: Vec<(&(usize, usize), &u64)> = self.back_edges.iter().collect();
+        loops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for ((from, to), count) in loops.into_iter().take(TOP_LOOPS) {
+            let count = format!("{count:>10}");
+            let (from, to) = (symbol(debug, *from), debug.describe(*to));
+            text += &format!("{}\n", msg!("profile.loop", count, from, to));
+        }
+
+        text += &format!("\n== {}\n", msg!("profile.lines"));
+        let starts: Vec<&usize> = debug.lines.keys().collect();
+        for (i, (address, location)) in debug.lines.iter().enumerate() {
+            let end = starts.get(i + 1).map_or(self.counts.len(), |next| **next);
+            let count: u64 = (self.counts.get(*address..end.max(*address)))
+                .map_or(0, |counts| counts.iter().sum());
+            text += &format!(
+                "{count:>10} {:>6.2}% | {}:{:<4} | {}\n",
+                self.percent(count),
+                location.file,
+                location.line,
+                location.text
+            );
+        }
+        text
+    }
+
+    /// フレームグラフで使える形式 (ラベル;命令 回数) にする
+    pub fn folded(&self, debug: &DebugInfo) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        for (address, count) in self.counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            // 呼び出し命令がないのでラベルを関数の代わりにする
+            let label = (debug.symbolize(address))
+                .map(|symbol| symbol.split('+').next().unwrap_or_default().to_string())
+                .unwrap_or(msg!("profile.no_label"));
+            let name = self
+                .instructions
+                .get(&address)
+                .map_or("?", |(name, _)| name);
+            *stacks.entry(format!("{label};{name}")).or_default() += count;
+        }
+        (stacks.into_iter())
+            .map(|(stack, count)| format!("{stack} {count}\n"))
+            .collect()
+    }
+
+    /// メモリの番地ごとに、そこを含む命令を実行した回数 (オペランドも含む)
+    pub fn cell_hits(&self) -> Vec<u64> {
+        let mut hits = vec![0; self.counts.len()];
+        for (address, (_, size)) in &self.instructions {
+            let end = (address + size).min(hits.len());
+            for hit in &mut hits[*address..end] {
+                *hit = (*hit).max(self.counts[*address]);
+            }
+        }
+        hits
+    }
+
+    /// 条件ジャンプのアドレスと飛んだ回数・飛ばなかった回数
+    pub fn branches(&self) -> &BTreeMap<usize, (u64, u64)> {
+        &self.branches
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.steps == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.steps as f64
+        }
+    }
+}
+
+/// 命令の名前 (オペランドを除く)
+fn mnemonic(instruction: Instruction) -> String {
+    let text = instruction.to_string();
+    text.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// アドレスをラベルで表す
+fn symbol(debug: &DebugInfo, address: usize) -> String {
+    debug
+        .symbolize(address)
+        .unwrap_or(msg!("common.address", address))
+}
+
+#[cfg(test)]
+mod test_profile {
+    use crate::device;
+    use crate::profile::Profile;
+    use crate::testing;
+
+    #[test]
+    fn test_loop_profile() {
+        // countを3から0まで減らすループ (0でなければloopに戻る)
+        let code = "loop: push\ncount\nload\npush\n1\nsub\npush\ncount\nstore\n\
+                    push\nloop\npush\ncount\nload\npush\n0\nequal\njump\nhalt\n\
+                    count: .word 3\n";
+        let mut vm = testing::machine("loop.asm", code);
+        let profile = Profile::run(&mut vm);
+
+        assert_eq!(profile.opcodes["store"], 3);
+        assert_eq!(
+            profile.back_edges.values().copied().collect::<Vec<u64>>(),
+            vec![2]
+        );
+        assert_eq!(profile.storage_reads + profile.storage_writes, 0);
+        let report = profile.report(vm.debug_info());
+        assert!(report.contains("loop.asm:9"));
+        assert!(report.contains("loop+"));
+        let folded = profile.folded(vm.debug_info());
+        assert!(folded.lines().any(|line| line == "loop;store 3"));
+    }
+
+    #[test]
+    fn test_interrupt_profile() {
+        // 20命令ごとのタイマー割り込みでcountを増やし、3になるまで待つ
+        let code = "push\ntable\nei\npush\n20\npush\n1012\nstore\n\
+                    loop: push\nloop\npush\ncount\nload\npush\n3\nequal\ncheck: jump\ndi\nhalt\n\
+                    handler: push\ncount\nload\npush\n1\nadd\npush\ncount\nstore\niret\n\
+                    table: .word handler, 0\ncount: .word 0\n";
+        let mut vm = testing::machine("interrupt.asm", code);
+        let (base, timer) = device::create("timer").unwrap();
+        vm.map_device(base, timer).unwrap();
+        let profile = Profile::run(&mut vm);
+
+        // 割り込みで処理の番地に移っても、実際に実行した命令を数える
+        let handler = vm.debug_info().address("handler").unwrap();
+        let check = vm.debug_info().address("check").unwrap();
+        assert_eq!(profile.counts[handler], 3);
+        assert_eq!(profile.opcodes["iret"], 3);
+        assert_eq!(profile.opcodes["jump"], profile.counts[check]);
+        let (taken, not_taken) = profile.branches[&check];
+        assert_eq!(taken + not_taken, profile.counts[check]);
+        assert_eq!(not_taken, 1);
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::assembly;
+use crate::msg;
+use crate::object::Object;
+use crate::vm::{Mode, State, VirtualMachine};
+
+/// 取り消せる命令の数
+const HISTORY_SIZE: usize = 10000;
+
+/// ジャンプした後に1行で実行できる命令の数
+const MAX_STEPS: usize = 100000;
+
+/// 入力した1行の実行の記録
+struct Entry {
+    here: usize,  // 入力する前の次に命令を置く番地
+    steps: usize, // 実行した命令の数
+}
+
+/// 命令を1行ずつ実行するREPL
+pub struct Repl {
+    vm: VirtualMachine,               // 仮想マシン
+    storage: File,                    // 初期化するときに使うストレージ
+    defines: HashMap<String, String>, // ラベルとコマンドラインで定義したシンボル
+    here: usize,                      // 次に命令を置く番地
+    entries: Vec<Entry>,              // 取り消すための入力の記録
+    memory_size: Option<usize>,       // メモリの大きさ
+}
+
+impl Repl {
+    pub fn new(
+        storage: File,
+        defines: HashMap<String, String>,
+        memory_size: Option<usize>,
+    ) -> Result<Repl, String> {
+        let mut repl = Repl {
+            vm: Self::machine(&storage, Object::default(), memory_size)?,
+            storage,
+            defines,
+            here: 0,
+            entries: Vec::new(),
+            memory_size,
+        };
+        repl.vm.set_history_size(HISTORY_SIZE);
+        Ok(repl)
+    }
+
+    /// プログラムを読み込んだ仮想マシンを作る
+    fn machine(
+        storage: &File,
+        program: Object,
+        memory_size: Option<usize>,
+    ) -> Result<VirtualMachine, String> {
+        let storage = storage.try_clone().map_err(|e| e.to_string())?;
+        let mut vm = VirtualMachine::new(storage, program, Mode::Embedded);
+        if let Some(size) = memory_size {
+            vm.set_memory_size(size);
+        }
+        vm.set_history_size(HISTORY_SIZE);
+        Ok(vm)
+    }
+
+    /// 1行を実行して表示する内容を返す
+    pub fn eval(&mut self, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => Ok(String::new()),
+            [":help"] => Ok(msg!("repl.help")),
+            [":stack"] => Ok(self.stack()),
+            [":mem"] => self.memory(0, self.here.max(16)),
+            [":mem", range] => {
+                let (start, end) = range.split_once("..").unwrap_or((range, ""));
+                let start = self.address(start)?;
+                let end = if end.is_empty() {
+                    start + 16
+                } else {
+                    self.address(end)?
+                };
+                self.memory(start, end)
+            }
+            [":label"] => {
+                let mut labels: Vec<(&String, &String)> = self.defines.iter().collect();
+                labels.sort_by_key(|(_, value)| value.parse::<i64>().ok());
+                Ok((labels.iter())
+                    .map(|(name, value)| format!("{name} = {value}"))
+                    .collect::<Vec<_>>()
+                    .join("\n"))
+            }
+            [":label", name, address] => {
+                let address = self.address(address)?;
+                self.define(name, address)
+            }
+            [":run"] => self.run_from(0),
+            [":run", target] => {
+                let address = self.address(target)?;
+                self.run_from(address)
+            }
+            [":undo"] => self.undo(),
+            [":reset"] => {
+                self.vm = Self::machine(&self.storage, Object::default(), self.memory_size)?;
+                self.here = 0;
+                self.entries.clear();
+                Ok(msg!("repl.reset"))
+            }
+            [":load", path] => self.load(path),
+            [command, ..] if command.starts_with(':') => Err(msg!("repl.unknown_command", command)),
+            _ => self.code(line),
+        }
+    }
+
+    /// ラベルの定義や命令・データを処理する
+    fn code(&mut self, line: &str) -> Result<String, String> {
+        let mut code = line;
+        let mut text = String::new();
+        if let Some((label, rest)) = line.split_once(':') {
+            if !label.trim().is_empty() && !label.contains(char::is_whitespace) {
+                text = self.define(label.trim(), self.here)?;
+                code = rest.trim();
+                if code.is_empty() {
+                    return Ok(text);
+                }
+                text += "\n";
+            }
+        }
+
+        // データ命令は実行せずに置くだけにする
+        let data = code.starts_with('.');
+        let source = if data {
+            code.to_string()
+        } else {
+            code.split_whitespace().collect::<Vec<_>>().join("\n")
+        };
+        let values = self.assemble(&source)?;
+        let start = self.here;
+        self.place(&values)?;
+        if data {
+            return Ok(text + &msg!("repl.placed", start, values.len()));
+        }
+
+        self.vm.set_pc(start);
+        let steps = self.execute(|vm, steps| steps > 0 && vm.pc() == start + values.len());
+        match steps {
+            Ok(steps) => {
+                self.entries.push(Entry { here: start, steps });
+                Ok(text + &self.result())
+            }
+            Err(e) => {
+                self.here = start;
+                Err(e)
+            }
+        }
+    }
+
+    /// 1行のソースをアセンブルする (ラベルはシンボルとして置き換える)
+    fn assemble(&self, source: &str) -> Result<Vec<i32>, String> {
+        let (program, messages) = assembly::capture(|| {
+            assembly::assembly(source.to_string(), Path::new("repl"), &self.defines)
+        });
+        let errors: Vec<&str> = (messages.iter())
+            .map(String::as_str)
+            .filter(|message| assembly::is_error(message))
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        Ok(program.code)
+    }
+
+    /// 値を次に命令を置く番地から順番に置く
+    fn place(&mut self, values: &[i32]) -> Result<(), String> {
+        if self.here + values.len() > self.vm.memory().len() {
+            return Err(msg!("repl.out_of_memory"));
+        }
+        for value in values {
+            self.vm.set_memory(self.here, *value)?;
+            self.here += 1;
+        }
+        Ok(())
+    }
+
+    /// haltかdoneが成り立つまで実行する (エラーになった命令は取り消す)
+    fn execute(&mut self, done: impl Fn(&VirtualMachine, usize) -> bool) -> Result<usize, String> {
+        if !self.vm.is_running() {
+            return Err(msg!("repl.stopped"));
+        }
+        let length = self.vm.output().len();
+        let mut steps = 0;
+        while !done(&self.vm, steps) {
+            if steps >= MAX_STEPS {
+                self.end_output(length);
+                self.rewind(steps);
+                return Err(msg!("repl.too_many_steps", MAX_STEPS));
+            }
+            // 入力命令の前に入力を受け付ける
+            if self.vm.needs_input() {
+                self.vm
+                    .push_input(prompt(&msg!("vm.input_prompt")).unwrap_or_default());
+            }
+            let printed = self.vm.output().len();
+            self.vm.step();
+            if self.vm.output().len() > printed {
+                print!("{}", &self.vm.output()[printed..]);
+                let _ = io::stdout().flush();
+            }
+            steps += 1;
+            match self.vm.state().clone() {
+                State::Running => {}
+                State::Halted => break,
+                State::Fault(message) => {
+                    self.end_output(length);
+                    self.rewind(steps);
+                    return Err(msg!("repl.fault_undone", message));
+                }
+            }
+        }
+        self.end_output(length);
+        Ok(steps)
+    }
+
+    /// 出力が改行で終わっていなければ改行する
+    fn end_output(&self, length: usize) {
+        let output = &self.vm.output()[length..];
+        if !output.is_empty() && !output.ends_with('\n') {
+            println!();
+        }
+    }
+
+    /// 実行した命令を取り消す
+    fn rewind(&mut self, steps: usize) {
+        for _ in 0..steps {
+            if self.vm.step_back().is_err() {
+                break;
+            }
+        }
+    }
+
+    /// 指定した番地からhaltか次に命令を置く番地まで実行する
+    fn run_from(&mut self, address: usize) -> Result<String, String> {
+        if address >= self.here {
+            return Err(msg!("repl.not_placed", address));
+        }
+        self.vm.set_pc(address);
+        let here = self.here;
+        let steps = self.execute(|vm, _| vm.pc() == here)?;
+        self.entries.push(Entry { here, steps });
+        Ok(self.result())
+    }
+
+    /// 最後に入力した行を取り消す
+    fn undo(&mut self) -> Result<String, String> {
+        let entry = self.entries.pop().ok_or(msg!("repl.nothing_to_undo"))?;
+        self.rewind(entry.steps);
+        self.here = entry.here;
+        self.vm.set_pc(self.here);
+        Ok(format!("{}\n{}", msg!("repl.undone"), self.stack()))
+    }
+
+    /// ファイルをアセンブルして0番地から読み込む
+    fn load(&mut self, path: &str) -> Result<String, String> {
+        let code = fs::read_to_string(path).map_err(|e| msg!("common.read_failed", path, e))?;
+        let (program, messages) =
+            assembly::capture(|| assembly::assembly(code, Path::new(path), &self.defines));
+        if let Some(error) = messages.iter().find(|message| assembly::is_error(message)) {
+            return Err(error.clone());
+        }
+        let size = program.code.len();
+        for (name, address) in &program.debug.labels {
+            self.defines.insert(name.clone(), address.to_string());
+        }
+        self.vm = Self::machine(&self.storage, program, self.memory_size)?;
+        self.here = size;
+        self.entries.clear();
+        Ok(msg!("repl.loaded", path, size))
+    }
+
+    /// ラベルを定義する
+    fn define(&mut self, name: &str, address: usize) -> Result<String, String> {
+        let valid = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if !valid {
+            return Err(msg!("repl.bad_label", name));
+        }
+        self.defines.insert(name.to_string(), address.to_string());
+        Ok(format!("{name} = {address}"))
+    }
+
+    /// 番地かラベルを番地にする
+    fn address(&self, text: &str) -> Result<usize, String> {
+        let value = self.defines.get(text).map_or(text, String::as_str);
+        value.parse().map_err(|_| msg!("repl.bad_address", text))
+    }
+
+    /// 実行した後の状態
+    fn result(&self) -> String {
+        match self.vm.state() {
+            State::Halted => format!("{}\n{}", self.stack(), msg!("repl.halted")),
+            _ => self.stack(),
+        }
+    }
+
+    fn stack(&self) -> String {
+        msg!("repl.stack", format!("{:?}", self.vm.stack()))
+    }
+
+    /// メモリの内容を8セルごとに表示する
+    fn memory(&self, start: usize, end: usize) -> Result<String, String> {
+        let end = end.min(self.vm.memory().len());
+        if start >= end {
+            return Err(msg!("repl.bad_range"));
+        }
+        let rows: Vec<String> = (start..end)
+            .step_by(8)
+            .map(|row| {
+                let cells: Vec<String> = (row..(row + 8).min(end))
+                    .map(|address| format!("{:>6}", self.vm.memory()[address]))
+                    .collect();
+                format!("{row:0>3}: {}", cells.join(" "))
+            })
+            .collect();
+        Ok(rows.join("\n"))
+    }
+}
+
+/// プロンプトを表示して1行読む (入力が終わったらNone)
+fn prompt(text: &str) -> Option<String> {
+    print!("{text}");
+    let _ = io::stdout().flush();
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end().to_string()),
+    }
+}
+
+/// 標準入力から1行ずつ読んで実行する
+pub fn run(mut repl: Repl) {
+    println!("{}", msg!("repl.help_hint"));
+    while let Some(line) = prompt(&format!("{:0>3}> ", repl.here)) {
+        if line.trim() == ":quit" {
+            break;
+        }
+        match repl.eval(&line) {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) => println!("{text}"),
+            Err(e) => println!("{}", msg!("vm.fault", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_repl {
+    use std::collections::HashMap;
+
+    use crate::repl::Repl;
+    use crate::testing;
+
+    fn repl() -> Repl {
+        let (_, storage) = testing::storage("repl.txt", "");
+        Repl::new(storage, HashMap::new(), None).unwrap()
+    }
+
+    #[test]
+    fn test_eval_and_undo() {
+        let mut repl = repl();
+        assert_eq!(repl.eval("push 2").unwrap(), "スタック: [2]");
+        assert_eq!(repl.eval("push 3").unwrap(), "スタック: [2, 3]");
+        assert_eq!(repl.eval("add").unwrap(), "スタック: [5]");
+        assert_eq!(
+            repl.eval(":undo").unwrap(),
+            "取り消しました\nスタック: [2, 3]"
+        );
+        assert_eq!(
+            repl.eval(":mem 0..4").unwrap(),
+            "000:      6      2      6      3"
+        );
+
+        // エラーになった命令は取り消して続けられる
+        assert!(repl.eval("div").is_ok());
+        assert!(repl.eval("div").unwrap_err().contains("取り消しました"));
+        assert_eq!(repl.eval(":stack").unwrap(), "スタック: [0]");
+        assert!(repl.eval(":unknown").is_err());
+
+        repl.eval(":reset").unwrap();
+        assert_eq!(repl.eval(":stack").unwrap(), "スタック: []");
+    }
+
+    #[test]
+    fn test_labels_and_loop() {
+        let mut repl = repl();
+        assert_eq!(
+            repl.eval("count: .word 3").unwrap(),
+            "count = 0\n0番地に1個の値を置きました"
+        );
+        // countを0になるまで減らすループ
+        assert_eq!(
+            repl.eval("loop: push count").unwrap(),
+            "loop = 1\nスタック: [0]"
+        );
+        for line in [
+            "load",
+            "push 1",
+            "sub",
+            "push count",
+            "store",
+            "push loop",
+            "push count",
+            "load",
+        ] {
+            repl.eval(line).unwrap();
+        }
+        repl.eval("push 0").unwrap();
+        repl.eval("equal").unwrap();
+        assert_eq!(repl.eval("jump").unwrap(), "スタック: []");
+        assert_eq!(repl.eval(":mem count..1").unwrap(), "000:      0");
+        assert!(repl.eval("3x: push 1").is_err());
+    }
+
+    #[test]
+    fn test_storage_fault_is_undone() {
+        let mut repl = repl();
+        repl.eval("push 5").unwrap();
+        repl.eval("push 1").unwrap();
+        // 空のストレージへの読み書きは中断せずにエラーとして取り消す
+        let error = repl.eval("write").unwrap_err();
+        assert!(error.contains("ストレージに1行目はありません"), "{error}");
+        assert_eq!(repl.eval(":stack").unwrap(), "スタック: [5, 1]");
+        repl.eval("push 0").unwrap();
+        assert!(repl.eval("read").unwrap_err().contains("取り消しました"));
+        repl.eval("push -1").unwrap();
+        assert!(repl.eval("read").is_err());
+        assert_eq!(repl.eval(":stack").unwrap(), "スタック: [5, 1, 0, -1]");
+    }
+}
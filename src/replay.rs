@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+
+use crate::assembly::{parse_string, quote_string};
+use crate::msg;
+
+/// 記録ファイルの先頭に書くマジックナンバー
+const MAGIC: &str = "SIMPLE-REPLAY 1";
+
+/// 外部から受け取った値
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Line(String),      // 入力された1行
+    EndOfInput,        // 入力の終わり
+    Host(String, i32), // ホスト呼び出しの名前と結果
+}
+
+/// 入力とホスト呼び出しの結果を記録するか、記録したものを再現する
+pub enum Replay {
+    Record(File),          // 記録する (命令が止まっても失わないようにすぐ書き込む)
+    Play(VecDeque<Entry>), // 再現する値の残り
+}
+
+impl Entry {
+    /// 記録ファイルの1行にする
+    fn to_text(&self) -> String {
+        match self {
+            Entry::Line(line) => format!("line {}", quote_string(line)),
+            Entry::EndOfInput => "eof".to_string(),
+            Entry::Host(name, value) => format!("host {name} {value}"),
+        }
+    }
+
+    /// 記録ファイルの1行を読む
+    fn parse(line: &str) -> Option<Entry> {
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match kind {
+            "line" => parse_string(rest).ok().map(Entry::Line),
+            "eof" => Some(Entry::EndOfInput),
+            "host" => {
+                let (name, value) = rest.split_once(' ')?;
+                Some(Entry::Host(name.to_string(), value.parse().ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Replay {
+    /// 記録を始める
+    pub fn record(path: &str) -> Result<Replay, String> {
+        let mut file = File::create(path).map_err(|e| msg!("common.create_failed", path, e))?;
+        writeln!(file, "{MAGIC}").map_err(|e| msg!("common.write_failed", path, e))?;
+        Ok(Replay::Record(file))
+    }
+
+    /// 記録ファイルを読み込んで再現を始める
+    pub fn play(path: &str) -> Result<Replay, String> {
+        let text = fs::read_to_string(path).map_err(|e| msg!("common.read_failed", path, e))?;
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some(MAGIC) {
+            return Err(msg!("replay.invalid", path));
+        }
+        let mut entries = VecDeque::new();
+        for (i, line) in lines.enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = Entry::parse(line).ok_or(msg!("replay.bad_record", i + 2, line))?;
+            entries.push_back(entry);
+        }
+        Ok(Replay::Play(entries))
+    }
+
+    /// 再現しているか判断する
+    pub fn is_playing(&self) -> bool {
+        matches!(self, Replay::Play(_))
+    }
+
+    /// 受け取った値を記録する (再現しているときは何もしない)
+    pub fn write(&mut self, entry: &Entry) -> Result<(), String> {
+        match self {
+            Replay::Record(file) => {
+                writeln!(file, "{}", entry.to_text()).map_err(|e| msg!("replay.write_failed", e))
+            }
+            Replay::Play(_) => Ok(()),
+        }
+    }
+
+    /// 入力の1行を再現する (入力の終わりはNone)
+    pub fn line(&mut self) -> Result<Option<String>, String> {
+        match self.next()? {
+            Entry::Line(line) => Ok(Some(line)),
+            Entry::EndOfInput => Ok(None),
+            entry => Err(msg!("replay.mismatch", "line", entry.to_text())),
+        }
+    }
+
+    /// ホスト呼び出しの結果を再現する
+    pub fn host(&mut self, name: &str) -> Result<i32, String> {
+        match self.next()? {
+            Entry::Host(recorded, value) if recorded == name => Ok(value),
+            entry => Err(msg!(
+                "replay.mismatch",
+                format!("host {name}"),
+                entry.to_text()
+            )),
+        }
+    }
+
+    /// 記録した次の値が指定したものなら取り出す (割り込みのように起きるかどうかを再現する)
+    pub fn take(&mut self, entry: &Entry) -> bool {
+        match self {
+            Replay::Play(entries) if entries.front() == Some(entry) => {
+                entries.pop_front();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 記録した次の値を取り出す
+    fn next(&mut self) -> Result<Entry, String> {
+        match self {
+            Replay::Play(entries) => entries.pop_front().ok_or(msg!("replay.exhausted")),
+            Replay::Record(_) => Err(msg!("replay.exhausted")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_replay {
+    use crate::replay::{Entry, Replay};
+    use crate::testing;
+    use crate::vm::State;
+
+    #[test]
+    fn test_record_and_play() {
+        // 2つの数を読んで足し、残りの1行を文字列として出力する
+        let code = "input\ninput\nadd\noutputnum\npush\nbuf\ninputstr\npop\npush\nbuf\noutputstr\n\
+                    eof\noutputnum\nhalt\nbuf: .zero 8\n";
+        let path = testing::temp_path("replay.txt");
+        let path = path.display().to_string();
+
+        let mut vm = testing::machine("replay.asm", code);
+        vm.set_replay(Replay::record(&path).unwrap());
+        for line in ["3", "4", "\"引用\" ok"] {
+            vm.push_input(line.to_string());
+        }
+        vm.run();
+        assert_eq!(vm.output(), "7\"引用\" ok1");
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.ends_with("line \"\\\"引用\\\" ok\"\neof\n"));
+
+        // 入力を与えなくても記録から同じ結果になる
+        let mut vm = testing::machine("replay.asm", code);
+        vm.set_replay(Replay::play(&path).unwrap());
+        vm.run();
+        assert_eq!(vm.output(), "7\"引用\" ok1");
+
+        // 記録が足りなければエラーで停止する
+        std::fs::write(&path, "SIMPLE-REPLAY 1\nline \"3\"\nhost winapi 1\n").unwrap();
+        let mut vm = testing::machine("replay.asm", code);
+        vm.set_replay(Replay::play(&path).unwrap());
+        vm.run();
+        assert!(matches!(vm.state(), State::Fault(_)));
+
+        assert_eq!(
+            Entry::parse("host random 42"),
+            Some(Entry::Host("random".to_string(), 42))
+        );
+        assert!(Replay::play(&path.replace("replay", "missing")).is_err());
+    }
+}
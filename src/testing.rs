@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::assembly;
+use crate::io;
+use crate::vm::{Mode, VirtualMachine};
+
+/// 一時ファイルの通し番号
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// テストごとに別の一時ファイルのパス (並列に実行しても、実際に使うファイルとも重ならない)
+pub fn temp_path(name: &str) -> PathBuf {
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let file = format!("simple_vm_test_{}_{count}_{name}", std::process::id());
+    std::env::temp_dir().join(file)
+}
+
+/// 中身を書いた一時ファイルをストレージとして開く
+pub fn storage(name: &str, text: &str) -> (PathBuf, File) {
+    let path = temp_path(name);
+    std::fs::write(&path, text).unwrap();
+    let storage = io::open_file(path.display().to_string()).unwrap();
+    (path, storage)
+}
+
+/// ソースをアセンブルして、空のストレージで外部から操作する仮想マシンを作る
+/// (nameはデバッグ情報に残るソースのファイル名)
+pub fn machine(name: &str, code: &str) -> VirtualMachine {
+    let (_, storage) = storage("storage.txt", "");
+    let program = assembly::assembly(code.to_string(), Path::new(name), &HashMap::new());
+    VirtualMachine::new(storage, program, Mode::Embedded)
+}
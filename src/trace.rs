@@ -0,0 +1,447 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+use crate::instruction::Instruction;
+use crate::json::Json;
+use crate::msg;
+
+/// バイナリ形式のトレースの先頭に書くマジックナンバー
+const MAGIC: &[u8] = b"SIMPLE-TRACE 1\n";
+
+/// 命令が起こした書き込みと入出力
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Memory(usize, i32),  // メモリの番地と書き込んだ値
+    Storage(usize, i32), // ストレージの行と書き込んだ値
+    Input(i32),          // 入力された値
+    Output(i32),         // 出力した文字コード
+}
+
+/// 1命令分の実行の記録
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Step {
+    pub step: usize,           // 何番目に実行した命令か (0から)
+    pub pc: usize,             // 命令のアドレス
+    pub opcode: i32,           // 命令コード
+    pub operands: Vec<i32>,    // オペランド
+    pub before: Vec<i32>,      // 実行前のスタック
+    pub after: Vec<i32>,       // 実行後のスタック
+    pub events: Vec<Event>,    // 書き込みと入出力
+    pub fault: Option<String>, // エラーで停止した場合はその内容
+}
+
+/// トレースの保存形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,   // 1行に1命令のJSON Lines
+    Binary, // 小さなバイナリ形式
+}
+
+impl Format {
+    /// ファイル名の拡張子から保存形式を決める
+    pub fn from_path(path: &str) -> Format {
+        if path.ends_with(".bin") || path.ends_with(".trace") {
+            Format::Binary
+        } else {
+            Format::Json
+        }
+    }
+}
+
+/// トレースをファイルに書き出す
+pub struct Tracer {
+    out: BufWriter<File>, // 出力先
+    format: Format,       // 保存形式
+    count: usize,         // 書き出した命令の数
+}
+
+impl Tracer {
+    pub fn create(path: &str, format: Format) -> Result<Tracer, String> {
+        let file = File::create(path).map_err(|e| msg!("common.create_failed", path, e))?;
+        let mut out = BufWriter::new(file);
+        if format == Format::Binary {
+            out.write_all(MAGIC).map_err(|e| e.to_string())?;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            count: 0,
+        })
+    }
+
+    /// 次の命令番号
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// 命令の記録を1つ書き出す
+    pub fn write(&mut self, step: &Step) -> std::io::Result<()> {
+        self.count += 1;
+        match self.format {
+            Format::Json => writeln!(self.out, "{}", step.to_json()),
+            Format::Binary => self.out.write_all(&step.to_bytes()),
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Step {
+    /// 命令を読める形にする
+    pub fn instruction(&self) -> String {
+        let mut memory = vec![self.opcode];
+        memory.extend(&self.operands);
+        match Instruction::decode(&memory, 0) {
+            Some((instruction, _)) => instruction.to_string(),
+            None => format!(".word {}", self.opcode),
+        }
+    }
+
+    /// JSONの1行に変換する
+    pub fn to_json(&self) -> String {
+        let events: Vec<String> = self
+            .events
+            .iter()
+            .map(|event| match event {
+                Event::Memory(address, value) => format!("[\"memory\",{address},{value}]"),
+                Event::Storage(line, value) => format!("[\"storage\",{line},{value}]"),
+                Event::Input(value) => format!("[\"input\",{value}]"),
+                Event::Output(value) => format!("[\"output\",{value}]"),
+            })
+            .collect();
+        let mut json = format!(
+            "{{\"step\":{},\"pc\":{},\"opcode\":{},\"operands\":{},\"before\":{},\"after\":{},\"events\":[{}]",
+            self.step,
+            self.pc,
+            self.opcode,
+            json_array(&self.operands),
+            json_array(&self.before),
+            json_array(&self.after),
+            events.join(",")
+        );
+        if let Some(fault) = &self.fault {
+            json += &format!(",\"fault\":{}", Json::from(fault.as_str()));
+        }
+        json + "}"
+    }
+
+    /// JSONの1行から読み込む
+    pub fn from_json(text: &str) -> Result<Step, String> {
+        let Json::Object(fields) = Json::parse(text)? else {
+            return Err(msg!("trace.not_object"));
+        };
+        let mut step = Step::default();
+        for (key, value) in fields {
+            match key.as_str() {
+                "step" => step.step = value.number()? as usize,
+                "pc" => step.pc = value.number()? as usize,
+                "opcode" => step.opcode = value.number()? as i32,
+                "operands" => step.operands = parse_numbers(value)?,
+                "before" => step.before = parse_numbers(value)?,
+                "after" => step.after = parse_numbers(value)?,
+                "events" => {
+                    for event in value.into_array()? {
+                        step.events.push(parse_event(event)?);
+                    }
+                }
+                "fault" => match value {
+                    Json::String(fault) => step.fault = Some(fault),
+                    _ => return Err(msg!("trace.bad_fault")),
+                },
+                _ => return Err(msg!("trace.unknown_field", key)),
+            }
+        }
+        Ok(step)
+    }
+
+    /// バイナリ形式に変換する
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend((self.step as u32).to_le_bytes());
+        bytes.extend((self.pc as u32).to_le_bytes());
+        bytes.extend(self.opcode.to_le_bytes());
+        for values in [&self.operands, &self.before, &self.after] {
+            bytes.extend((values.len() as u32).to_le_bytes());
+            for value in values {
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+        bytes.extend((self.events.len() as u32).to_le_bytes());
+        for event in &self.events {
+            let (tag, a, b) = match *event {
+                Event::Memory(address, value) => (0u8, address as i32, value),
+                Event::Storage(line, value) => (1, line as i32, value),
+                Event::Input(value) => (2, 0, value),
+                Event::Output(value) => (3, 0, value),
+            };
+            bytes.push(tag);
+            bytes.extend(a.to_le_bytes());
+            bytes.extend(b.to_le_bytes());
+        }
+        match &self.fault {
+            Some(fault) => {
+                bytes.extend((fault.len() as u32 + 1).to_le_bytes());
+                bytes.extend(fault.as_bytes());
+            }
+            None => bytes.extend(0u32.to_le_bytes()),
+        }
+        bytes
+    }
+
+    /// バイナリ形式から1命令分を読み込む
+    fn from_bytes(reader: &mut Reader) -> Result<Step, String> {
+        let mut step = Step {
+            step: reader.u32()? as usize,
+            pc: reader.u32()? as usize,
+            opcode: reader.i32()?,
+            ..Step::default()
+        };
+        for values in [&mut step.operands, &mut step.before, &mut step.after] {
+            for _ in 0..reader.u32()? {
+                values.push(reader.i32()?);
+            }
+        }
+        for _ in 0..reader.u32()? {
+            let tag = reader.bytes(1)?[0];
+            let (a, b) = (reader.i32()?, reader.i32()?);
+            step.events.push(match tag {
+                0 => Event::Memory(a as usize, b),
+                1 => Event::Storage(a as usize, b),
+                2 => Event::Input(b),
+                3 => Event::Output(b),
+                _ => return Err(msg!("trace.unknown_event", tag)),
+            });
+        }
+        let length = reader.u32()? as usize;
+        if length > 0 {
+            let fault = reader.bytes(length - 1)?;
+            step.fault = Some(String::from_utf8_lossy(fault).to_string());
+        }
+        Ok(step)
+    }
+
+    /// 1行で表示する
+    pub fn pretty(&self) -> String {
+        let mut text = format!(
+            "#{:<6} {:>4}: {:<12} {:?} -> {:?}",
+            self.step,
+            self.pc,
+            self.instruction(),
+            self.before,
+            self.after
+        );
+        for event in &self.events {
+            text += &match event {
+                Event::Memory(address, value) => format!("  mem[{address}]={value}"),
+                Event::Storage(line, value) => format!("  storage[{line}]={value}"),
+                Event::Input(value) => format!("  in={value}"),
+                Event::Output(value) => match char::from_u32(*value as u32) {
+                    Some(c) => format!("  out={c:?}"),
+                    None => format!("  out={value}"),
+                },
+            };
+        }
+        if let Some(fault) = &self.fault {
+            text += &format!("  {}", msg!("vm.fault", fault));
+        }
+        text
+    }
+}
+
+/// トレースファイルを読み込む (形式は内容から判断する)
+pub fn load(path: &str) -> Result<Vec<Step>, String> {
+    let bytes = fs::read(path).map_err(|e| msg!("common.read_failed", path, e))?;
+    if let Some(body) = bytes.strip_prefix(MAGIC) {
+        let mut reader = Reader {
+            bytes: body,
+            pos: 0,
+        };
+        let mut steps = Vec::new();
+        while reader.pos < body.len() {
+            steps.push(Step::from_bytes(&mut reader)?);
+        }
+        return Ok(steps);
+    }
+    let text = String::from_utf8(bytes).map_err(|_| msg!("trace.invalid", path))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| Step::from_json(line).map_err(|e| msg!("trace.line_error", i + 1, e)))
+        .collect()
+}
+
+/// 表示する命令の条件
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub pc: Option<(usize, usize)>, // アドレスの範囲 (終わりを含む)
+    pub opcode: Option<String>,     // 命令の名前
+    pub writes: bool,               // 書き込んだ命令だけ
+    pub io: bool,                   // 入出力した命令だけ
+}
+
+impl Filter {
+    /// コマンドライン引数から条件を読み取る
+    pub fn parse(args: &[String]) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--pc" => {
+                    let range = args.next().ok_or(msg!("cli.missing_value", arg))?;
+                    let number = |text: &str| {
+                        (text.trim().parse::<usize>()).map_err(|_| msg!("trace.bad_address", text))
+                    };
+                    filter.pc = Some(match range.split_once("..") {
+                        Some((start, end)) => (number(start)?, number(end)?),
+                        None => (number(range)?, number(range)?),
+                    });
+                }
+                "--op" => {
+                    filter.opcode = Some(args.next().ok_or(msg!("cli.missing_value", arg))?.clone())
+                }
+                "--writes" => filter.writes = true,
+                "--io" => filter.io = true,
+                _ => return Err(msg!("cli.unknown_option", arg)),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, step: &Step) -> bool {
+        let in_range = match self.pc {
+            Some((start, end)) => (start..=end).contains(&step.pc),
+            None => true,
+        };
+        let opcode = match &self.opcode {
+            Some(name) => step.instruction().split_whitespace().next() == Some(name.as_str()),
+            None => true,
+        };
+        let writes = !self.writes
+            || (step.events.iter()).any(|e| matches!(e, Event::Memory(..) | Event::Storage(..)));
+        let io = !self.io
+            || (step.events.iter()).any(|e| matches!(e, Event::Input(_) | Event::Output(_)));
+        in_range && opcode && writes && io
+    }
+}
+
+/// 2つのトレースを比べて最初に違う命令の位置を返す
+pub fn diff(a: &[Step], b: &[Step]) -> Option<usize> {
+    let same = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    if same == a.len() && same == b.len() {
+        None
+    } else {
+        Some(same)
+    }
+}
+
+/// 数値の配列をJSONにする
+fn json_array(values: &[i32]) -> String {
+    let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+    format!("[{}]", values.join(","))
+}
+
+/// トレースのイベントをJSONの配列から読み込む
+fn parse_event(json: Json) -> Result<Event, String> {
+    let values = json.into_array()?;
+    let number = |i: usize| match values.get(i) {
+        Some(value) => value.number(),
+        None => Err(msg!("trace.missing_event_value")),
+    };
+    match values.first().and_then(Json::as_str) {
+        Some("memory") => Ok(Event::Memory(number(1)? as usize, number(2)? as i32)),
+        Some("storage") => Ok(Event::Storage(number(1)? as usize, number(2)? as i32)),
+        Some("input") => Ok(Event::Input(number(1)? as i32)),
+        Some("output") => Ok(Event::Output(number(1)? as i32)),
+        Some(kind) => Err(msg!("trace.unknown_event", kind)),
+        None => Err(msg!("trace.missing_event_kind")),
+    }
+}
+
+/// 数値の配列を読み込む
+fn parse_numbers(json: Json) -> Result<Vec<i32>, String> {
+    (json.into_array()?.iter())
+        .map(|value| Ok(value.number()? as i32))
+        .collect()
+}
+
+/// バイナリ形式を読む
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let bytes = (self.bytes.get(self.pos..self.pos + length)).ok_or(msg!("trace.truncated"))?;
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test_trace {
+    use crate::trace::{diff, Event, Filter, Reader, Step};
+
+    fn sample() -> Step {
+        Step {
+            step: 3,
+            pc: 10,
+            opcode: 15,
+            operands: vec![],
+            before: vec![5, -21],
+            after: vec![],
+            events: vec![Event::Memory(21, 5), Event::Output(0x3042)],
+            fault: Some("\"失敗\"\n".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let step = sample();
+        assert_eq!(Step::from_json(&step.to_json()), Ok(step));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let step = sample();
+        let bytes = step.to_bytes();
+        let mut reader = Reader {
+            bytes: &bytes,
+            pos: 0,
+        };
+        assert_eq!(Step::from_bytes(&mut reader), Ok(step));
+        assert_eq!(reader.pos, bytes.len());
+    }
+
+    #[test]
+    fn test_filter_and_diff() {
+        let args: Vec<String> = ["--pc", "5..10", "--op", "store", "--writes"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let filter = Filter::parse(&args).unwrap();
+        let step = sample();
+        assert!(filter.matches(&step));
+        assert!(!filter.matches(&Step { pc: 11, ..sample() }));
+
+        let other = Step {
+            after: vec![1],
+            ..sample()
+        };
+        assert_eq!(
+            diff(&[step.clone(), step.clone()], &[step.clone(), other]),
+            Some(1)
+        );
+        assert_eq!(diff(&[sample()], &[sample()]), None);
+    }
+}
@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use crate::instruction::Instruction;
+use crate::msg;
+use crate::vm::{Access, State, VirtualMachine};
+
+/// 戻れる命令の数
+const HISTORY_SIZE: usize = 10000;
+
+/// 継続したときに止まらずに実行する命令の上限
+const MAX_CONTINUE: usize = 1_000_000;
+
+/// 最近書き込まれたとして色を付ける命令の数
+const RECENT: usize = 10;
+
+/// 端末の大きさが分からないときの大きさ
+const DEFAULT_SIZE: (usize, usize) = (100, 32);
+
+/// 文字の色
+const RESET: &str = "\x1b[0m";
+const TITLE: &str = "\x1b[1;36m";
+const SELECTED: &str = "\x1b[7m";
+const CURSOR: &str = "\x1b[4m";
+const BREAKPOINT: &str = "\x1b[31m";
+const DIM: &str = "\x1b[2m";
+const WRITTEN: &str = "\x1b[1;33m";
+const RECENTLY_WRITTEN: &str = "\x1b[33m";
+
+/// 押されたキー
+#[derive(Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Enter,
+    Backspace,
+    Other,
+}
+
+/// 画面の1行の一部
+struct Span {
+    text: String,        // 表示する文字列
+    style: &'static str, // 色
+}
+
+type Row = Vec<Span>;
+
+fn span(text: impl Into<String>, style: &'static str) -> Span {
+    Span {
+        text: text.into(),
+        style,
+    }
+}
+
+/// 全画面のデバッガ
+pub struct Tui {
+    breakpoints: Vec<usize>,        // ブレークポイントのアドレス
+    cursor: usize,                  // 逆アセンブルのカーソルのアドレス
+    memory_row: usize,              // メモリを表示し始める行
+    hex: bool,                      // メモリを16進数で表示するか
+    written: HashMap<usize, usize>, // メモリの番地と最後に書き込んだ命令の番号
+    steps: usize,                   // 実行した命令の数
+    message: String,                // 一番下に表示するメッセージ
+}
+
+/// 全画面のデバッガでプログラムを実行する
+pub fn run(vm: &mut VirtualMachine) -> io::Result<()> {
+    let terminal = Terminal::enter()?;
+    vm.set_history_size(HISTORY_SIZE);
+    let mut tui = Tui {
+        breakpoints: Vec::new(),
+        cursor: vm.pc(),
+        memory_row: 0,
+        hex: false,
+        written: HashMap::new(),
+        steps: 0,
+        message: msg!("tui.help"),
+    };
+    loop {
+        tui.draw(vm, terminal.size())?;
+        let Some(key) = read_key()? else {
+            break;
+        };
+        match key {
+            Key::Char('q') => break,
+            Key::Char('s') | Key::Enter => {
+                tui.step(vm, &terminal)?;
+            }
+            Key::Char('c') => tui.resume(vm, &terminal)?,
+            Key::Char('r') => tui.back(vm),
+            Key::Char('b') => tui.toggle_breakpoint(),
+            Key::Up | Key::Down => {
+                let starts = instruction_starts(vm.memory(), vm.pc());
+                let index = starts.partition_point(|a| *a < tui.cursor);
+                tui.cursor = match key {
+                    Key::Up => starts[index.saturating_sub(1)],
+                    _ => starts[(index + 1).min(starts.len() - 1)],
+                };
+            }
+            Key::Char('j') => tui.memory_row += 1,
+            Key::Char('k') => tui.memory_row = tui.memory_row.saturating_sub(1),
+            Key::PageDown => tui.memory_row += 8,
+            Key::PageUp => tui.memory_row = tui.memory_row.saturating_sub(8),
+            Key::Char('x') => tui.hex = !tui.hex,
+            Key::Char('?') => tui.message = msg!("tui.help"),
+            _ => {}
+        }
+    }
+    drop(terminal);
+    vm.report_fault();
+    Ok(())
+}
+
+impl Tui {
+    /// 命令を1つ実行する (実行できなければfalseを返す)
+    fn step(&mut self, vm: &mut VirtualMachine, terminal: &Terminal) -> io::Result<bool> {
+        if !vm.is_running() {
+            self.message = msg!("tui.not_running");
+            return Ok(false);
+        }
+        if vm.needs_input() {
+            let line = self.prompt(vm, terminal)?;
+            vm.push_input(line);
+        }
+        vm.step();
+        self.steps += 1;
+        for access in vm.writes() {
+            if let Access::Memory(address) = access {
+                self.written.insert(*address, self.steps);
+            }
+        }
+        self.cursor = vm.pc();
+        self.message = match vm.state() {
+            State::Running => String::new(),
+            State::Halted => msg!("tui.halted"),
+            State::Fault(message) => msg!("vm.fault", message),
+        };
+        Ok(vm.is_running())
+    }
+
+    /// ブレークポイントか終了まで実行する
+    fn resume(&mut self, vm: &mut VirtualMachine, terminal: &Terminal) -> io::Result<()> {
+        for _ in 0..MAX_CONTINUE {
+            if !self.step(vm, terminal)? {
+                return Ok(());
+            }
+            if self.breakpoints.contains(&vm.pc()) {
+                self.message = msg!("tui.breakpoint_hit", vm.pc());
+                return Ok(());
+            }
+        }
+        self.message = msg!("tui.max_continue", MAX_CONTINUE);
+        Ok(())
+    }
+
+    /// 最後に実行した命令を取り消す
+    fn back(&mut self, vm: &mut VirtualMachine) {
+        match vm.step_back() {
+            Ok(_) => {
+                self.steps = self.steps.saturating_sub(1);
+                let steps = self.steps;
+                self.written.retain(|_, step| *step <= steps);
+                self.message = String::new();
+            }
+            Err(message) => self.message = message,
+        }
+        self.cursor = vm.pc();
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        if let Some(index) = self.breakpoints.iter().position(|a| *a == self.cursor) {
+            self.breakpoints.remove(index);
+            self.message = msg!("tui.breakpoint_removed", self.cursor);
+        } else {
+            self.breakpoints.push(self.cursor);
+            self.message = msg!("tui.breakpoint_set", self.cursor);
+        }
+    }
+
+    /// 入力命令のために1行入力してもらう
+    fn prompt(&mut self, vm: &VirtualMachine, terminal: &Terminal) -> io::Result<String> {
+        let mut line = String::new();
+        loop {
+            self.message = format!("{}{line}", msg!("vm.input_prompt"));
+            self.draw(vm, terminal.size())?;
+            match read_key()? {
+                None | Some(Key::Enter) => return Ok(line),
+                Some(Key::Backspace) => {
+                    line.pop();
+                }
+                Some(Key::Char(c)) => line.push(c),
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// 画面全体を描く
+    fn draw(&self, vm: &VirtualMachine, (width, height): (usize, usize)) -> io::Result<()> {
+        let body = height.saturating_sub(2).max(8);
+        let top = body * 11 / 20;
+        let bottom = body - top;
+        let left = width * 3 / 5;
+
+        let state = match vm.state() {
+            State::Running => msg!("tui.state.running"),
+            State::Halted => msg!("tui.state.halted"),
+            State::Fault(_) => msg!("tui.state.fault"),
+        };
+        let mut rows = vec![vec![span(
+            format!(
+                " Simple VM  pc={}  sp={}  {}",
+                vm.pc(),
+                vm.stack().len(),
+                msg!("tui.status", state, self.steps)
+            ),
+            SELECTED,
+        )]];
+        rows.extend(beside(self.disassembly(vm, top), stack_pane(vm, top), left));
+        let storage = bottom / 2;
+        let mut side = storage_pane(vm, storage);
+        side.extend(output_pane(vm, bottom - storage));
+        rows.extend(beside(self.memory_pane(vm, bottom, left), side, left));
+        rows.push(vec![span(self.message.clone(), "")]);
+
+        let mut screen = String::from("\x1b[H");
+        for (i, row) in rows.into_iter().enumerate() {
+            if i > 0 {
+                screen += "\r\n";
+            }
+            screen += &render(&fit(row, width));
+        }
+        let mut out = io::stdout().lock();
+        out.write_all(screen.as_bytes())?;
+        out.flush()
+    }
+
+    /// pcの周りを逆アセンブルする
+    fn disassembly(&self, vm: &VirtualMachine, height: usize) -> Vec<Row> {
+        let starts = instruction_starts(vm.memory(), vm.pc());
+        let index = starts.partition_point(|a| *a < self.cursor);
+        let first = index.saturating_sub(height.saturating_sub(1) / 2);
+        let debug = vm.debug_info();
+        let lines = starts.iter().skip(first).map(|&address| {
+            let label = (debug.labels.iter())
+                .find(|(_, a)| *a == address)
+                .map_or(String::new(), |(name, _)| format!("{name}:"));
+            let instruction = match Instruction::decode(vm.memory(), address) {
+                Some((instruction, _)) => instruction.to_string(),
+                None => format!(".word {}", vm.memory()[address]),
+            };
+            let style = if address == vm.pc() {
+                SELECTED
+            } else if address == self.cursor {
+                CURSOR
+            } else {
+                ""
+            };
+            let marker = if self.breakpoints.contains(&address) {
+                span("●", BREAKPOINT)
+            } else {
+                span(" ", "")
+            };
+            let arrow = if address == vm.pc() { "▶" } else { " " };
+            let source = debug.location(address).map_or(String::new(), |location| {
+                format!("  {}", msg!("tui.line", location.line))
+            });
+            vec![
+                marker,
+                span(
+                    format!("{arrow}{address:>4} {label:<10} {instruction:<12}"),
+                    style,
+                ),
+                span(source, DIM),
+            ]
+        });
+        pane(&msg!("tui.pane.disassembly"), lines.collect(), height)
+    }
+
+    /// メモリを表示する (最近書き込まれたセルには色を付ける)
+    fn memory_pane(&self, vm: &VirtualMachine, height: usize, width: usize) -> Vec<Row> {
+        let cell_width = if self.hex { 9 } else { 7 };
+        let per_row = (width.saturating_sub(6) / cell_width).max(1);
+        let total_rows = vm.memory().len().div_ceil(per_row);
+        let first = self.memory_row.min(total_rows.saturating_sub(1));
+        let rows = (first..total_rows).map(|row| {
+            let start = row * per_row;
+            let end = (start + per_row).min(vm.memory().len());
+            let mut line = vec![span(format!("{start:>4}: "), DIM)];
+            for address in start..end {
+                let value = vm.memory()[address];
+                let text = if self.hex {
+                    format!("{:>8x} ", value)
+                } else {
+                    format!("{value:>6} ")
+                };
+                let style = match self.written.get(&address) {
+                    Some(step) if *step == self.steps => WRITTEN,
+                    Some(step) if self.steps.saturating_sub(*step) < RECENT => RECENTLY_WRITTEN,
+                    _ => "",
+                };
+                line.push(span(text, style));
+            }
+            line
+        });
+        let title = if self.hex {
+            msg!("tui.pane.memory_hex")
+        } else {
+            msg!("tui.pane.memory_dec")
+        };
+        pane(&title, rows.collect(), height)
+    }
+}
+
+/// スタックを上から表示する
+fn stack_pane(vm: &VirtualMachine, height: usize) -> Vec<Row> {
+    let rows = (vm.stack().iter().enumerate().rev())
+        .map(|(i, value)| vec![span(format!("{i:>3}: {value}"), "")]);
+    pane(&msg!("tui.pane.stack"), rows.collect(), height)
+}
+
+/// ストレージの内容を表示する (直前に書き込んだ行には色を付ける)
+fn storage_pane(vm: &VirtualMachine, height: usize) -> Vec<Row> {
+    let text = crate::io::read_all(vm.storage()).unwrap_or_default();
+    let rows = text.lines().enumerate().map(|(i, line)| {
+        let style = if vm.writes().contains(&Access::Storage(i + 1)) {
+            WRITTEN
+        } else {
+            ""
+        };
+        vec![span(format!("{:>3}: {line}", i + 1), style)]
+    });
+    pane(&msg!("tui.pane.storage"), rows.collect(), height)
+}
+
+/// 出力した文字列の最後の部分を表示する
+fn output_pane(vm: &VirtualMachine, height: usize) -> Vec<Row> {
+    let lines: Vec<&str> = vm.output().split('\n').collect();
+    let skip = lines.len().saturating_sub(height.saturating_sub(1));
+    let rows = lines[skip..].iter().map(|line| vec![span(*line, "")]);
+    pane(&msg!("tui.pane.output"), rows.collect(), height)
+}
+
+/// 見出しを付けて高さを揃える
+fn pane(title: &str, lines: Vec<Row>, height: usize) -> Vec<Row> {
+    let mut rows = vec![vec![span(format!("─ {title} "), TITLE)]];
+    rows.extend(lines.into_iter().take(height.saturating_sub(1)));
+    rows.resize_with(height, Vec::new);
+    rows
+}
+
+/// 2つの領域を左右に並べる
+fn beside(left: Vec<Row>, right: Vec<Row>, width: usize) -> Vec<Row> {
+    left.into_iter()
+        .zip(right)
+        .map(|(left, right)| {
+            let mut row = fit(left, width);
+            row.push(span("│", DIM));
+            row.extend(right);
+            row
+        })
+        .collect()
+}
+
+/// 行を指定した幅に切り詰めるか空白で埋める
+fn fit(row: Row, width: usize) -> Row {
+    let mut fitted = Vec::new();
+    let mut used = 0;
+    let mut full = false;
+    for span in row {
+        let mut text = String::new();
+        for c in span.text.chars() {
+            if full || used + char_width(c) > width {
+                full = true;
+                break;
+            }
+            used += char_width(c);
+            text.push(c);
+        }
+        fitted.push(Span { text, ..span });
+    }
+    fitted.push(span(" ".repeat(width - used), ""));
+    fitted
+}
+
+/// 色の指定を付けて1行の文字列にする
+fn render(row: &Row) -> String {
+    let mut text = String::new();
+    for span in row {
+        if span.style.is_empty() {
+            text += &span.text;
+        } else {
+            text += &format!("{}{}{RESET}", span.style, span.text);
+        }
+    }
+    text
+}
+
+/// 1文字の表示幅 (全角文字は2)
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x1100..=0x115f
+        | 0x2e80..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1faff
+        | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+/// 命令の先頭のアドレスを並べる (pcは必ず命令の先頭として扱う)
+fn instruction_starts(memory: &[i32], pc: usize) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut address = 0;
+    while address < memory.len() {
+        starts.push(address);
+        let size = Instruction::decode(memory, address).map_or(1, |(_, size)| size);
+        address = if address < pc && pc < address + size {
+            pc
+        } else {
+            address + size
+        };
+    }
+    starts
+}
+
+/// キーを1つ読む (入力が終わったらNone)
+fn read_key() -> io::Result<Option<Key>> {
+    let mut stdin = io::stdin().lock();
+    let mut byte = [0];
+    if stdin.read(&mut byte)? == 0 {
+        return Ok(None);
+    }
+    let key = match byte[0] {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        // Ctrl-Cは終了にする
+        0x03 => Key::Char('q'),
+        0x1b => {
+            let mut sequence = [0; 2];
+            stdin.read_exact(&mut sequence)?;
+            match &sequence {
+                b"[A" => Key::Up,
+                b"[B" => Key::Down,
+                b"[5" | b"[6" => {
+                    stdin.read_exact(&mut byte)?;
+                    if sequence[1] == b'5' {
+                        Key::PageUp
+                    } else {
+                        Key::PageDown
+                    }
+                }
+                _ => Key::Other,
+            }
+        }
+        first => {
+            // UTF-8の残りのバイトを読む
+            let length = match first {
+                0xf0.. => 4,
+                0xe0.. => 3,
+                0xc0.. => 2,
+                _ => 1,
+            };
+            let mut bytes = vec![first; length];
+            stdin.read_exact(&mut bytes[1..])?;
+            match std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Other,
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+/// 全画面で1文字ずつ入力できる状態の端末 (Dropで元に戻す)
+struct Terminal {
+    saved: Option<console::Saved>, // 元の端末の設定
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Terminal> {
+        let saved = console::raw();
+        let mut out = io::stdout().lock();
+        out.write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        out.flush()?;
+        Ok(Terminal { saved })
+    }
+
+    fn size(&self) -> (usize, usize) {
+        console::size().unwrap_or(DEFAULT_SIZE)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("{RESET}\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        if let Some(saved) = self.saved.take() {
+            console::restore(saved);
+        }
+    }
+}
+
+#[cfg(windows)]
+mod console {
+    use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
+    use winapi::um::processenv::GetStdHandle;
+    use winapi::um::winbase::{STD_INPUT_HANDLE, STD_OUTPUT_HANDLE};
+    use winapi::um::wincon::{
+        GetConsoleScreenBufferInfo, CONSOLE_SCREEN_BUFFER_INFO, ENABLE_ECHO_INPUT,
+        ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT, ENABLE_VIRTUAL_TERMINAL_INPUT,
+        ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+    };
+
+    /// 入力と出力の元のコンソールモード
+    pub type Saved = (u32, u32);
+
+    /// 1文字ずつ読めてエスケープシーケンスが使えるようにする
+    pub fn raw() -> Option<Saved> {
+        unsafe {
+            let input = GetStdHandle(STD_INPUT_HANDLE);
+            let output = GetStdHandle(STD_OUTPUT_HANDLE);
+            let (mut input_mode, mut output_mode) = (0, 0);
+            if GetConsoleMode(input, &mut input_mode) == 0
+                || GetConsoleMode(output, &mut output_mode) == 0
+            {
+                return None;
+            }
+            let raw =
+                input_mode & !(ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT);
+            SetConsoleMode(input, raw | ENABLE_VIRTUAL_TERMINAL_INPUT);
+            SetConsoleMode(output, output_mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING);
+            Some((input_mode, output_mode))
+        }
+    }
+
+    pub fn restore((input_mode, output_mode): Saved) {
+        unsafe {
+            SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), input_mode);
+            SetConsoleMode(GetStdHandle(STD_OUTPUT_HANDLE), output_mode);
+        }
+    }
+
+    /// 画面の幅と高さ
+    pub fn size() -> Option<(usize, usize)> {
+        unsafe {
+            let mut info: CONSOLE_SCREEN_BUFFER_INFO = std::mem::zeroed();
+            if GetConsoleScreenBufferInfo(GetStdHandle(STD_OUTPUT_HANDLE), &mut info) == 0 {
+                return None;
+            }
+            let window = info.srWindow;
+            Some((
+                (window.Right - window.Left + 1) as usize,
+                (window.Bottom - window.Top + 1) as usize,
+            ))
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod console {
+    use std::process::{Command, Stdio};
+
+    /// sttyで保存した元の端末の設定
+    pub type Saved = String;
+
+    fn stty(args: &[&str]) -> Option<String> {
+        let output = Command::new("stty")
+            .args(args)
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()?;
+        let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        output.status.success().then_some(text)
+    }
+
+    pub fn raw() -> Option<Saved> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Some(saved)
+    }
+
+    pub fn restore(saved: Saved) {
+        stty(&[&saved]);
+    }
+
+    /// 画面の幅と高さ
+    pub fn size() -> Option<(usize, usize)> {
+        let size = stty(&["size"])?;
+        let (rows, columns) = size.split_once(' ')?;
+        Some((columns.parse().ok()?, rows.parse().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod test_tui {
+    use crate::tui::{fit, instruction_starts, render, span};
+
+    #[test]
+    fn test_fit_wide_characters() {
+        let row = || vec![span("メモリ", "\x1b[1m"), span("ab", "")];
+        assert_eq!(render(&fit(row(), 7)), "\x1b[1mメモリ\x1b[0ma");
+        assert_eq!(render(&fit(row(), 5)), "\x1b[1mメモ\x1b[0m ");
+        assert_eq!(render(&fit(row(), 10)), "\x1b[1mメモリ\x1b[0mab  ");
+    }
+
+    #[test]
+    fn test_instruction_starts() {
+        // push 5 / add / push 7
+        let memory = [6, 5, 1, 6, 7];
+        assert_eq!(instruction_starts(&memory, 0), vec![0, 2, 3]);
+        // pcが命令の途中を指していてもそこを先頭にする
+        assert_eq!(instruction_starts(&memory, 1), vec![0, 1, 2, 3]);
+    }
+}
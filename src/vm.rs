@@ -1,36 +1,113 @@
+use std::collections::VecDeque;
 use std::ffi::CString;
-use std::{fs::File, io::Read};
+use std::fs::File;
+use std::sync::mpsc::Receiver;
 use winapi::um::winuser::{MessageBoxA, MB_OK};
 
-use crate::assembly;
+use crate::assembly::{parse_string, quote_string};
+use crate::debug_info::DebugInfo;
+use crate::device::{Bus, Device, Host};
+use crate::filesystem::Disk;
 use crate::instruction::Instruction;
+use crate::interrupt::{self, Controller};
 use crate::io;
+use crate::msg;
+use crate::object::{self, Object};
+use crate::process::{self, Scheduler};
+use crate::replay::{Entry, Replay};
+use crate::trace::{Event, Step, Tracer};
+
+/// スナップショットの先頭に書くマジックナンバー
+const SNAPSHOT: &str = "SIMPLE-SNAPSHOT 1";
+
+/// プロセスが続けて実行できる命令の数の既定値
+pub const DEFAULT_QUANTUM: u64 = 100;
 
 /// 実行モード
 #[derive(Debug, Clone, Copy)]
 pub enum Mode {
     Execute,
     Debug,
+    Embedded, // 外部から操作する (入出力は標準入出力を使わない)
+}
+
+/// 実行状態
+#[derive(Debug, Clone, PartialEq)]
+pub enum State {
+    Running,       // 実行中
+    Halted,        // 終了した
+    Fault(String), // エラーで停止した
+}
+
+/// 命令が書き込んだ場所
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Memory(usize),  // メモリの番地
+    Storage(usize), // ストレージの行
+}
+
+/// 命令を取り消すための変更の記録
+#[derive(Debug, Clone)]
+enum Change {
+    Push,                                      // スタックに積んだ
+    Pop(i32),                                  // スタックから取り出した値
+    Memory(usize, i32),                        // 書き換える前のメモリの値
+    Resize(usize),                             // 広げる前のメモリの大きさ
+    Storage(usize, String),                    // 書き換える前のストレージの行
+    Output(usize),                             // 出力する前の出力の長さ
+    Process(Box<Option<Scheduler>>, Vec<i32>), // プロセスを操作する前のスケジューラとスタック
+    Device(&'static str),                      // 取り消せない装置への書き込み (装置の名前)
+}
+
+/// 1命令分の実行の記録
+#[derive(Debug, Clone)]
+struct Record {
+    pc: usize,                      // 実行前のプログラムカウンタ
+    address: usize,                 // 実行前の命令のアドレス
+    state: State,                   // 実行前の実行状態
+    interrupts: Controller,         // 実行前の割り込みの状態
+    devices: Vec<Option<Vec<i64>>>, // 実行前の装置の状態
+    changes: Vec<Change>,           // 命令による変更
 }
 
 /// 仮想マシン
 pub struct VirtualMachine {
-    memory: Vec<i32>, // メモリ内部
-    stack: Vec<i32>,  // スタック
-    storage: File,    // 補助記憶装置
-    pc: usize,        // プログラムカウンタ
-    mode: Mode,       // 実行モード
-    output: String,   // 出力した文字列
+    memory: Vec<i32>,                           // メモリ内部
+    stack: Vec<i32>,                            // スタック
+    storage: File,                              // 補助記憶装置
+    pc: usize,                                  // プログラムカウンタ
+    mode: Mode,                                 // 実行モード
+    output: String,                             // 出力した文字列
+    address: usize,                             // 実行中の命令のアドレス
+    debug: DebugInfo,                           // ソースとの対応
+    state: State,                               // 実行状態
+    writes: Vec<Access>,                        // 直前の命令が書き込んだ場所
+    history: VecDeque<Record>,                  // 取り消すための実行の履歴
+    history_size: usize,                        // 履歴に残す命令の数
+    trace: Option<Tracer>,                      // 実行のトレースの出力先
+    events: Vec<Event>,                         // 直前の命令の書き込みと入出力
+    input: VecDeque<String>,                    // 外部から操作するときの入力
+    scripted: bool,                             // 入力をファイルなどから与えたか
+    steps: u64,                                 // 実行した命令の数
+    max_steps: Option<u64>,                     // 実行できる命令の数の上限
+    pending: VecDeque<char>,                    // 読みかけの入力の行の残り (改行を含む)
+    end_of_input: bool,                         // 入力が終わったか
+    replay: Option<Replay>,                     // 入力とホスト呼び出しの結果の記録か再現
+    bus: Bus,                                   // 番地に割り当てた装置
+    interrupts: Controller,                     // 割り込みコントローラ
+    keyboard: Option<Receiver<Option<String>>>, // 別のスレッドで読んだ標準入力
+    announced: bool,                            // 読んでいない入力の行を割り込みで知らせたか
+    disk: Option<Disk>,                         // システムコールで使うディスク
+    scheduler: Option<Scheduler>, // プロセスのスケジューラ (最初にspawnするまではNone)
+    quantum: u64,                 // プロセスが続けて実行できる命令の数
 }
 
 impl VirtualMachine {
-    pub fn new(mut storage: File, mode: Mode) -> VirtualMachine {
-        let mut code = String::new();
-        let _ = storage.read_to_string(&mut code);
-        let memory = assembly::assembly(code.to_string());
+    pub fn new(storage: File, program: Object, mode: Mode) -> VirtualMachine {
+        let memory = program.code;
         let mut vm = VirtualMachine {
             memory: {
-                let mut temp = vec![0; 512];
+                let mut temp = vec![0; memory.len().max(512)];
                 for i in 0..memory.clone().len() {
                     temp[i] = memory[i];
                 }
@@ -41,6 +118,28 @@ impl VirtualMachine {
             pc: 0,
             mode,
             output: String::new(),
+            address: 0,
+            debug: program.debug,
+            state: State::Running,
+            writes: Vec::new(),
+            history: VecDeque::new(),
+            history_size: 0,
+            trace: None,
+            events: Vec::new(),
+            input: VecDeque::new(),
+            scripted: false,
+            steps: 0,
+            max_steps: None,
+            pending: VecDeque::new(),
+            end_of_input: false,
+            replay: None,
+            bus: Bus::default(),
+            interrupts: Controller::default(),
+            keyboard: None,
+            announced: false,
+            disk: None,
+            scheduler: None,
+            quantum: DEFAULT_QUANTUM,
         };
 
         for i in 0..memory.len() {
@@ -50,6 +149,113 @@ impl VirtualMachine {
         return vm;
     }
 
+    /// 実行中の状態をスナップショットの形式に変換する
+    pub fn snapshot(&self) -> Result<String, String> {
+        if self.scheduler.is_some() {
+            return Err(msg!("vm.snapshot.processes"));
+        }
+        // 装置の状態は保存できないので、装置を割り当てている間は作らない
+        if let Some((name, _, _)) = self.bus.devices().first() {
+            return Err(msg!("vm.snapshot.devices", name));
+        }
+        let storage = io::read_all(&self.storage).map_err(|e| msg!("vm.storage_read_failed", e))?;
+        let mut text = format!("{SNAPSHOT}\n");
+        text += &format!("pc {}\n", self.pc);
+        text += &format!("address {}\n", self.address);
+        text += match self.mode {
+            Mode::Execute => "mode execute\n",
+            Mode::Debug => "mode debug\n",
+            Mode::Embedded => "mode embedded\n",
+        };
+        text += &match &self.state {
+            State::Running => "state running\n".to_string(),
+            State::Halted => "state halted\n".to_string(),
+            State::Fault(message) => format!("state fault {}\n", quote_string(message)),
+        };
+        let stack: Vec<String> = self.stack.iter().map(|value| value.to_string()).collect();
+        text += &format!("stack {}\n", stack.join(" "));
+        if self.interrupts != Controller::default() {
+            text += &format!("interrupts {}\n", self.interrupts.to_text());
+        }
+        text += &format!("output {}\n", quote_string(&self.output));
+        for line in storage.lines() {
+            text += &format!("storage {}\n", quote_string(line));
+        }
+        // メモリとデバッグ情報はオブジェクトファイルの形式で書く
+        let image = Object {
+            code: self.memory.clone(),
+            debug: self.debug.clone(),
+            ..Object::default()
+        };
+        Ok(text + &image.to_text())
+    }
+
+    /// スナップショットから仮想マシンを復元する (ストレージの内容も書き戻す)
+    pub fn restore(text: &str, storage: File) -> Result<VirtualMachine, String> {
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some(SNAPSHOT) {
+            return Err(msg!("vm.snapshot.invalid"));
+        }
+        let start = text
+            .find(&format!("\n{}", object::MAGIC))
+            .ok_or(msg!("vm.snapshot.no_memory"))?;
+        let image = Object::parse(&text[start + 1..])?;
+
+        let mut vm = VirtualMachine::new(storage, Object::default(), Mode::Debug);
+        vm.memory = image.code;
+        vm.debug = image.debug;
+        let mut storage = String::new();
+        for (i, line) in lines.enumerate() {
+            if line.trim() == object::MAGIC {
+                break;
+            }
+            let error = || msg!("vm.snapshot.bad_record", i + 2, line);
+            let number = |value: &str| value.parse::<usize>().map_err(|_| error());
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            match (name, value) {
+                ("pc", value) => vm.pc = number(value)?,
+                ("address", value) => vm.address = number(value)?,
+                ("mode", "execute") => vm.mode = Mode::Execute,
+                ("mode", "debug") => vm.mode = Mode::Debug,
+                ("mode", "embedded") => vm.mode = Mode::Embedded,
+                ("state", "running") => vm.state = State::Running,
+                ("state", "halted") => vm.state = State::Halted,
+                ("state", value) => match value.strip_prefix("fault ") {
+                    Some(message) => vm.state = State::Fault(parse_string(message)?),
+                    None => return Err(error()),
+                },
+                ("stack", values) => {
+                    for value in values.split_whitespace() {
+                        vm.stack.push(value.parse().map_err(|_| error())?);
+                    }
+                }
+                ("interrupts", value) => {
+                    vm.interrupts = Controller::parse(value).ok_or_else(error)?;
+                }
+                ("output", value) => vm.output = parse_string(value)?,
+                ("storage", value) => storage += &(parse_string(value)? + "\n"),
+                _ => return Err(error()),
+            }
+        }
+        io::write_all(&vm.storage, &storage).map_err(|e| msg!("vm.storage_write_failed", e))?;
+        Ok(vm)
+    }
+
+    /// 復元した仮想マシンに、スナップショットに含まれない実行の環境を引き継ぐ
+    /// (装置・ディスク・入力の記録と再現・入力・命令の数の上限・履歴の長さ・トレース)
+    pub fn inherit(&mut self, old: &mut VirtualMachine) {
+        self.bus = std::mem::take(&mut old.bus);
+        self.disk = old.disk.take();
+        self.replay = old.replay.take();
+        self.input = std::mem::take(&mut old.input);
+        self.scripted = old.scripted;
+        self.keyboard = old.keyboard.take();
+        self.max_steps = old.max_steps;
+        self.quantum = old.quantum;
+        self.set_history_size(old.history_size);
+        self.trace = old.trace.take();
+    }
+
     /// ログ出力
     fn log_print(&mut self, text: String) {
         if let Mode::Debug = self.mode {
@@ -57,281 +263,1177 @@ impl VirtualMachine {
         }
     }
 
-    /// デバッグメニューを表示する
-    fn debug_menu(&mut self) {
-        loop {
-            let menu = io::input("デバッグメニュー>>> ");
-            if menu.contains("s") {
-                println!("スタック {:?}", self.stack);
-            } else if menu.contains("m") {
-                println!("+-- メモリ内部");
-                for i in 0..self.memory.len() {
-                    if self.memory[i] != 0 {
-                        println!("| {i:0>3} :  {}", self.memory[i]);
-                    }
-                }
-            } else if menu.contains("o") {
-                println!("+-- 標準出力");
-                for i in self.output.split("\n").collect::<Vec<&str>>() {
-                    println!("| {i}");
-                }
-            } else if menu.contains("exit") {
-                io::input("デバッグを中断します");
-                std::process::exit(0)
-            } else {
-                println!("継続します");
-                break;
+    /// プログラムカウンタ
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// 実行中(最後に実行した)命令のアドレス
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// スタック
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    /// メモリ内部 (プロセスがあれば実行中のプロセスの領域)
+    pub fn memory(&self) -> &[i32] {
+        self.region()
+    }
+
+    /// 実行中のプロセスのメモリの領域 (先頭の物理番地と大きさ)
+    fn region_bounds(&self) -> (usize, usize) {
+        match &self.scheduler {
+            Some(scheduler) => (scheduler.current().base, scheduler.current().size),
+            None => (0, self.memory.len()),
+        }
+    }
+
+    /// 実行中のプロセスのメモリの領域
+    fn region(&self) -> &[i32] {
+        let (base, size) = self.region_bounds();
+        &self.memory[base..base + size]
+    }
+
+    /// 補助記憶装置
+    pub fn storage(&self) -> &File {
+        &self.storage
+    }
+
+    /// 出力した文字列
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// ソースとの対応
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug
+    }
+
+    /// 実行状態
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// 直前の命令が書き込んだ場所
+    pub fn writes(&self) -> &[Access] {
+        &self.writes
+    }
+
+    /// メモリの値を書き換える
+    pub fn set_memory(&mut self, address: usize, value: i32) -> Result<(), String> {
+        let (base, size) = self.region_bounds();
+        match (self.memory.get_mut(base + address)).filter(|_| address < size) {
+            Some(cell) => {
+                *cell = value;
+                Ok(())
+            }
+            None => Err(msg!("vm.fault.memory_out_of_range", address)),
+        }
+    }
+
+    /// 外部から操作するときの入力を追加する
+    pub fn push_input(&mut self, line: String) {
+        self.input.push_back(line);
+    }
+
+    /// 入力命令に渡す行を与える (使い切った後は入力の終わりとして扱う)
+    pub fn set_input(&mut self, lines: Vec<String>) {
+        self.input = lines.into();
+        self.scripted = true;
+    }
+
+    /// 入力とホスト呼び出しの結果を記録するか、記録したものを再現する
+    pub fn set_replay(&mut self, replay: Replay) {
+        self.replay = Some(replay);
+    }
+
+    /// システムコールで使うディスクをつなぐ
+    pub fn set_disk(&mut self, disk: Disk) {
+        self.disk = Some(disk);
+    }
+
+    /// 装置を番地に割り当てる (loadとstoreでその番地を使うと装置を操作する)
+    pub fn map_device(&mut self, base: usize, device: Box<dyn Device>) -> Result<(), String> {
+        self.bus.map(base, device)
+    }
+
+    /// 番地に割り当てた装置
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    /// 割り込みコントローラ
+    pub fn interrupts(&self) -> &Controller {
+        &self.interrupts
+    }
+
+    /// プロセスのスケジューラ (プロセスを作っていなければNone)
+    pub fn scheduler(&self) -> Option<&Scheduler> {
+        self.scheduler.as_ref()
+    }
+
+    /// プロセスが続けて実行できる命令の数を決める (0なら自分から譲るまで切り替えない)
+    pub fn set_quantum(&mut self, quantum: u64) {
+        self.quantum = quantum;
+    }
+
+    /// 次の命令が入力を読むのに新しい行が要るか判断する (入力を受け付ける画面で使う)
+    pub fn needs_input(&self) -> bool {
+        let reads = matches!(
+            Instruction::decode(self.region(), self.pc),
+            Some((
+                Instruction::Input
+                    | Instruction::InputChar
+                    | Instruction::InputString
+                    | Instruction::EndOfInput,
+                _
+            ))
+        );
+        reads && self.pending.is_empty() && !self.end_of_input
+    }
+
+    /// メモリの大きさを変える
+    pub fn set_memory_size(&mut self, size: usize) {
+        self.memory.resize(size, 0);
+    }
+
+    /// 実行できる命令の数の上限を決める
+    pub fn set_max_steps(&mut self, max_steps: Option<u64>) {
+        self.max_steps = max_steps;
+    }
+
+    /// プログラムカウンタを書き換える
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// スタックの値を書き換える (0が一番下)
+    pub fn set_stack(&mut self, index: usize, value: i32) -> Result<(), String> {
+        match self.stack.get_mut(index) {
+            Some(cell) => {
+                *cell = value;
+                Ok(())
             }
+            None => Err(msg!("vm.stack_index_missing", index)),
         }
     }
 
-    fn execute(&mut self, instruction: Instruction) {
+    fn execute(&mut self, instruction: Instruction) -> Result<(), String> {
         match instruction {
             Instruction::Nop => {}
             Instruction::Add => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{a}と{b}を足します"));
-                self.stack.push(a + b);
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.add", a, b));
+                self.push(a + b);
             }
             Instruction::Sub => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{a}から{b}を引きます"));
-                self.stack.push(a - b);
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.sub", a, b));
+                self.push(a - b);
             }
             Instruction::Mul => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{a}と{b}を掛けます"));
-                self.stack.push(a * b);
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.mul", a, b));
+                self.push(a * b);
             }
             Instruction::Div => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{a}を{b}で割ります"));
-                self.stack.push(a / b);
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.div", a, b));
+                if b == 0 {
+                    return Err(msg!("vm.fault.divide_by_zero"));
+                }
+                self.push(a / b);
             }
             Instruction::Mod => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{a}÷{b}の余りを求めます"));
-                self.stack.push(a % b);
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.mod", a, b));
+                if b == 0 {
+                    return Err(msg!("vm.fault.divide_by_zero"));
+                }
+                self.push(a % b);
             }
             Instruction::Push(value) => {
-                self.log_print(format!("{value}をスタックに追加します"));
-                self.stack.push(value)
+                self.log_print(msg!("vm.log.push", value));
+                self.push(value)
             }
             Instruction::Pop => {
-                self.log_print(format!("スタックから値を削除します"));
-                let _ = self.pop();
+                self.log_print(msg!("vm.log.pop"));
+                self.pop()?;
             }
             Instruction::Equal => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{}と{}が等しいかを判断します", a, b));
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.equal", a, b));
                 let result = a == b;
                 if result {
-                    self.log_print(format!("条件が一致したので1を返します"));
-                    self.stack.push(1);
+                    self.log_print(msg!("vm.log.true"));
+                    self.push(1);
                 } else {
-                    self.log_print(format!("条件が一致なかったので0を返します"));
-                    self.stack.push(0);
+                    self.log_print(msg!("vm.log.false"));
+                    self.push(0);
                 }
             }
             Instruction::LessThan => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{}が{}が未満かを判断します", a, b));
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.lessthan", a, b));
                 let result = a < b;
                 if result {
-                    self.log_print(format!("条件が一致したので1を返します"));
-                    self.stack.push(1);
+                    self.log_print(msg!("vm.log.true"));
+                    self.push(1);
                 } else {
-                    self.log_print(format!("条件が一致なかったので0を返します"));
-                    self.stack.push(0);
+                    self.log_print(msg!("vm.log.false"));
+                    self.push(0);
                 }
             }
             Instruction::And => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{}と{}でAND条件が成立するかを判断します", a, b));
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.and", a, b));
                 let result = a != 0 && b != 0;
                 if result {
-                    self.log_print(format!("条件が一致したので1を返します"));
-                    self.stack.push(1);
+                    self.log_print(msg!("vm.log.true"));
+                    self.push(1);
                 } else {
-                    self.log_print(format!("条件が一致なかったので0を返します"));
-                    self.stack.push(0);
+                    self.log_print(msg!("vm.log.false"));
+                    self.push(0);
                 }
             }
             Instruction::Or => {
-                let b = self.pop();
-                let a = self.pop();
-                self.log_print(format!("{}と{}でOR条件が成立するかを判断します", a, b));
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.log_print(msg!("vm.log.or", a, b));
                 let result = a != 0 || b != 0;
                 if result {
-                    self.log_print(format!("条件が一致したので1を返します"));
-                    self.stack.push(1);
+                    self.log_print(msg!("vm.log.true"));
+                    self.push(1);
                 } else {
-                    self.log_print(format!("条件が一致なかったので0を返します"));
-                    self.stack.push(0);
+                    self.log_print(msg!("vm.log.false"));
+                    self.push(0);
                 }
             }
             Instruction::Not => {
-                let b = self.pop();
-                self.log_print(format!("{}の値を否定します", b));
-                self.stack.push(!b);
+                let b = self.pop()?;
+                self.log_print(msg!("vm.log.not", b));
+                self.push(!b);
             }
             Instruction::JumpIfZero => {
-                let condition = self.pop();
-                let target = self.pop();
+                let condition = self.pop()?;
+                let target = self.pop()?;
                 if condition == 0 {
-                    self.log_print(format!("値が0に一致したので{target}行目にジャンプします"));
+                    self.log_print(msg!("vm.log.jump", target));
                     self.pc = target as usize;
                 } else {
-                    self.log_print("値が0にが一致しなかったのでジャンプしません".to_string());
+                    self.log_print(msg!("vm.log.no_jump"));
                 }
             }
             Instruction::Load => {
-                let index = self.pop();
-                self.log_print(format!("メモリ{index}番地の値を読み込みます"));
-                let value = self.memory[index as usize];
-                self.stack.push(value);
+                let index = self.pop()?;
+                self.log_print(msg!("vm.log.load", index));
+                if self.bus.contains(index as usize) {
+                    let value = self.with_bus(|bus, vm| bus.read(index as usize, vm))?;
+                    self.push(value);
+                    return Ok(());
+                }
+                match self.region().get(index as usize).copied() {
+                    Some(value) => self.push(value),
+                    None => return Err(msg!("vm.fault.memory_out_of_range", index)),
+                }
             }
             Instruction::Store => {
-                let index = self.pop();
-                let value = self.pop();
-                self.log_print(format!("メモリ{index}番地に{value}を書き込みます"));
-                self.memory[index as usize] = value;
+                let index = self.pop()?;
+                let value = self.pop()?;
+                self.log_print(msg!("vm.log.store", index, value));
+                if self.bus.contains(index as usize) {
+                    if let Some(name) = self.bus.irreversible(index as usize) {
+                        self.record(Change::Device(name));
+                    }
+                    self.with_bus(|bus, vm| bus.write(index as usize, value, vm))?;
+                    return Ok(());
+                }
+                self.store(index, value)?;
             }
             Instruction::Input => {
-                self.log_print(format!("入力を受け付けます"));
-                if let Mode::Execute = self.mode {
-                    self.stack.push(io::input("> ").parse().unwrap_or(0));
+                self.log_print(msg!("vm.log.input"));
+                // 1文字ずつ読んだ行の残りがあればそれを使う
+                let line = if !self.pending.is_empty() {
+                    let line: String = self.pending.drain(..).collect();
+                    line.trim_end_matches('\n').to_string()
                 } else {
-                    self.stack.push(io::input("[入力]> ").parse().unwrap_or(0));
-                }
+                    match self.read_input("> ")? {
+                        Some(line) => line,
+                        None if matches!(self.mode, Mode::Embedded) => {
+                            return Err(msg!("vm.fault.no_input"));
+                        }
+                        None => {
+                            self.end_of_input = true;
+                            String::new()
+                        }
+                    }
+                };
+                let value = line.trim().parse().unwrap_or(0);
+                self.events.push(Event::Input(value));
+                self.push(value);
             }
             Instruction::Output => {
-                let value = self.pop();
-                self.log_print(format!("{value}をUTF-8の文字として出力します"));
-                if let Some(c) = std::char::from_u32(value as u32) {
-                    if let Mode::Debug = self.mode {
-                        println!("[出力]: {}", c);
-                        self.output.push(c);
-                    } else {
-                        print!("{c}")
-                    }
-                } else {
-                    panic!("Invalid UTF-8 character code");
-                }
+                let value = self.pop()?;
+                self.log_print(msg!("vm.log.output", value));
+                let c = std::char::from_u32(value as u32)
+                    .ok_or(msg!("vm.fault.invalid_char", value))?;
+                self.write_output(&c.to_string());
             }
             Instruction::Read => {
-                let index = self.pop();
-                self.log_print(format!("ストレージ{}行目の値を読み込みます", index));
-                self.stack.push(
-                    io::read_specific_line(&self.storage, index as usize)
-                        .unwrap()
-                        .parse()
-                        .unwrap_or(0),
-                );
+                let index = self.pop()?;
+                self.log_print(msg!("vm.log.read", index));
+                let line = self.storage_line(index)?;
+                let text = io::read_specific_line(&self.storage, line)
+                    .map_err(|e| msg!("vm.fault.storage", e))?;
+                self.push(text.parse().unwrap_or(0));
             }
             Instruction::Write => {
-                let index = self.pop();
-                let value = self.pop();
-                self.log_print(format!(
-                    "ストレージ{}行目に値{}を書き込みます",
-                    index, value
-                ));
-                let _ = io::write_specific_line(
-                    &self.storage,
-                    index as usize,
-                    value.to_string().as_str(),
-                );
+                let index = self.pop()?;
+                let value = self.pop()?;
+                self.log_print(msg!("vm.log.write", index, value));
+                let line = self.storage_line(index)?;
+                let old = io::read_specific_line(&self.storage, line)
+                    .map_err(|e| msg!("vm.fault.storage", e))?;
+                self.record(Change::Storage(line, old));
+                io::write_specific_line(&self.storage, line, value.to_string().as_str())
+                    .map_err(|e| msg!("vm.fault.storage", e))?;
+                self.writes.push(Access::Storage(line));
+                self.events.push(Event::Storage(line, value));
             }
             Instruction::Halt => {
-                self.log_print(format!("プログラムを終了します"));
-                std::process::exit(0);
+                self.log_print(msg!("vm.log.halt"));
+                self.state = State::Halted;
             }
             Instruction::WinAPI => {
-                match self.pop() {
+                match self.pop()? {
                     1 => unsafe {
-                        self.log_print(
-                            "WindowsAPIを呼び出してメッセージボックスを表示します".to_string(),
-                        );
+                        self.log_print(msg!("vm.log.message_box"));
                         let text = CString::new("Hello Windows API from Simple VM")
                             .expect("CString::new failed");
                         let caption =
                             CString::new("Simple VM MessageBox").expect("CString::new failed");
 
                         // `MessageBoxA`関数の呼び出し
-                        let number = MessageBoxA(
-                            std::ptr::null_mut(),
-                            text.as_ptr(),
-                            caption.as_ptr(),
-                            MB_OK,
-                        );
-                        self.stack.push(number);
+                        let number = self.host_call("winapi", &mut || {
+                            MessageBoxA(
+                                std::ptr::null_mut(),
+                                text.as_ptr(),
+                                caption.as_ptr(),
+                                MB_OK,
+                            )
+                        })?;
+                        self.push(number);
                     },
                     _ => {
-                        self.log_print("エラー! その番号のAPIはありません".to_string());
+                        self.log_print(msg!("vm.log.no_api"));
+                    }
+                }
+            }
+            Instruction::InputChar => {
+                self.log_print(msg!("vm.log.input_char"));
+                // 入力が終わっていれば-1を返す
+                let value = self.read_char()?;
+                self.events.push(Event::Input(value));
+                self.push(value);
+            }
+            Instruction::InputString => {
+                let address = self.pop()?;
+                self.log_print(msg!("vm.log.input_string", address));
+                if !self.fill_input()? {
+                    self.events.push(Event::Input(-1));
+                    self.push(-1);
+                    return Ok(());
+                }
+                // 改行は読み捨てて0で終わる文字列にする
+                let mut length = 0;
+                while let Some(c) = self.pending.pop_front().filter(|c| *c != '\n') {
+                    self.store(address + length, c as i32)?;
+                    length += 1;
+                }
+                self.store(address + length, 0)?;
+                self.events.push(Event::Input(length));
+                self.push(length);
+            }
+            Instruction::OutputNumber => {
+                let value = self.pop()?;
+                self.log_print(msg!("vm.log.output_number", value));
+                self.write_output(&value.to_string());
+            }
+            Instruction::OutputString => {
+                let address = self.pop()?;
+                self.log_print(msg!("vm.log.output_string", address));
+                let text = self.read_string(address)?;
+                self.write_output(&text);
+            }
+            Instruction::EndOfInput => {
+                self.log_print(msg!("vm.log.eof"));
+                // 次の行を先読みして終わりかどうかを確かめる
+                let value = !self.fill_input()? as i32;
+                self.push(value);
+            }
+            Instruction::EnableInterrupts => {
+                let table = self.pop()?;
+                self.log_print(msg!("vm.log.ei", table));
+                self.interrupts.enable(table as usize);
+            }
+            Instruction::DisableInterrupts => {
+                self.log_print(msg!("vm.log.di"));
+                self.interrupts.disable();
+            }
+            Instruction::ReturnFromInterrupt => {
+                self.pc = self.interrupts.ret()?;
+                self.log_print(msg!("vm.log.iret", self.pc));
+            }
+            Instruction::SystemCall => {
+                let number = self.pop()?;
+                self.log_print(msg!("vm.log.syscall", number));
+                // プロセスを切り替えたときは結果を積まない
+                if let Some(value) = self.system_call(number)? {
+                    self.push(value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// ストレージの行番号 (1から始まる) を確かめる
+    fn storage_line(&self, index: i32) -> Result<usize, String> {
+        let text = io::read_all(&self.storage).map_err(|e| msg!("vm.fault.storage", e))?;
+        match usize::try_from(index) {
+            Ok(line) if (1..=text.lines().count()).contains(&line) => Ok(line),
+            _ => Err(msg!("vm.fault.storage_out_of_range", index)),
+        }
+    }
+
+    /// メモリの0で終わる文字列を読む
+    fn read_string(&self, address: i32) -> Result<String, String> {
+        let mut text = String::new();
+        for index in address.. {
+            let value = (self.region().get(index as usize).copied())
+                .ok_or(msg!("vm.fault.memory_out_of_range", index))?;
+            if value == 0 {
+                break;
+            }
+            text.push(char::from_u32(value as u32).ok_or(msg!("vm.fault.invalid_char", value))?);
+        }
+        Ok(text)
+    }
+
+    /// システムコールを呼び出して結果を返す (引数は番号の前にプッシュした順に並ぶ)
+    /// (プロセスを切り替えたときは結果がないのでNone)
+    fn system_call(&mut self, number: i32) -> Result<Option<i32>, String> {
+        match number {
+            1..=8 => {
+                let mut disk = self.disk.take().ok_or(msg!("vm.fault.no_disk"))?;
+                let result = self.disk_call(&mut disk, number);
+                self.disk = Some(disk);
+                // ファイルの操作の失敗はプログラムに-1を返し、仮想マシンのエラーだけで停止する
+                Ok(Some(result?.unwrap_or_else(|message| {
+                    self.log_print(msg!("vm.log.disk_error", message));
+                    -1
+                })))
+            }
+            10..=14 => self.process_call(number),
+            _ => Err(msg!("vm.fault.unknown_syscall", number)),
+        }
+    }
+
+    /// プロセスのシステムコール (プロセスを切り替えたときはNone)
+    fn process_call(&mut self, number: i32) -> Result<Option<i32>, String> {
+        match number {
+            // spawn(開始番地, 引数) -> プロセスID
+            10 => {
+                let arg = self.pop()?;
+                let entry = self.pop()?;
+                let (base, size) = self.region_bounds();
+                if entry < 0 || entry as usize >= size {
+                    return Err(msg!("vm.fault.memory_out_of_range", entry));
+                }
+                self.record_processes();
+                let length = self.memory.len();
+                let scheduler =
+                    (self.scheduler).get_or_insert_with(|| Scheduler::new(length, self.quantum));
+                // 親の領域を写して子プロセスの領域にする
+                let start = scheduler.free_region().unwrap_or(length);
+                // 広げたメモリと上書きするセルは取り消せるように記録する
+                if start + size > length {
+                    self.record(Change::Resize(length));
+                    self.memory.resize(start + size, 0);
+                }
+                for address in start..(start + size).min(length) {
+                    self.record(Change::Memory(address, self.memory[address]));
+                }
+                self.memory.copy_within(base..base + size, start);
+                let scheduler = self.scheduler.as_mut().expect("scheduler");
+                let id = scheduler.spawn(start, size, entry as usize, vec![arg]);
+                self.log_print(msg!("vm.log.spawn", id, entry, start));
+                Ok(Some(id as i32))
+            }
+            // exit(終了コード)
+            11 => {
+                let code = self.pop()?;
+                self.exit_process(code)?;
+                Ok(None)
+            }
+            // wait(プロセスID) -> 終了コード
+            12 => {
+                let id = self.pop()?;
+                if self.interrupts.in_handler() {
+                    return Err(msg!("vm.fault.process_in_interrupt"));
+                }
+                if self.scheduler.is_none() {
+                    self.log_print(msg!("vm.log.process_error", msg!("process.not_found", id)));
+                    return Ok(Some(-1));
+                }
+                self.record_processes();
+                let scheduler = self.scheduler.as_mut().expect("scheduler");
+                match scheduler.wait(id as usize) {
+                    Ok(Some(code)) => Ok(Some(code)),
+                    // 待っている間は切り替え、終了コードは終わったプロセスが積む
+                    Ok(None) => {
+                        self.log_print(msg!("vm.log.wait", id));
+                        self.switch_process()?;
+                        Ok(None)
                     }
+                    Err(message) => {
+                        self.log_print(msg!("vm.log.process_error", message));
+                        Ok(Some(-1))
+                    }
+                }
+            }
+            // yield() -> 0
+            13 => {
+                if self.scheduler.is_none() || self.interrupts.in_handler() {
+                    return Ok(Some(0));
                 }
+                self.push(0);
+                self.record_processes();
+                self.log_print(msg!("vm.log.yield"));
+                self.switch_process()?;
+                Ok(None)
             }
+            // getpid() -> プロセスID
+            _ => Ok(Some(self.process_id() as i32)),
         }
     }
 
-    fn pop(&mut self) -> i32 {
-        self.stack.pop().expect("Stack underflow")
+    /// 実行中のプロセスのID
+    pub fn process_id(&self) -> usize {
+        match &self.scheduler {
+            Some(scheduler) => scheduler.current().id,
+            None => process::MAIN,
+        }
     }
 
-    pub fn run(&mut self) {
-        println!("プログラムを実行します");
-        while self.pc < self.memory.len() {
-            let instruction = self.memory[self.pc].clone();
-            let result = match instruction {
-                0 => Instruction::Nop,
-                1 => Instruction::Add,
-                2 => Instruction::Sub,
-                3 => Instruction::Mul,
-                4 => Instruction::Div,
-                5 => Instruction::Mod,
-                6 => {
-                    self.pc += 1;
-                    Instruction::Push(self.memory[self.pc])
-                }
-                7 => Instruction::Pop,
-                8 => Instruction::Equal,
-                9 => Instruction::LessThan,
-                10 => Instruction::And,
-                11 => Instruction::Or,
-                12 => Instruction::Not,
-                13 => Instruction::JumpIfZero,
-                14 => Instruction::Load,
-                15 => Instruction::Store,
-                16 => Instruction::Input,
-                17 => Instruction::Output,
-                18 => Instruction::Read,
-                19 => Instruction::Write,
-                20 => Instruction::Halt,
-                21 => Instruction::WinAPI,
-                _ => {
-                    self.pc += 1;
-                    self.log_print(format!("エラー! 命令コード{instruction}は定義されてません"));
-                    continue;
-                }
-            };
-            self.log_print(format!(
-                "メモリ{}番目の命令コード{}を実行します",
-                self.pc, instruction
-            ));
-            self.execute(result);
-
-            if let Mode::Debug = self.mode {
-                self.debug_menu();
+    /// 実行中のプロセスを終了する (プロセスを作っていなければ仮想マシンを止める)
+    fn exit_process(&mut self, code: i32) -> Result<(), String> {
+        if self.interrupts.in_handler() {
+            return Err(msg!("vm.fault.process_in_interrupt"));
+        }
+        self.log_print(msg!("vm.log.exit", self.process_id(), code));
+        if self.scheduler.is_none() {
+            self.state = State::Halted;
+            return Ok(());
+        }
+        self.record_processes();
+        self.scheduler.as_mut().expect("scheduler").exit(code);
+        self.switch_process()
+    }
+
+    /// 次に実行できるプロセスに切り替える (全て終了していれば仮想マシンを止める)
+    fn switch_process(&mut self) -> Result<(), String> {
+        let Some(scheduler) = &mut self.scheduler else {
+            return Ok(());
+        };
+        let from = scheduler.current().id;
+        match scheduler.switch(&mut self.pc, &mut self.stack)? {
+            Some(to) if to != from => self.log_print(msg!("vm.log.switch", from, to)),
+            Some(_) => {}
+            None => {
+                self.log_print(msg!("vm.log.all_exited"));
+                self.state = State::Halted;
             }
+        }
+        Ok(())
+    }
+
+    /// 量子を使い切ったかタイマー割り込みで、実行中のプロセスを切り替える
+    /// (割り込みの処理中は切り替えない)
+    fn schedule(&mut self) -> Result<(), String> {
+        if self.scheduler.is_none() {
+            return Ok(());
+        }
+        // 領域の外まで実行したプロセスは終了コード0で終了する
+        while self.is_running() && self.pc >= self.region().len() {
+            self.exit_process(0)?;
+        }
+        if !self.is_running() || self.interrupts.in_handler() {
+            return Ok(());
+        }
+        // 処理が登録されていないタイマー割り込みはプロセスの切り替えに使う
+        let (base, size) = self.region_bounds();
+        let region = &self.memory[base..base + size];
+        let preempt = self.interrupts.handler(interrupt::TIMER, region) == Ok(None)
+            && self.interrupts.take(interrupt::TIMER);
+        let scheduler = self.scheduler.as_ref().expect("scheduler");
+        if preempt || scheduler.expired() {
+            self.record_processes();
+            self.switch_process()?;
+        }
+        if let Some(scheduler) = &mut self.scheduler {
+            scheduler.tick();
+        }
+        Ok(())
+    }
+
+    /// プロセスを操作する前のスケジューラとスタックを履歴に記録する
+    fn record_processes(&mut self) {
+        if self.history.back().is_some() {
+            let change = Change::Process(Box::new(self.scheduler.clone()), self.stack.clone());
+            self.record(change);
+        }
+    }
+
+    /// ディスクのシステムコール (外側は仮想マシンのエラー、内側はファイルの操作のエラー)
+    fn disk_call(&mut self, disk: &mut Disk, number: i32) -> Result<Result<i32, String>, String> {
+        Ok(match number {
+            // open(パス, モード) -> ファイル番号
+            1 => {
+                let mode = self.pop()?;
+                let path = self.pop()?;
+                disk.open(&self.read_string(path)?, mode)
+            }
+            // close(ファイル番号) -> 0
+            2 => {
+                let fd = self.pop()?;
+                disk.close(fd).map(|_| 0)
+            }
+            // read(ファイル番号, 番地, 数) -> 読んだバイト数
+            3 => {
+                let count = self.pop()?;
+                let address = self.pop()?;
+                let fd = self.pop()?;
+                match disk.read(fd, count.max(0) as usize) {
+                    Ok(bytes) => {
+                        for (i, byte) in bytes.iter().enumerate() {
+                            self.store(address + i as i32, *byte as i32)?;
+                        }
+                        Ok(bytes.len() as i32)
+                    }
+                    Err(message) => Err(message),
+                }
+            }
+            // write(ファイル番号, 番地, 数) -> 書いたバイト数
+            4 => {
+                let count = self.pop()?;
+                let address = self.pop()?;
+                let fd = self.pop()?;
+                let mut bytes = Vec::new();
+                for index in address..address + count.max(0) {
+                    let value = (self.region().get(index as usize).copied())
+                        .ok_or(msg!("vm.fault.memory_out_of_range", index))?;
+                    bytes.push(value as u8);
+                }
+                disk.write(fd, &bytes).map(|length| length as i32)
+            }
+            // seek(ファイル番号, 位置) -> 移った位置
+            5 => {
+                let position = self.pop()?;
+                let fd = self.pop()?;
+                disk.seek(fd, position).map(|position| position as i32)
+            }
+            // list(パス, 番地, 大きさ) -> 項目の数
+            6 => {
+                let size = self.pop()?;
+                let address = self.pop()?;
+                let path = self.pop()?;
+                match disk.list(&self.read_string(path)?) {
+                    Ok((count, text)) => {
+                        // 入りきらない分は切り捨てて0で終える
+                        let chars: Vec<char> = text.chars().collect();
+                        let length = chars.len().min((size - 1).max(0) as usize);
+                        for (i, c) in chars[..length].iter().enumerate() {
+                            self.store(address + i as i32, *c as i32)?;
+                        }
+                        if size > 0 {
+                            self.store(address + length as i32, 0)?;
+                        }
+                        Ok(count as i32)
+                    }
+                    Err(message) => Err(message),
+                }
+            }
+            // delete(パス) -> 0
+            7 => {
+                let path = self.pop()?;
+                disk.delete(&self.read_string(path)?).map(|_| 0)
+            }
+            // mkdir(パス) -> 0
+            _ => {
+                let path = self.pop()?;
+                disk.mkdir(&self.read_string(path)?).map(|_| 0)
+            }
+        })
+    }
+
+    /// メモリに値を書き込む
+    fn store(&mut self, index: i32, value: i32) -> Result<(), String> {
+        let (base, size) = self.region_bounds();
+        if index < 0 || index as usize >= size {
+            return Err(msg!("vm.fault.memory_out_of_range", index));
+        }
+        // 履歴には物理番地を記録する
+        let old = self.memory[base + index as usize];
+        self.memory[base + index as usize] = value;
+        self.record(Change::Memory(base + index as usize, old));
+        self.writes.push(Access::Memory(index as usize));
+        self.events.push(Event::Memory(index as usize, value));
+        Ok(())
+    }
+
+    /// 文字列を出力する
+    fn write_output(&mut self, text: &str) {
+        (self.events).extend(text.chars().map(|c| Event::Output(c as i32)));
+        match self.mode {
+            Mode::Execute => print!("{text}"),
+            Mode::Debug | Mode::Embedded => {
+                if let Mode::Debug = self.mode {
+                    println!("{}", msg!("vm.output", text));
+                }
+                self.record(Change::Output(self.output.len()));
+                self.output.push_str(text);
+            }
+        }
+    }
+
+    /// 読みかけの行がなければ次の行を読み込む (入力が終わっていればfalseを返す)
+    fn fill_input(&mut self) -> Result<bool, String> {
+        if !self.pending.is_empty() {
+            return Ok(true);
+        }
+        if self.end_of_input {
+            return Ok(false);
+        }
+        match self.read_input("")? {
+            Some(line) => {
+                self.pending.extend(line.chars());
+                self.pending.push_back('\n');
+                Ok(true)
+            }
+            None => {
+                self.end_of_input = true;
+                Ok(false)
+            }
+        }
+    }
+
+    /// 入力を1行読む (入力が終わっていればNone、再現しているときは記録から読む)
+    fn read_input(&mut self, prompt: &str) -> Result<Option<String>, String> {
+        if let Some(replay) = self.replay.as_mut().filter(|replay| replay.is_playing()) {
+            return replay.line();
+        }
+        let line = match self.mode {
+            Mode::Execute | Mode::Debug if self.scripted => self.input.pop_front(),
+            // キーボード割り込みのために別のスレッドで読んでいる場合
+            Mode::Execute if self.keyboard.is_some() => {
+                (self.input.pop_front()).or_else(|| self.keyboard.as_ref()?.recv().ok().flatten())
+            }
+            Mode::Execute => io::read_line(prompt),
+            Mode::Debug => io::read_line(&msg!("vm.input_prompt")),
+            Mode::Embedded => self.input.pop_front(),
+        };
+        self.announced = false;
+        if let Some(replay) = &mut self.replay {
+            replay.write(&match &line {
+                Some(line) => Entry::Line(line.clone()),
+                None => Entry::EndOfInput,
+            })?;
+        }
+        Ok(line)
+    }
+
+    /// 読んでいない入力の行があるか判断する (標準入力は別のスレッドで読んでおく)
+    fn line_ready(&mut self) -> bool {
+        if matches!(self.mode, Mode::Execute) && !self.scripted {
+            let keyboard = self.keyboard.get_or_insert_with(io::spawn_reader);
+            while let Ok(Some(line)) = keyboard.try_recv() {
+                self.input.push_back(line);
+            }
+        }
+        !self.input.is_empty()
+    }
+
+    /// 全ての装置の終わりの処理をする (画像の書き出しなど)
+    pub fn finish_devices(&mut self) -> Result<(), String> {
+        self.with_bus(|bus, vm| bus.finish(vm))
+    }
+
+    /// バスを取り出して装置を操作する (装置から仮想マシンの機能を使えるようにする)
+    fn with_bus<T>(&mut self, f: impl FnOnce(&mut Bus, &mut Self) -> T) -> T {
+        let mut bus = std::mem::take(&mut self.bus);
+        let result = f(&mut bus, self);
+        self.bus = bus;
+        result
+    }
+
+    fn push(&mut self, value: i32) {
+        self.stack.push(value);
+        self.record(Change::Push);
+    }
+
+    fn pop(&mut self) -> Result<i32, String> {
+        let value = self.stack.pop().ok_or(msg!("vm.fault.stack_empty"))?;
+        self.record(Change::Pop(value));
+        Ok(value)
+    }
+
+    /// 実行中の命令の変更を履歴に記録する
+    fn record(&mut self, change: Change) {
+        if let Some(record) = self.history.back_mut() {
+            record.changes.push(change);
+        }
+    }
+
+    /// 履歴に残す命令の数を設定する (0にすると記録しない)
+    pub fn set_history_size(&mut self, size: usize) {
+        self.history_size = size;
+        while self.history.len() > size {
+            self.history.pop_front();
+        }
+    }
+
+    /// 履歴に残す命令の数
+    pub fn history_size(&self) -> usize {
+        self.history_size
+    }
+
+    /// 履歴に残っている命令の数
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// 最後に実行した命令を取り消す (取り消した命令が書き込んだ場所を返す)
+    /// (履歴がないか、取り消せない装置に書き込んだ命令ならエラー)
+    pub fn step_back(&mut self) -> Result<Vec<Access>, String> {
+        let record = self.history.back().ok_or(msg!("vm.history_start"))?;
+        if let Some(name) = (record.changes.iter()).find_map(|change| match change {
+            Change::Device(name) => Some(*name),
+            _ => None,
+        }) {
+            return Err(msg!("vm.history_device", name));
+        }
+        let record = self.history.pop_back().expect("history");
+        let mut writes = Vec::new();
+        for change in record.changes.into_iter().rev() {
+            match change {
+                Change::Push => {
+                    self.stack.pop();
+                }
+                Change::Pop(value) => self.stack.push(value),
+                Change::Memory(address, old) => {
+                    self.memory[address] = old;
+                    writes.push(Access::Memory(address));
+                }
+                Change::Resize(length) => self.memory.truncate(length),
+                Change::Process(scheduler, stack) => {
+                    self.scheduler = *scheduler;
+                    self.stack = stack;
+                }
+                Change::Storage(line, old) => {
+                    let _ = io::write_specific_line(&self.storage, line, &old);
+                    writes.push(Access::Storage(line));
+                }
+                Change::Output(length) => self.output.truncate(length),
+                Change::Device(_) => {}
+            }
+        }
+        self.bus.restore(&record.devices);
+        self.interrupts = record.interrupts;
+        self.pc = record.pc;
+        self.address = record.address;
+        self.state = record.state;
+        self.steps = self.steps.saturating_sub(1);
+        self.writes.clear();
+        // 書き込んだ場所は取り消した後のプロセスの領域の番地で返す
+        let (base, size) = self.region_bounds();
+        let writes = (writes.into_iter())
+            .filter_map(|access| match access {
+                Access::Memory(address) if address < base || address >= base + size => None,
+                Access::Memory(address) => Some(Access::Memory(address - base)),
+                access => Some(access),
+            })
+            .collect();
+        Ok(writes)
+    }
+
+    /// 実行した命令をトレースに書き出すようにする
+    pub fn set_trace(&mut self, tracer: Tracer) {
+        self.trace = Some(tracer);
+    }
+
+    /// トレースの書き出しを完了させる
+    pub fn flush_trace(&mut self) {
+        if let Some(Err(e)) = self.trace.as_mut().map(Tracer::flush) {
+            println!("{}", msg!("vm.trace_write_failed", e));
+        }
+    }
+
+    /// 実行した命令をトレースに書き出す
+    fn write_trace(&mut self, before: Vec<i32>) {
+        let Some(tracer) = &mut self.trace else {
+            return;
+        };
+        let (base, length) = match &self.scheduler {
+            Some(scheduler) => (scheduler.current().base, scheduler.current().size),
+            None => (0, self.memory.len()),
+        };
+        let region = &self.memory[base..base + length];
+        let size = match Instruction::decode(region, self.address) {
+            Some((_, size)) => size,
+            None => 1,
+        };
+        let step = Step {
+            step: tracer.count(),
+            pc: self.address,
+            opcode: region[self.address],
+            operands: region[self.address + 1..(self.address + size).min(length)].to_vec(),
+            before,
+            after: self.stack.clone(),
+            events: std::mem::take(&mut self.events),
+            fault: match &self.state {
+                State::Fault(message) => Some(message.clone()),
+                _ => None,
+            },
+        };
+        if let Err(e) = tracer.write(&step) {
+            println!("{}", msg!("vm.trace_write_failed", e));
+            self.trace = None;
+        }
+    }
+
+    /// 実行中か判断する
+    pub fn is_running(&self) -> bool {
+        self.state == State::Running
+    }
+
+    /// 命令を1つ実行する (実行した命令のアドレスと命令と長さを返す、実行しなければNone)
+    pub fn step(&mut self) -> Option<(usize, Instruction, usize)> {
+        if !self.is_running() {
+            return None;
+        }
+        if self.pc >= self.memory.len() && self.scheduler.is_none() {
+            self.state = State::Halted;
+            return None;
+        }
+        if let Some(max) = self.max_steps.filter(|max| self.steps >= *max) {
+            self.address = self.pc;
+            self.state = State::Fault(msg!("vm.fault.max_steps", max));
+            return None;
+        }
+        self.steps += 1;
+
+        // 取り消せるように実行前の状態を記録する
+        if self.history_size > 0 {
+            if self.history.len() >= self.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(Record {
+                pc: self.pc,
+                address: self.address,
+                state: self.state.clone(),
+                interrupts: self.interrupts.clone(),
+                devices: self.bus.save(),
+                changes: Vec::new(),
+            });
+        }
+
+        // 装置を進めて、割り込みを受け付けるなら処理の番地へ移る
+        match self.with_bus(|bus, vm| bus.tick(vm)) {
+            Ok(raised) => raised
+                .into_iter()
+                .for_each(|number| self.interrupts.raise(number)),
+            Err(message) => {
+                self.address = self.pc;
+                self.state = State::Fault(message);
+                return None;
+            }
+        }
+        if let Err(message) = self.schedule() {
+            self.address = self.pc;
+            self.state = State::Fault(message);
+            return None;
+        }
+        if !self.is_running() {
+            return None;
+        }
+        let (base, size) = self.region_bounds();
+        let region = &self.memory[base..base + size];
+        match self.interrupts.dispatch(self.pc, region) {
+            Ok(Some((number, handler))) => {
+                self.log_print(msg!("vm.log.interrupt", number, handler));
+                self.pc = handler;
+            }
+            Ok(None) => {}
+            Err(message) => {
+                self.address = self.pc;
+                self.state = State::Fault(message);
+                return None;
+            }
+        }
+
+        self.address = self.pc;
+        self.writes.clear();
+        self.events.clear();
+        let before = match self.trace {
+            Some(_) => self.stack.clone(),
+            None => Vec::new(),
+        };
+        let instruction = self.region()[self.pc];
+        let Some((result, size)) = Instruction::decode(self.region(), self.pc) else {
             self.pc += 1;
+            self.log_print(msg!("vm.log.undefined_opcode", instruction));
+            self.write_trace(before);
+            return None;
+        };
+        let prefix = match &self.scheduler {
+            Some(scheduler) => msg!("vm.process_prefix", scheduler.current().id),
+            None => String::new(),
+        };
+        self.log_print(prefix + &msg!("vm.execute", self.address, instruction));
+        self.log_print(format!("| {}", self.debug.describe(self.address)));
+        // ジャンプ先の命令から実行できるように先に次の番地へ進める
+        self.pc += size;
+        if let Err(message) = self.execute(result) {
+            self.state = State::Fault(message);
+        }
+        self.write_trace(before);
+        Some((self.address, result, size))
+    }
+
+    /// エラーで停止した場合はその内容とソースを表示する
+    pub fn report_fault(&self) {
+        if let State::Fault(message) = &self.state {
+            println!("{}", msg!("vm.fault", message));
+            println!("| {}", self.debug.describe(self.address));
+        }
+    }
+
+    pub fn run(&mut self) {
+        while self.is_running() {
+            self.step();
+        }
+        self.report_fault();
+    }
+}
+
+impl Host for VirtualMachine {
+    fn read_char(&mut self) -> Result<i32, String> {
+        Ok(match self.fill_input()? {
+            true => self.pending.pop_front().map_or(-1, |c| c as i32),
+            false => -1,
+        })
+    }
+
+    fn end_of_input(&mut self) -> Result<bool, String> {
+        Ok(!self.fill_input()?)
+    }
+
+    fn write_text(&mut self, text: &str) {
+        self.write_output(text);
+    }
+
+    /// ホストの機能を呼び出す (結果は記録され、再現しているときは記録から返す)
+    fn host_call(&mut self, name: &str, call: &mut dyn FnMut() -> i32) -> Result<i32, String> {
+        if let Some(replay) = self.replay.as_mut().filter(|replay| replay.is_playing()) {
+            return replay.host(name);
         }
+        let value = call();
+        if let Some(replay) = &mut self.replay {
+            replay.write(&Entry::Host(name.to_string(), value))?;
+        }
+        Ok(value)
+    }
+
+    fn poll_keyboard(&mut self) -> Result<bool, String> {
+        let entry = Entry::Host("keyboard".to_string(), self.steps as i32);
+        if let Some(replay) = self.replay.as_mut().filter(|replay| replay.is_playing()) {
+            return Ok(replay.take(&entry));
+        }
+        if self.announced || !self.line_ready() {
+            return Ok(false);
+        }
+        self.announced = true;
+        if let Some(replay) = &mut self.replay {
+            replay.write(&entry)?;
+        }
+        Ok(true)
+    }
+
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+}
+
+#[cfg(test)]
+mod test_vm {
+    use std::fs::File;
+
+    use crate::object::Object;
+    use crate::testing;
+    use crate::vm::{Mode, State, VirtualMachine};
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let (path, storage) = testing::storage("snapshot.txt", "1\n\"2\"\n");
+        // push 65 / output / push 7 / div (スタックが足りずにエラーになる)
+        let program = Object {
+            code: vec![6, 65, 17, 6, 7, 4],
+            ..Object::default()
+        };
+        let mut vm = VirtualMachine::new(storage, program, Mode::Debug);
+        while vm.is_running() {
+            vm.step();
+        }
+        let snapshot = vm.snapshot().unwrap();
+
+        std::fs::write(&path, "").unwrap();
+        let storage = File::options().read(true).write(true).open(&path).unwrap();
+        let restored = VirtualMachine::restore(&snapshot, storage).unwrap();
+        assert_eq!(restored.pc(), vm.pc());
+        assert_eq!(restored.memory(), vm.memory());
+        assert_eq!(restored.output(), "A");
+        assert_eq!(
+            restored.state(),
+            &State::Fault("スタックが空です".to_string())
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1\n\"2\"\n");
+        assert_eq!(restored.snapshot(), Ok(snapshot));
+    }
+
+    #[test]
+    fn test_console_io() {
+        // 1文字読んだ行の残りと次の行を文字列として読み、入力の終わりを確かめる
+        let code = "inputchar\noutputnum\npush\nbuf\ninputstr\noutputnum\npush\nbuf\noutputstr\n\
+                    push\nbuf\ninputstr\npop\npush\nbuf\noutputstr\n\
+                    eof\noutputnum\ninputchar\noutputnum\nhalt\nbuf: .zero 8\n";
+        let mut vm = testing::machine("io.asm", code);
+        vm.push_input("ab".to_string());
+        vm.push_input("あい".to_string());
+        vm.run();
+        assert_eq!(vm.state(), &State::Halted);
+        assert_eq!(vm.output(), "971bあい1-1");
+        let buf = vm.debug_info().address("buf").unwrap();
+        assert_eq!(&vm.memory()[buf..buf + 3], &[0x3042, 0x3044, 0]);
     }
 }